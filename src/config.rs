@@ -0,0 +1,273 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use anyhow::{Context as _, Result};
+use mongodb::options::{
+    Acknowledgment, ClientOptions, ReadPreference, SelectionCriteria, WriteConcern,
+};
+
+pub const DEFAULT_PROFILE: &str = "default";
+pub const DEFAULT_DATABASE: &str = "testdb";
+pub const DEFAULT_COLLECTION: &str = "test_collection";
+/// suffix of the default history collection name, after the residents collection
+pub const HISTORY_COLLECTION_SUFFIX: &str = "_history";
+
+/// Config file with named connection profiles
+///
+/// ```toml
+/// default_profile = "dev"
+///
+/// [profiles.dev]
+/// uri = "mongodb://localhost:27017"
+/// database = "testdb"
+/// collection = "test_collection"
+/// history_collection = "test_collection_history"
+/// max_pool_size = 10
+/// connect_timeout_ms = 5000
+/// read_preference = "primaryPreferred"
+///
+/// [profiles.dev.write_concern]
+/// w = "majority"
+/// journal = true
+/// wtimeout_ms = 2000
+///
+/// [escalation]
+/// interval_sec = 30
+///
+/// [[escalation.rules]]
+/// name = "falls"
+/// message = "fall"
+/// after_sec = [120, 600]
+///
+/// [[escalation.rules]]
+/// location = "^Wing B"
+/// after_sec = [600, 1800, 3600]
+/// ```
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    pub escalation: EscalationConfig,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read config {}", path.display()))?;
+        toml::from_str(&data).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Returns the requested profile, or the default one when `name` is None.
+    /// A missing default profile yields the built-in defaults.
+    pub fn profile(&self, name: Option<&str>) -> Result<(String, Profile)> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => match self.profiles.get(name) {
+                Some(profile) => Ok((name.to_string(), profile.clone())),
+                None => anyhow::bail!(
+                    "profile '{}' not found, available: {}",
+                    name,
+                    self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            },
+            None => Ok((
+                DEFAULT_PROFILE.to_string(),
+                self.profiles
+                    .get(DEFAULT_PROFILE)
+                    .cloned()
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+/// Connection settings for one environment
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub uri: Option<String>,
+    pub database: Option<String>,
+    pub collection: Option<String>,
+    /// collection of cleared alarms [default: <collection>_history]
+    pub history_collection: Option<String>,
+    pub app_name: Option<String>,
+    pub max_pool_size: Option<u32>,
+    pub min_pool_size: Option<u32>,
+    pub connect_timeout_ms: Option<u64>,
+    pub server_selection_timeout_ms: Option<u64>,
+    pub write_concern: Option<WriteConcernConfig>,
+    /// primary, primaryPreferred, secondary, secondaryPreferred or nearest
+    pub read_preference: Option<String>,
+    /// accept a standalone server without transactions, where clearing alarms and
+    /// deleting or restoring residents are no longer atomic
+    #[serde(default)]
+    pub allow_standalone: bool,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteConcernConfig {
+    /// number of nodes, "majority" or a custom tag
+    pub w: Option<WriteAck>,
+    pub journal: Option<bool>,
+    pub wtimeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+pub enum WriteAck {
+    Nodes(u32),
+    Tag(String),
+}
+
+/// Settings of the `escalate` daemon
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EscalationConfig {
+    /// seconds between scans of the active alarms [default: 60]
+    pub interval_sec: Option<u64>,
+    /// the first rule matching an alarm applies
+    #[serde(default)]
+    pub rules: Vec<EscalationRule>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EscalationRule {
+    /// shown in escalation events [default: rule number]
+    pub name: Option<String>,
+    /// regexp matching the resident location, any location if not set
+    pub location: Option<String>,
+    /// regexp matching the alarm message, any message if not set
+    pub message: Option<String>,
+    /// alarm age in seconds for escalation levels 1, 2, ...
+    pub after_sec: Vec<u64>,
+}
+
+impl Profile {
+    pub fn database(&self) -> &str {
+        self.database.as_deref().unwrap_or(DEFAULT_DATABASE)
+    }
+
+    pub fn collection(&self) -> &str {
+        self.collection.as_deref().unwrap_or(DEFAULT_COLLECTION)
+    }
+
+    /// History of this residents collection, so environments sharing a database keep
+    /// their alarms apart
+    pub fn history_collection(&self) -> String {
+        match &self.history_collection {
+            Some(name) => name.clone(),
+            None => format!("{}{}", self.collection(), HISTORY_COLLECTION_SUFFIX),
+        }
+    }
+
+    /// `database.collection` this profile works on
+    pub fn namespace(&self) -> String {
+        format!("{}.{}", self.database(), self.collection())
+    }
+
+    /// Applies the profile settings on top of the options parsed from the URI
+    pub fn apply(&self, options: &mut ClientOptions) -> Result<()> {
+        if let Some(app_name) = &self.app_name {
+            options.app_name = Some(app_name.clone());
+        }
+        if let Some(size) = self.max_pool_size {
+            options.max_pool_size = Some(size);
+        }
+        if let Some(size) = self.min_pool_size {
+            options.min_pool_size = Some(size);
+        }
+        if let Some(ms) = self.connect_timeout_ms {
+            options.connect_timeout = Some(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.server_selection_timeout_ms {
+            options.server_selection_timeout = Some(Duration::from_millis(ms));
+        }
+        if let Some(wc) = &self.write_concern {
+            let mut write_concern = WriteConcern::default();
+            write_concern.w = wc.w.as_ref().map(|w| match w {
+                WriteAck::Nodes(n) => Acknowledgment::Nodes(*n),
+                WriteAck::Tag(tag) => Acknowledgment::from(tag.as_str()),
+            });
+            write_concern.journal = wc.journal;
+            write_concern.w_timeout = wc.wtimeout_ms.map(Duration::from_millis);
+            options.write_concern = Some(write_concern);
+        }
+        if let Some(mode) = &self.read_preference {
+            let read_preference = match mode.as_str() {
+                "primary" => ReadPreference::Primary,
+                "primaryPreferred" => ReadPreference::PrimaryPreferred { options: None },
+                "secondary" => ReadPreference::Secondary { options: None },
+                "secondaryPreferred" => ReadPreference::SecondaryPreferred { options: None },
+                "nearest" => ReadPreference::Nearest { options: None },
+                _ => anyhow::bail!("unknown read_preference '{}'", mode),
+            };
+            options.selection_criteria = Some(SelectionCriteria::ReadPreference(read_preference));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_collection_follows_the_residents_collection() {
+        let config: Config = toml::from_str(
+            r#"
+            [profiles.a]
+            collection = "residents_a"
+
+            [profiles.b]
+            collection = "residents_b"
+            history_collection = "shared"
+            "#,
+        )
+        .unwrap();
+        let (_, a) = config.profile(Some("a")).unwrap();
+        assert_eq!(a.history_collection(), "residents_a_history");
+        let (_, b) = config.profile(Some("b")).unwrap();
+        assert_eq!(b.history_collection(), "shared");
+        assert_eq!(
+            Profile::default().history_collection(),
+            "test_collection_history"
+        );
+    }
+
+    #[tokio::test]
+    async fn write_concern_reaches_collections() {
+        let config: Config = toml::from_str(
+            r#"
+            [profiles.default.write_concern]
+            w = "majority"
+            journal = true
+            wtimeout_ms = 2000
+            "#,
+        )
+        .unwrap();
+        let (_, profile) = config.profile(None).unwrap();
+        let mut options = ClientOptions::parse("mongodb://localhost:27017")
+            .await
+            .unwrap();
+        profile.apply(&mut options).unwrap();
+        let client = mongodb::Client::with_options(options).unwrap();
+        let collection = client
+            .database(profile.database())
+            .collection::<mongodb::bson::Document>(profile.collection());
+        // the form write_batch sends with its update command
+        let write_concern =
+            mongodb::bson::to_document(collection.write_concern().unwrap()).unwrap();
+        assert_eq!(write_concern.get_str("w").unwrap(), "majority");
+        assert!(write_concern.get_bool("j").unwrap());
+        assert_eq!(
+            write_concern
+                .get("wtimeout")
+                .and_then(|ms| ms.as_i64().or(ms.as_i32().map(i64::from))),
+            Some(2000)
+        );
+    }
+}
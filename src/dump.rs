@@ -0,0 +1,243 @@
+//! NDJSON snapshots of residents with their alarms.
+//!
+//! Every line is one resident in canonical extended JSON, so dates and number types
+//! round-trip exactly. The alarm history is embedded in `alarms`, the layout residents
+//! had before history moved to its own collection. Files ending in `.gz` are written
+//! gzip-compressed; compressed input is detected on restore.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use clap::{Parser, ValueEnum};
+use flate2::{Compression, read::MultiGzDecoder, write::GzEncoder};
+use futures::TryStreamExt as _;
+use mongodb::bson::{self, Bson};
+use tracing::{Level, info, warn};
+
+use crate::{
+    model::Resident,
+    store::{ResidentFilter, ResidentStore},
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct ExportParams {
+    #[clap(short, long, help = "Optional Regexp pattern to match resident names")]
+    pub name: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Optional Regexp pattern to match resident locations"
+    )]
+    pub location: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Output file, gzip-compressed if it ends in .gz [default: stdout]"
+    )]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RestoreMode {
+    /// Replace the residents found in the dump, keep all others
+    Merge,
+    /// Delete every matching resident first, the store ends up like the dump
+    Replace,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct RestoreParams {
+    #[clap(help = "NDJSON dump written by export, optionally gzip-compressed")]
+    pub input: PathBuf,
+    #[clap(short, long, help = "Optional Regexp pattern to match resident names")]
+    pub name: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Optional Regexp pattern to match resident locations"
+    )]
+    pub location: Option<String>,
+    #[clap(long, value_enum, default_value_t = RestoreMode::Merge)]
+    pub mode: RestoreMode,
+}
+
+/// Writes matching residents with their alarm history, returns the number written
+#[tracing::instrument(name = "export", skip_all, level = Level::TRACE)]
+pub async fn export(store: &dyn ResidentStore, params: &ExportParams) -> Result<u64> {
+    let filter = ResidentFilter::new(&params.name, &params.location)?;
+    let mut output = Output::create(params.output.as_deref())?;
+    let (mut residents, mut alarms) = (0, 0);
+    let mut stream = store.export(&filter).await?;
+    while let Some(resident) = stream.try_next().await? {
+        let json = bson::to_bson(&resident)?.into_canonical_extjson();
+        serde_json::to_writer(output.writer(), &json)?;
+        output.writer().write_all(b"\n")?;
+        residents += 1;
+        alarms += resident.alarms.len();
+    }
+    output.finish()?;
+    info!(
+        "Exported {} residents with {} history alarms to {}",
+        residents,
+        alarms,
+        params
+            .output
+            .as_ref()
+            .map_or("stdout".into(), |p| p.display().to_string())
+    );
+    Ok(residents)
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreSummary {
+    /// residents removed by `--mode replace` before restoring
+    pub deleted: u64,
+    pub inserted: u64,
+    pub replaced: u64,
+    /// residents in the dump not matching the filter
+    pub skipped: u64,
+    /// history alarms in the restored residents, including ones already stored
+    pub alarms: u64,
+}
+
+impl fmt::Display for RestoreSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Restored {} residents ({} new, {} replaced) with {} history alarms, {} skipped",
+            self.inserted + self.replaced,
+            self.inserted,
+            self.replaced,
+            self.alarms,
+            self.skipped,
+        )?;
+        if self.deleted > 0 {
+            write!(f, ", {} deleted first", self.deleted)?;
+        }
+        Ok(())
+    }
+}
+
+/// Restores a dump written by [`export`]. A first pass parses the whole file before the
+/// store is changed, so a damaged dump is refused before anything is deleted; a second
+/// pass reads it again to write, the dump is never held in memory. The delete of
+/// `--mode replace` commits on its own though: a write failing after it leaves the
+/// remaining residents of the dump missing until the restore is run again.
+#[tracing::instrument(name = "restore", skip_all, level = Level::TRACE)]
+pub async fn restore(store: &dyn ResidentStore, params: &RestoreParams) -> Result<RestoreSummary> {
+    let filter = ResidentFilter::new(&params.name, &params.location)?;
+    let mut summary = RestoreSummary::default();
+    let mut matching = 0;
+    for resident in read_dump(&params.input)? {
+        if filter.matches(&resident?) {
+            matching += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+
+    if params.mode == RestoreMode::Replace {
+        warn!(
+            "Deleting matching residents before restoring {}, if the restore fails run it again to put them back",
+            params.input.display()
+        );
+        summary.deleted = store.delete_matching(&filter).await?;
+        warn!(
+            "Deleted {} residents before restoring {}",
+            summary.deleted,
+            params.input.display()
+        );
+    }
+    let mut restored_count = 0;
+    for resident in read_dump(&params.input)? {
+        let resident = resident?;
+        if !filter.matches(&resident) {
+            continue;
+        }
+        let restored = store.restore(&resident).await.with_context(|| {
+            format!(
+                "restored {} of {} residents, {} deleted first; run the restore again to finish it",
+                restored_count, matching, summary.deleted
+            )
+        })?;
+        restored_count += 1;
+        if restored {
+            summary.inserted += 1;
+        } else {
+            summary.replaced += 1;
+        }
+        summary.alarms += resident.alarms.len() as u64;
+    }
+    Ok(summary)
+}
+
+/// Residents of a dump in file order, blank lines skipped, errors name the line
+fn read_dump(path: &Path) -> Result<impl Iterator<Item = Result<Resident>> + '_> {
+    let lines = open(path)?.lines().enumerate();
+    Ok(lines.filter_map(move |(index, line)| {
+        let line = match line.with_context(|| format!("cannot read {}", path.display())) {
+            Ok(line) if line.trim().is_empty() => return None,
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(parse_line(&line).with_context(|| format!("{} line {}", path.display(), index + 1)))
+    }))
+}
+
+fn parse_line(line: &str) -> Result<Resident> {
+    let json: serde_json::Value = serde_json::from_str(line)?;
+    Ok(bson::from_bson(Bson::try_from(json)?)?)
+}
+
+/// Opens a dump, decompressing it if it starts with the gzip magic bytes
+fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Output {
+    fn create(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Output::Plain(Box::new(BufWriter::new(io::stdout().lock()))));
+        };
+        let file = BufWriter::new(
+            File::create(path).with_context(|| format!("cannot create {}", path.display()))?,
+        );
+        if path.extension().is_some_and(|e| e == "gz") {
+            Ok(Output::Gzip(GzEncoder::new(file, Compression::default())))
+        } else {
+            Ok(Output::Plain(Box::new(file)))
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Output::Plain(writer) => writer,
+            Output::Gzip(encoder) => encoder,
+        }
+    }
+
+    /// Flushes buffers and writes the gzip trailer
+    fn finish(self) -> Result<()> {
+        match self {
+            Output::Plain(mut writer) => writer.flush()?,
+            Output::Gzip(encoder) => encoder.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
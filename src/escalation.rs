@@ -0,0 +1,294 @@
+//! Escalation of active alarms that stay open too long.
+//!
+//! Each scan matches every active alarm against the configured rules and records the
+//! levels that became due since the last scan. A level is recorded on the alarm exactly
+//! once, also with concurrent daemons. Its event is emitted after that and then marked as
+//! sent on the alarm, so the alarm itself is the outbox: a level whose event was not
+//! marked sent, because the daemon stopped in between, is emitted again by a later scan
+//! once it is [`REDELIVERY_DELAY_SEC`] old. Events are therefore delivered at least once,
+//! repeats share `alarm_id` and `level`. No further level is recorded while one is unsent.
+
+use std::fmt;
+
+use anyhow::Result;
+use mongodb::bson::{self, oid::ObjectId};
+use regex::{Regex, RegexBuilder};
+use tracing::{Level, debug, warn};
+
+use crate::{
+    config::{EscalationConfig, EscalationRule},
+    model::{ActiveAlarm, AlarmState, Resident},
+    store::ResidentStore,
+    utils,
+};
+
+/// Seconds after recording a level before a scan takes its unsent event as lost
+/// and emits it again
+pub const REDELIVERY_DELAY_SEC: u64 = 60;
+
+/// An alarm reaching a new escalation level
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EscalationEvent {
+    pub level: u32,
+    pub rule: String,
+    /// alarm age that triggers this level
+    pub threshold_sec: u64,
+    pub age_sec: u64,
+    pub state: AlarmState,
+    #[serde(serialize_with = "bson::serde_helpers::serialize_object_id_as_hex_string")]
+    pub alarm_id: ObjectId,
+    #[serde(serialize_with = "bson::serde_helpers::serialize_object_id_as_hex_string")]
+    pub resident_id: ObjectId,
+    pub name: String,
+    pub location: String,
+    pub message: String,
+    #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub raised_at: bson::DateTime,
+}
+
+impl fmt::Display for EscalationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Escalation level {} ({}) {} ({}) alarm {} '{}' {} since {}, open {}s",
+            self.level,
+            self.rule,
+            self.name,
+            self.location,
+            self.alarm_id,
+            self.message,
+            self.state,
+            utils::format_datetime(&self.raised_at, "%Y-%m-%d %H:%M:%S"),
+            self.age_sec,
+        )
+    }
+}
+
+struct Rule {
+    name: String,
+    location: Option<Regex>,
+    message: Option<Regex>,
+    after_sec: Vec<u64>,
+}
+
+impl Rule {
+    fn new(index: usize, rule: &EscalationRule) -> Result<Self> {
+        let name = rule
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("rule {}", index + 1));
+        if rule.after_sec.is_empty() || !rule.after_sec.is_sorted_by(|a, b| a < b) {
+            anyhow::bail!("escalation {name}: after_sec must be a non-empty increasing list");
+        }
+        let pattern = |p: &Option<String>| -> Result<Option<Regex>> {
+            Ok(match p {
+                Some(p) => Some(RegexBuilder::new(p).case_insensitive(true).build()?),
+                None => None,
+            })
+        };
+        Ok(Rule {
+            location: pattern(&rule.location)?,
+            message: pattern(&rule.message)?,
+            after_sec: rule.after_sec.clone(),
+            name,
+        })
+    }
+
+    fn matches(&self, resident: &Resident, alarm: &ActiveAlarm) -> bool {
+        self.location
+            .as_ref()
+            .is_none_or(|l| l.is_match(&resident.location))
+            && self
+                .message
+                .as_ref()
+                .is_none_or(|m| m.is_match(&alarm.message))
+    }
+}
+
+pub struct Escalator {
+    rules: Vec<Rule>,
+}
+
+impl Escalator {
+    pub fn new(config: &EscalationConfig) -> Result<Self> {
+        if config.rules.is_empty() {
+            anyhow::bail!("no escalation rules in the config file");
+        }
+        Ok(Escalator {
+            rules: config
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| Rule::new(index, rule))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Records the escalation levels due at `now` and calls `emit` for each one, lower
+    /// levels first, after the unsent events of earlier scans. Returns the number of
+    /// events emitted.
+    #[tracing::instrument(name = "escalate", skip_all, level = Level::TRACE)]
+    pub async fn scan(
+        &self,
+        store: &dyn ResidentStore,
+        now: bson::DateTime,
+        mut emit: impl FnMut(&EscalationEvent) -> Result<()>,
+    ) -> Result<usize> {
+        let mut emitted = 0;
+        for resident in store.with_active_alarms().await? {
+            for alarm in &resident.active_alarms {
+                let Some(rule) = self.rules.iter().find(|r| r.matches(&resident, alarm)) else {
+                    continue;
+                };
+                let age_sec = now
+                    .checked_duration_since(alarm.time)
+                    .unwrap_or_default()
+                    .as_secs();
+                let event = |level: u32| EscalationEvent {
+                    level,
+                    rule: rule.name.clone(),
+                    threshold_sec: rule
+                        .after_sec
+                        .get(level as usize - 1)
+                        .copied()
+                        .unwrap_or_default(),
+                    age_sec,
+                    state: alarm.state(),
+                    alarm_id: alarm.id,
+                    resident_id: resident.id.unwrap_or_default(),
+                    name: resident.name.clone(),
+                    location: resident.location.clone(),
+                    message: alarm.message.clone(),
+                    raised_at: alarm.time,
+                };
+
+                let unsent = alarm.unsent_escalations();
+                if !unsent.is_empty() {
+                    let recorded_sec = alarm
+                        .escalated_at
+                        .and_then(|at| now.checked_duration_since(at))
+                        .unwrap_or_default()
+                        .as_secs();
+                    if recorded_sec < REDELIVERY_DELAY_SEC {
+                        // most likely another daemon is emitting it right now
+                        debug!("Alarm {} has unsent escalation levels", alarm.id);
+                        continue;
+                    }
+                    warn!(
+                        "Emitting escalation levels {:?} of alarm {} again, they were recorded but not marked sent",
+                        unsent, alarm.id
+                    );
+                    for level in unsent.clone() {
+                        emit(&event(level))?;
+                        emitted += 1;
+                    }
+                    store
+                        .escalation_sent(&resident.name, resident.birth, alarm.id, *unsent.end())
+                        .await?;
+                }
+
+                let due = rule.after_sec.iter().filter(|&&t| age_sec >= t).count() as u32;
+                for level in alarm.escalation_level.unwrap_or_default() + 1..=due {
+                    if !store
+                        .escalate_alarm(&resident.name, resident.birth, alarm.id, level)
+                        .await?
+                    {
+                        // cleared meanwhile or recorded by another daemon
+                        debug!("Alarm {} level {} not recorded", alarm.id, level);
+                        break;
+                    }
+                    emit(&event(level))?;
+                    emitted += 1;
+                    store
+                        .escalation_sent(&resident.name, resident.birth, alarm.id, level)
+                        .await?;
+                }
+            }
+        }
+        Ok(emitted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::EscalationRule, model::Resident, store::MemoryStore};
+
+    fn escalator() -> Escalator {
+        Escalator::new(&EscalationConfig {
+            interval_sec: None,
+            rules: vec![EscalationRule {
+                name: Some("all".to_string()),
+                location: None,
+                message: None,
+                after_sec: vec![60, 600],
+            }],
+        })
+        .unwrap()
+    }
+
+    async fn scan(store: &MemoryStore, now: bson::DateTime) -> Vec<u32> {
+        let mut levels = Vec::new();
+        escalator()
+            .scan(store, now, |event| {
+                levels.push(event.level);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        levels
+    }
+
+    fn after(time: bson::DateTime, sec: u64) -> bson::DateTime {
+        time.saturating_add_duration(std::time::Duration::from_secs(sec))
+    }
+
+    #[tokio::test]
+    async fn every_level_is_emitted_once() {
+        let store = MemoryStore::new();
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        let alarm = store
+            .new_alarm(&ann.name, ann.birth, "call", None)
+            .await
+            .unwrap();
+
+        assert!(scan(&store, after(alarm.time, 30)).await.is_empty());
+        assert_eq!(scan(&store, after(alarm.time, 90)).await, [1]);
+        assert!(scan(&store, after(alarm.time, 120)).await.is_empty());
+        assert_eq!(scan(&store, after(alarm.time, 700)).await, [2]);
+        assert!(scan(&store, after(alarm.time, 800)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsent_levels_are_emitted_again() {
+        let store = MemoryStore::new();
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        let alarm = store
+            .new_alarm(&ann.name, ann.birth, "call", None)
+            .await
+            .unwrap();
+        // a daemon recorded level 1 and stopped before emitting it
+        assert!(
+            store
+                .escalate_alarm(&ann.name, ann.birth, alarm.id, 1)
+                .await
+                .unwrap()
+        );
+        let recorded = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        let escalated_at = recorded.active_alarms[0].escalated_at.unwrap();
+
+        // left to the recording daemon for a while, and no further level meanwhile
+        assert!(scan(&store, after(escalated_at, 10)).await.is_empty());
+        assert!(
+            !store
+                .escalate_alarm(&ann.name, ann.birth, alarm.id, 2)
+                .await
+                .unwrap()
+        );
+        let late = after(escalated_at, REDELIVERY_DELAY_SEC + 600);
+        assert_eq!(scan(&store, late).await, [1, 2]);
+        assert!(scan(&store, late).await.is_empty());
+    }
+}
@@ -0,0 +1,322 @@
+//! When alarms happen: a weekday by hour-of-day matrix of alarm counts or average
+//! durations, in the facility timezone.
+//!
+//! Alarms are bucketed by the time they were raised. Active alarms are left out unless
+//! asked for, and then count with the time they have been open so far.
+
+use std::io::Write;
+
+use anyhow::Result;
+use chrono::{Datelike as _, Timelike as _};
+use chrono_tz::Tz;
+use clap::{Parser, ValueEnum};
+use comfy_table::{Cell, Color, Table, presets};
+use futures::TryStreamExt as _;
+use mongodb::bson::{self, doc};
+use tracing::{Level, info};
+
+use crate::{
+    store::{AlarmWindow, ResidentStore},
+    utils::{self, OutputFormat},
+};
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HeatmapMetric {
+    /// number of alarms raised
+    Count,
+    /// average seconds from raised to cleared
+    AvgDuration,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct HeatmapParams {
+    #[clap(long, value_enum, default_value_t = HeatmapMetric::Count)]
+    pub metric: HeatmapMetric,
+    #[clap(
+        long,
+        help = "Include active alarms, with the time they have been open so far"
+    )]
+    pub active: bool,
+    #[command(flatten)]
+    pub window: AlarmWindow,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    count: u64,
+    total_sec: u64,
+}
+
+/// Alarms bucketed by weekday (Monday first) and hour of day in `tz`
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    metric: HeatmapMetric,
+    buckets: [[Bucket; 24]; 7],
+    tz: Tz,
+}
+
+impl Heatmap {
+    fn new(metric: HeatmapMetric, tz: Tz) -> Self {
+        Heatmap {
+            metric,
+            buckets: [[Bucket::default(); 24]; 7],
+            tz,
+        }
+    }
+
+    /// Buckets the alarms of the matching residents raised in the date window
+    #[tracing::instrument(name = "heatmap", skip_all, level = Level::TRACE)]
+    pub async fn build(store: &dyn ResidentStore, params: &HeatmapParams) -> Result<Self> {
+        let filter = params.window.resident_filter()?;
+        let (from_date, to_date) = params.window.date_range()?;
+        let in_window = |time: &bson::DateTime| {
+            from_date.is_none_or(|f| *time >= f) && to_date.is_none_or(|t| *time <= t)
+        };
+        let now = bson::DateTime::now();
+        let mut heatmap = Heatmap::new(params.metric, utils::timezone());
+        let mut stream = store.with_history(&filter, from_date, to_date).await?;
+        while let Some(resident) = stream.try_next().await? {
+            for alarm in &resident.alarms {
+                heatmap.add(alarm.time, alarm.duration_sec);
+            }
+            if params.active {
+                for alarm in resident.active_alarms.iter().filter(|a| in_window(&a.time)) {
+                    let open_ms = now.timestamp_millis() - alarm.time.timestamp_millis();
+                    heatmap.add(alarm.time, open_ms.max(0) as u64 / 1000);
+                }
+            }
+        }
+        info!(
+            "{} alarms in the heatmap",
+            heatmap
+                .buckets
+                .iter()
+                .flatten()
+                .map(|b| b.count)
+                .sum::<u64>()
+        );
+        Ok(heatmap)
+    }
+
+    fn add(&mut self, time: bson::DateTime, duration_sec: u64) {
+        let local = time.to_chrono().with_timezone(&self.tz);
+        let bucket = &mut self.buckets[local.weekday().num_days_from_monday() as usize]
+            [local.hour() as usize];
+        bucket.count += 1;
+        bucket.total_sec += duration_sec;
+    }
+
+    /// Value of a bucket, None for an average without alarms
+    fn value(&self, bucket: &Bucket) -> Option<f64> {
+        match self.metric {
+            HeatmapMetric::Count => Some(bucket.count as f64),
+            HeatmapMetric::AvgDuration if bucket.count == 0 => None,
+            HeatmapMetric::AvgDuration => Some(bucket.total_sec as f64 / bucket.count as f64),
+        }
+    }
+
+    /// One row per weekday with a column per hour, `00` to `23`, averages in whole seconds
+    pub fn to_documents(&self) -> Vec<bson::Document> {
+        WEEKDAYS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(weekday, hours)| {
+                let mut row = doc! { "weekday": *weekday };
+                for (hour, bucket) in hours.iter().enumerate() {
+                    let value = self.value(bucket).map(|v| v.round() as i64);
+                    row.insert(format!("{hour:02}"), value);
+                }
+                row
+            })
+            .collect()
+    }
+
+    /// Table with every cell coloured from pale yellow for the lowest value to dark red
+    /// for the highest, averages as m:ss of the seconds the other formats have
+    pub fn to_table(&self) -> Table {
+        let max = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter_map(|b| self.value(b))
+            .fold(0.0, f64::max);
+        let mut table = Table::new();
+        table.load_preset(presets::UTF8_BORDERS_ONLY);
+        table.set_header(std::iter::once(String::new()).chain((0..24).map(|h| format!("{h:02}"))));
+        for (weekday, hours) in WEEKDAYS.iter().zip(&self.buckets) {
+            let cells = hours.iter().map(|bucket| match self.value(bucket) {
+                Some(value) if value > 0.0 => {
+                    let text = match self.metric {
+                        HeatmapMetric::Count => format!("{value}"),
+                        HeatmapMetric::AvgDuration => {
+                            let seconds = value.round() as u64;
+                            format!("{}:{:02}", seconds / 60, seconds % 60)
+                        }
+                    };
+                    let (background, foreground) = heat_color(value / max);
+                    Cell::new(text).bg(background).fg(foreground)
+                }
+                Some(_) | None => Cell::new(""),
+            });
+            table.add_row(std::iter::once(Cell::new(weekday)).chain(cells));
+        }
+        table
+    }
+
+    /// Writes the table or, for the other formats, the rows of [`to_documents`](Self::to_documents).
+    /// The table is coloured if `colors` is set and stdout is a terminal.
+    pub fn write(&self, format: OutputFormat, out: &mut dyn Write, colors: bool) -> Result<()> {
+        match format {
+            OutputFormat::Table => {
+                let mut table = self.to_table();
+                if !colors {
+                    table.force_no_tty();
+                }
+                writeln!(out, "{table}")?;
+                let unit = match self.metric {
+                    HeatmapMetric::Count => "alarms",
+                    HeatmapMetric::AvgDuration => "average time to clear in m:ss",
+                };
+                writeln!(out, "Weekday by hour in {}, {}", self.tz, unit)?;
+                Ok(())
+            }
+            format => utils::bson_write(&self.to_documents(), format, out),
+        }
+    }
+}
+
+/// Background and text colour for `heat` between 0 and 1
+fn heat_color(heat: f64) -> (Color, Color) {
+    // pale yellow, orange, dark red
+    const STOPS: [(f64, f64, f64); 3] = [
+        (255.0, 237.0, 160.0),
+        (253.0, 141.0, 60.0),
+        (189.0, 0.0, 38.0),
+    ];
+    let heat = heat.clamp(0.0, 1.0) * 2.0;
+    let (from, to, t) = if heat <= 1.0 {
+        (STOPS[0], STOPS[1], heat)
+    } else {
+        (STOPS[1], STOPS[2], heat - 1.0)
+    };
+    let mix = |a: f64, b: f64| (a + (b - a) * t).round() as u8;
+    let background = Color::Rgb {
+        r: mix(from.0, to.0),
+        g: mix(from.1, to.1),
+        b: mix(from.2, to.2),
+    };
+    let foreground = if heat > 1.2 {
+        Color::White
+    } else {
+        Color::Black
+    };
+    (background, foreground)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::{AlarmRef, Resident},
+        store::MemoryStore,
+    };
+
+    fn time(s: &str) -> bson::DateTime {
+        utils::parse_datetime(s).unwrap()
+    }
+
+    #[test]
+    fn buckets_by_local_weekday_and_hour() {
+        // Bucharest is UTC+3 in summer, 2024-07-15 is a Monday
+        let mut heatmap = Heatmap::new(HeatmapMetric::Count, chrono_tz::Europe::Bucharest);
+        heatmap.add(time("2024-07-14T22:30:00Z"), 60);
+        heatmap.add(time("2024-07-15T20:59:59Z"), 60);
+        heatmap.add(time("2024-07-15T21:00:00Z"), 60);
+        heatmap.add(time("2024-07-21T20:59:59Z"), 60);
+        assert_eq!(heatmap.buckets[0][1].count, 1);
+        assert_eq!(heatmap.buckets[0][23].count, 1);
+        assert_eq!(heatmap.buckets[1][0].count, 1);
+        assert_eq!(heatmap.buckets[6][23].count, 1);
+        assert_eq!(
+            heatmap
+                .buckets
+                .iter()
+                .flatten()
+                .map(|b| b.count)
+                .sum::<u64>(),
+            4
+        );
+    }
+
+    #[test]
+    fn documents_have_a_row_per_weekday_and_a_column_per_hour() {
+        let mut heatmap = Heatmap::new(HeatmapMetric::AvgDuration, Tz::UTC);
+        heatmap.add(time("2024-07-16T08:15:00Z"), 60);
+        heatmap.add(time("2024-07-16T08:45:00Z"), 91);
+        assert_eq!(heatmap.value(&heatmap.buckets[1][8]), Some(75.5));
+        assert_eq!(heatmap.value(&heatmap.buckets[1][9]), None);
+
+        let rows = heatmap.to_documents();
+        let weekdays: Vec<&str> = rows.iter().map(|r| r.get_str("weekday").unwrap()).collect();
+        assert_eq!(weekdays, WEEKDAYS);
+        let columns: Vec<&str> = rows[0].keys().map(String::as_str).collect();
+        let hours: Vec<String> = (0..24).map(|h| format!("{h:02}")).collect();
+        assert_eq!(columns[0], "weekday");
+        assert_eq!(columns[1..], hours);
+        // averages in whole seconds, empty buckets have none
+        assert_eq!(rows[1].get_i64("08").unwrap(), 76);
+        assert_eq!(rows[1].get("09"), Some(&bson::Bson::Null));
+
+        let mut counts = Heatmap::new(HeatmapMetric::Count, Tz::UTC);
+        counts.add(time("2024-07-16T08:15:00Z"), 60);
+        let rows = counts.to_documents();
+        assert_eq!(rows[1].get_i64("08").unwrap(), 1);
+        assert_eq!(rows[1].get_i64("09").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn active_alarms_in_the_window_are_included_on_request() {
+        let store = MemoryStore::new();
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        let now = bson::DateTime::now().timestamp_millis();
+        let minutes_ago = |minutes: i64| bson::DateTime::from_millis(now - minutes * 60 * 1000);
+        let cleared = minutes_ago(90);
+        store
+            .new_alarm(&ann.name, ann.birth, "call", Some(cleared))
+            .await
+            .unwrap();
+        store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Time(cleared), Some(60))
+            .await
+            .unwrap();
+        for minutes in [60, 300] {
+            store
+                .new_alarm(&ann.name, ann.birth, "call", Some(minutes_ago(minutes)))
+                .await
+                .unwrap();
+        }
+
+        let from_date = minutes_ago(120).try_to_rfc3339_string().unwrap();
+        let totals = |heatmap: &Heatmap| {
+            heatmap
+                .buckets
+                .iter()
+                .flatten()
+                .map(|b| (b.count, b.total_sec))
+                .fold((0, 0), |(c, t), (count, total)| (c + count, t + total))
+        };
+        let params = HeatmapParams::parse_from(["heatmap", "--from-date", &from_date]);
+        let heatmap = Heatmap::build(&store, &params).await.unwrap();
+        assert_eq!(totals(&heatmap), (1, 60));
+
+        let params = HeatmapParams::parse_from(["heatmap", "--active", "--from-date", &from_date]);
+        let heatmap = Heatmap::build(&store, &params).await.unwrap();
+        // the alarm raised before the window is left out, the other one has been open an hour
+        let (alarms, total_sec) = totals(&heatmap);
+        assert_eq!(alarms, 2);
+        assert!((60 + 3600..60 + 3660).contains(&total_sec), "{total_sec}");
+    }
+}
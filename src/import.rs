@@ -0,0 +1,530 @@
+//! Batched resident import from CSV.
+//!
+//! Rows are validated first, then written with [`ResidentStore::write_batch`], one round
+//! trip per batch instead of one per row. Rows that are rejected or fail to write are
+//! reported with their CSV line and copied to a reject file with a reason column, also
+//! rows that are not valid UTF-8 or cannot be read as CSV.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use csv::{ByteRecord, ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use mongodb::bson;
+use tokio::time::Instant;
+use tracing::{Level, debug, info, warn};
+
+use crate::{
+    model::{Resident, ResidentCsv},
+    store::{BatchOutcome, ResidentStore},
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct ImportParams {
+    #[clap(
+        long,
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..=100_000),
+        help = "Rows written per bulk write"
+    )]
+    pub batch_size: u64,
+    #[clap(
+        long,
+        help = "Stop at the first rejected or failed row instead of importing the remaining ones"
+    )]
+    pub ordered: bool,
+    #[clap(long, help = "Only check the file, nothing is written to the store")]
+    pub validate_only: bool,
+    #[clap(
+        long,
+        help = "CSV file receiving rejected and failed rows with a reason column [default: <file>.rejects.csv]"
+    )]
+    pub reject_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub validate_only: bool,
+    /// rows that passed validation, only counted with `--validate-only`
+    pub valid: u64,
+    pub inserted: u64,
+    pub updated: u64,
+    /// rows that failed validation
+    pub rejected: u64,
+    /// valid rows the store did not write
+    pub failed: u64,
+    /// set when `--ordered` stopped the import at a rejected or failed row
+    pub stopped_at_line: Option<u64>,
+    /// rows not written because `--ordered` stopped the import: the rest of the batch
+    /// holding the failed row and every row after the stop
+    pub skipped: u64,
+    /// reject file, if any row was written to it
+    pub reject_file: Option<PathBuf>,
+    pub elapsed: Duration,
+}
+
+impl ImportSummary {
+    pub fn rows(&self) -> u64 {
+        self.valid + self.inserted + self.updated + self.rejected + self.failed
+    }
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.elapsed.as_millis().max(1);
+        if self.validate_only {
+            write!(
+                f,
+                "Validated {} rows in {} ms: {} valid, {} rejected",
+                self.rows(),
+                millis,
+                self.valid,
+                self.rejected,
+            )?;
+        } else {
+            write!(
+                f,
+                "Imported {} rows in {} ms ({} rows/s): {} inserted, {} updated, {} rejected, {} failed",
+                self.rows(),
+                millis,
+                self.rows() as u128 * 1000 / millis,
+                self.inserted,
+                self.updated,
+                self.rejected,
+                self.failed,
+            )?;
+        }
+        if let Some(line) = self.stopped_at_line {
+            write!(f, ", stopped at line {}, {} skipped", line, self.skipped)?;
+        }
+        if let Some(path) = &self.reject_file {
+            write!(f, ", rejects in {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Imports residents from a CSV file with `name,birth,location,resident_since` columns
+pub async fn import_csv(
+    store: &dyn ResidentStore,
+    path: &Path,
+    upsert: bool,
+    params: &ImportParams,
+) -> Result<ImportSummary> {
+    run(Some(store), path, upsert, params).await
+}
+
+/// Checks a CSV file like [`import_csv`] with `--validate-only`, without a store
+pub async fn validate_csv(path: &Path, params: &ImportParams) -> Result<ImportSummary> {
+    let params = ImportParams {
+        validate_only: true,
+        ..params.clone()
+    };
+    run(None, path, false, &params).await
+}
+
+#[tracing::instrument(name = "import_csv", skip(store, params), level = Level::TRACE)]
+async fn run(
+    store: Option<&dyn ResidentStore>,
+    path: &Path,
+    upsert: bool,
+    params: &ImportParams,
+) -> Result<ImportSummary> {
+    let start = Instant::now();
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        // rows with missing or extra columns are rejected instead of ending the import
+        .flexible(true)
+        .from_path(path)
+        .with_context(|| format!("cannot read {}", path.display()))?;
+    let headers = StringRecord::from_byte_record(reader.byte_headers()?.clone())
+        .with_context(|| format!("invalid UTF-8 in the header of {}", path.display()))?;
+    let reject_file = params
+        .reject_file
+        .clone()
+        .unwrap_or_else(|| path.with_extension("rejects.csv"));
+    let mut rejects = Rejects::create(reject_file, &headers)?;
+    let mut validator = Validator::default();
+    let mut summary = ImportSummary {
+        validate_only: params.validate_only,
+        ..Default::default()
+    };
+    let mut batch = Batch::default();
+
+    let mut raw = ByteRecord::new();
+    loop {
+        // rows are read as bytes, so a row that is not UTF-8 is rejected like any invalid row
+        let read = reader.read_byte_record(&mut raw);
+        let line = raw
+            .position()
+            .map_or_else(|| reader.position().line(), |p| p.line());
+        let checked = match read {
+            Ok(false) => break,
+            Ok(true) => match StringRecord::from_byte_record(raw.clone()) {
+                Ok(record) => {
+                    let checked = validator.check(&record, &headers, line);
+                    (record, checked)
+                }
+                Err(e) => {
+                    let record = StringRecord::from_byte_record_lossy(raw.clone());
+                    let column = e.utf8_error().field();
+                    let column = headers.get(column).unwrap_or("row");
+                    (record, Err(format!("invalid UTF-8 in {}", column)))
+                }
+            },
+            Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => {
+                return Err(e).with_context(|| format!("cannot read {}", path.display()));
+            }
+            Err(e) => (StringRecord::new(), Err(format!("malformed row: {}", e))),
+        };
+        let (record, resident) = checked;
+        let resident = match resident {
+            Ok(resident) => resident,
+            Err(reason) => {
+                warn!("Line {}: {}", line, reason);
+                rejects.write(&record, line, &reason)?;
+                summary.rejected += 1;
+                if params.ordered && !params.validate_only {
+                    summary.stopped_at_line = Some(line);
+                    summary.skipped += count_remaining(&mut reader, path)?;
+                    break;
+                }
+                continue;
+            }
+        };
+        if params.validate_only {
+            summary.valid += 1;
+            continue;
+        }
+        debug!("Importing {}", resident);
+        batch.records.push((line, record));
+        batch.residents.push(resident);
+        if batch.residents.len() as u64 >= params.batch_size {
+            write_batch(
+                store,
+                &mut batch,
+                upsert,
+                params,
+                &mut rejects,
+                &mut summary,
+            )
+            .await?;
+            if summary.stopped_at_line.is_some() {
+                summary.skipped += count_remaining(&mut reader, path)?;
+                break;
+            }
+        }
+    }
+    // rows before a stop are still written, a failed write already emptied the batch
+    write_batch(
+        store,
+        &mut batch,
+        upsert,
+        params,
+        &mut rejects,
+        &mut summary,
+    )
+    .await?;
+    summary.reject_file = rejects.finish()?;
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
+
+/// Counts the rows left after an ordered stop, malformed ones included
+fn count_remaining(reader: &mut csv::Reader<File>, path: &Path) -> Result<u64> {
+    let mut raw = ByteRecord::new();
+    let mut rows = 0;
+    loop {
+        match reader.read_byte_record(&mut raw) {
+            Ok(false) => return Ok(rows),
+            Ok(true) => rows += 1,
+            Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => {
+                return Err(e).with_context(|| format!("cannot read {}", path.display()));
+            }
+            Err(_) => rows += 1,
+        }
+    }
+}
+
+/// Row checks, including duplicates of earlier rows of the same file
+#[derive(Default)]
+struct Validator {
+    /// line of the first row of every name and birth date
+    seen: HashMap<(String, bson::DateTime), u64>,
+}
+
+impl Validator {
+    fn check(
+        &mut self,
+        record: &StringRecord,
+        headers: &StringRecord,
+        line: u64,
+    ) -> Result<Resident, String> {
+        if record.len() != headers.len() {
+            return Err(format!(
+                "expected {} columns, found {}",
+                headers.len(),
+                record.len()
+            ));
+        }
+        let row = record
+            .deserialize::<ResidentCsv>(Some(headers))
+            .map_err(|e| match e.kind() {
+                csv::ErrorKind::Deserialize { err, .. } => {
+                    let column = err.field().and_then(|i| headers.get(i as usize));
+                    match column {
+                        Some(column) => format!("invalid {}: {}", column, err.kind()),
+                        None => err.kind().to_string(),
+                    }
+                }
+                _ => e.to_string(),
+            })?;
+        let resident = Resident::from(row);
+        if resident.name.trim().is_empty() {
+            return Err("empty name".to_string());
+        }
+        if resident.location.trim().is_empty() {
+            return Err("empty location".to_string());
+        }
+        if resident.birth > bson::DateTime::now() {
+            return Err("birth date in the future".to_string());
+        }
+        if resident.resident_since < resident.birth {
+            return Err("resident_since before birth date".to_string());
+        }
+        match self.seen.entry((resident.name.clone(), resident.birth)) {
+            Entry::Occupied(first) => Err(format!("duplicate of line {}", first.get())),
+            Entry::Vacant(entry) => {
+                entry.insert(line);
+                Ok(resident)
+            }
+        }
+    }
+}
+
+/// Reject CSV with the input columns followed by `line` and `reason`. A file left by an
+/// earlier run is replaced, and removed again if no row is rejected.
+struct Rejects {
+    path: PathBuf,
+    writer: Writer<File>,
+    rows: u64,
+}
+
+impl Rejects {
+    fn create(path: PathBuf, headers: &StringRecord) -> Result<Self> {
+        let mut writer = WriterBuilder::new()
+            // rows with a wrong number of columns are copied as they are
+            .flexible(true)
+            .from_path(&path)
+            .with_context(|| format!("cannot create {}", path.display()))?;
+        let mut headers = headers.clone();
+        headers.push_field("line");
+        headers.push_field("reason");
+        writer.write_record(&headers)?;
+        Ok(Rejects {
+            path,
+            writer,
+            rows: 0,
+        })
+    }
+
+    fn write(&mut self, record: &StringRecord, line: u64, reason: &str) -> Result<()> {
+        let mut row = record.clone();
+        row.push_field(&line.to_string());
+        row.push_field(reason);
+        self.writer.write_record(&row)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Flushes the file, returns its path if any row was rejected
+    fn finish(mut self) -> Result<Option<PathBuf>> {
+        self.writer.flush()?;
+        if self.rows == 0 {
+            drop(self.writer);
+            std::fs::remove_file(&self.path)
+                .with_context(|| format!("cannot remove {}", self.path.display()))?;
+            return Ok(None);
+        }
+        Ok(Some(self.path))
+    }
+}
+
+/// Residents waiting to be written with their CSV lines and records
+#[derive(Default)]
+struct Batch {
+    records: Vec<(u64, StringRecord)>,
+    residents: Vec<Resident>,
+}
+
+/// Writes and empties `batch`, adding the outcome to `summary`
+async fn write_batch(
+    store: Option<&dyn ResidentStore>,
+    batch: &mut Batch,
+    upsert: bool,
+    params: &ImportParams,
+    rejects: &mut Rejects,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    if batch.residents.is_empty() {
+        return Ok(());
+    }
+    let Some(store) = store else {
+        anyhow::bail!("no store to import into");
+    };
+    let start = Instant::now();
+    let outcome = store
+        .write_batch(&batch.residents, upsert, params.ordered)
+        .await?;
+    debug!(
+        "Wrote batch of {} rows in {} ms",
+        batch.residents.len(),
+        start.elapsed().as_millis()
+    );
+    record_outcome(batch, &outcome, params.ordered, rejects, summary)?;
+    info!(
+        "{} rows imported so far",
+        summary.inserted + summary.updated
+    );
+    Ok(())
+}
+
+/// Adds the outcome of writing `batch` to `summary` and empties the batch. Failed rows
+/// go to the reject file, rows an ordered write did not reach are skipped.
+fn record_outcome(
+    batch: &mut Batch,
+    outcome: &BatchOutcome,
+    ordered: bool,
+    rejects: &mut Rejects,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    summary.inserted += outcome.inserted;
+    summary.updated += outcome.updated;
+    summary.failed += outcome.failed.len() as u64;
+    for (index, reason) in &outcome.failed {
+        let Some((line, record)) = batch.records.get(*index) else {
+            continue;
+        };
+        warn!("Line {}: {}", line, reason);
+        rejects.write(record, *line, reason)?;
+        if ordered {
+            summary.stopped_at_line = Some(*line);
+        }
+    }
+    if ordered && !outcome.failed.is_empty() {
+        let written = outcome.inserted + outcome.updated + outcome.failed.len() as u64;
+        summary.skipped += (batch.residents.len() as u64).saturating_sub(written);
+    }
+    batch.records.clear();
+    batch.residents.clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[tokio::test]
+    async fn bad_rows_are_rejected_with_their_line() {
+        let dir = std::env::temp_dir().join(format!("import-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("residents.csv");
+        let reject_file = dir.join("rejects.csv");
+        std::fs::write(&reject_file, "left over\n").unwrap();
+        std::fs::write(
+            &path,
+            b"name,birth,location,resident_since\n\
+              Anna,1940-01-02,R1,2020-01-01\n\
+              B\xffob,1941-01-02,R2,2020-01-01\n\
+              Carl,1943-01-01\n",
+        )
+        .unwrap();
+        let params =
+            ImportParams::parse_from(["import", "--reject-file", reject_file.to_str().unwrap()]);
+        let store = MemoryStore::new();
+        let summary = import_csv(&store, &path, false, &params).await.unwrap();
+        assert_eq!((summary.inserted, summary.rejected), (1, 2));
+        let rejects = std::fs::read_to_string(&reject_file).unwrap();
+        let lines: Vec<&str> = rejects.lines().collect();
+        assert_eq!(lines[0], "name,birth,location,resident_since,line,reason");
+        assert!(
+            lines[1].ends_with(",3,invalid UTF-8 in name"),
+            "{}",
+            lines[1]
+        );
+        assert!(lines[2].starts_with("Carl,1943-01-01,4,"), "{}", lines[2]);
+        assert_eq!(lines.len(), 3);
+
+        // validating needs no store, a clean file leaves no reject file behind
+        std::fs::write(&path, "name,birth,location,resident_since\n").unwrap();
+        let summary = validate_csv(&path, &params).await.unwrap();
+        assert!(summary.reject_file.is_none());
+        assert!(!reject_file.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ordered_import_stops_at_a_rejected_row() {
+        let dir = std::env::temp_dir().join(format!("import-ordered-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("residents.csv");
+        std::fs::write(
+            &path,
+            "name,birth,location,resident_since\n\
+             Anna,1940-01-02,R1,2020-01-01\n\
+             Bert,1941-01-02,R2,2020-01-01\n\
+             Carl,1943-01-01\n\
+             Dora,1944-01-02,R4,2020-01-01\n\
+             Emil,1945-01-02,R5,2020-01-01\n",
+        )
+        .unwrap();
+        let params = ImportParams::parse_from(["import", "--ordered", "--batch-size", "10"]);
+        let store = MemoryStore::new();
+        let summary = import_csv(&store, &path, false, &params).await.unwrap();
+        // the rows before the stop are still written
+        assert_eq!((summary.inserted, summary.rejected), (2, 1));
+        assert_eq!(summary.stopped_at_line, Some(4));
+        assert_eq!(summary.skipped, 2);
+        assert!(summary.to_string().contains("stopped at line 4, 2 skipped"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ordered_batch_failure_skips_the_rest_of_the_batch() {
+        let dir = std::env::temp_dir().join(format!("import-outcome-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let headers = StringRecord::from(vec!["name", "birth", "location", "resident_since"]);
+        let mut rejects = Rejects::create(dir.join("rejects.csv"), &headers).unwrap();
+        let mut batch = Batch::default();
+        for (line, name) in [(2, "Anna"), (3, "Bert"), (4, "Carl"), (5, "Dora")] {
+            let row = [name, "1940-01-02", "R1", "2020-01-01"];
+            batch.records.push((line, StringRecord::from(row.to_vec())));
+            batch
+                .residents
+                .push(Resident::new(name, row[1], row[2], row[3]).unwrap());
+        }
+        let outcome = BatchOutcome {
+            inserted: 1,
+            updated: 0,
+            failed: vec![(1, "duplicate key".to_string())],
+        };
+        let mut summary = ImportSummary::default();
+        record_outcome(&mut batch, &outcome, true, &mut rejects, &mut summary).unwrap();
+        assert_eq!((summary.inserted, summary.failed), (1, 1));
+        assert_eq!(summary.stopped_at_line, Some(3));
+        assert_eq!(summary.skipped, 2);
+        assert!(batch.residents.is_empty() && batch.records.is_empty());
+
+        let reject_file = rejects.finish().unwrap().unwrap();
+        let rejected = std::fs::read_to_string(reject_file).unwrap();
+        assert!(rejected.ends_with("Bert,1940-01-02,R1,2020-01-01,3,duplicate key\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+pub mod config;
+pub mod dump;
+pub mod escalation;
+pub mod heatmap;
+pub mod import;
+pub mod listener;
+pub mod model;
+pub mod server;
+pub mod sla;
+pub mod store;
+pub mod utils;
+
+pub use model::{
+    ActiveAlarm, Alarm, AlarmEvent, AlarmRef, AlarmState, HistoryAlarm, Resident, ResidentCsv,
+    SCHEMA_VERSION,
+};
+pub use store::{
+    AlarmWindow, BatchOutcome, MemoryStore, MongoStore, NotifyingStore, QueryParams, ReportParams,
+    ResidentStore, StoreError, WriteOutcome,
+};
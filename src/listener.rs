@@ -0,0 +1,391 @@
+//! Line protocol listener for nurse-call buttons and sensors.
+//!
+//! Each frame is one line `DEVICE_ID EVENT [message]`, where EVENT is `RAISE`, `ACK` or
+//! `CLEAR` (case-insensitive). Devices are resolved to residents through a CSV device map
+//! with `device_id,name,birth[,location]` columns. Every frame is answered with `OK ...`
+//! or `ERR ...`; frames that cannot be applied are appended to the dead-letter log.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write as _,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use csv::ReaderBuilder;
+use futures::StreamExt as _;
+use mongodb::bson;
+use tokio::{
+    io::AsyncWriteExt as _,
+    net::{TcpListener, UdpSocket},
+};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tracing::{Level, debug, error, info, warn};
+
+use crate::{model::AlarmRef, store::ResidentStore, utils};
+
+/// Longer frames are rejected, a TCP connection sending one is closed
+pub const MAX_FRAME_LEN: usize = 512;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    pub name: String,
+    #[serde(with = "utils::serde_helpers::bson_dateonly")]
+    pub birth: bson::DateTime,
+    /// where the device is installed, e.g. "Room 101 bathroom"
+    #[serde(default)]
+    pub location: Option<String>,
+}
+
+impl Device {
+    /// Suffix identifying alarms raised by this device, used to find them on CLEAR and ACK
+    fn tag(&self) -> String {
+        format!("[{}]", self.device_id)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeviceMap {
+    devices: HashMap<String, Device>,
+}
+
+impl DeviceMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("cannot read device map {}", path.display()))?;
+        let mut devices = HashMap::new();
+        for result in reader.deserialize::<Device>() {
+            let device =
+                result.with_context(|| format!("invalid device map {}", path.display()))?;
+            if let Some(previous) = devices.insert(device.device_id.clone(), device) {
+                anyhow::bail!(
+                    "device {} listed twice in {}",
+                    previous.device_id,
+                    path.display()
+                );
+            }
+        }
+        info!("Loaded {} devices from {}", devices.len(), path.display());
+        Ok(DeviceMap { devices })
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&Device> {
+        self.devices.get(device_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameEvent {
+    Raise,
+    Ack,
+    Clear,
+}
+
+#[derive(Debug)]
+struct Frame<'a> {
+    device_id: &'a str,
+    event: FrameEvent,
+    message: Option<&'a str>,
+}
+
+fn parse_frame(line: &str) -> Result<Frame<'_>, String> {
+    if line.len() > MAX_FRAME_LEN {
+        return Err(format!("frame longer than {} bytes", MAX_FRAME_LEN));
+    }
+    // fields may be separated by runs of whitespace, the message keeps its inner spacing
+    let line = line.trim();
+    let (device_id, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if device_id.is_empty() {
+        return Err("empty frame".to_string());
+    }
+    let rest = rest.trim_start();
+    let (event, message) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let event = match event.to_ascii_uppercase().as_str() {
+        "RAISE" => FrameEvent::Raise,
+        "ACK" => FrameEvent::Ack,
+        "CLEAR" => FrameEvent::Clear,
+        "" => return Err("missing event".to_string()),
+        other => return Err(format!("unknown event '{}'", other)),
+    };
+    let message = Some(message.trim()).filter(|m| !m.is_empty());
+    Ok(Frame {
+        device_id,
+        event,
+        message,
+    })
+}
+
+#[derive(serde::Serialize)]
+struct DeadLetter<'a> {
+    #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
+    time: bson::DateTime,
+    transport: &'a str,
+    peer: String,
+    frame: &'a str,
+    reason: &'a str,
+}
+
+pub struct Listener {
+    store: Arc<dyn ResidentStore>,
+    devices: DeviceMap,
+    dead_letter: Mutex<File>,
+}
+
+impl Listener {
+    pub fn new(
+        store: Arc<dyn ResidentStore>,
+        devices: DeviceMap,
+        dead_letter: &Path,
+    ) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dead_letter)
+            .with_context(|| format!("cannot open dead-letter log {}", dead_letter.display()))?;
+        Ok(Listener {
+            store,
+            devices,
+            dead_letter: Mutex::new(file),
+        })
+    }
+
+    /// Serves TCP and/or UDP until Ctrl-C
+    pub async fn run(
+        self: Arc<Self>,
+        tcp: Option<SocketAddr>,
+        udp: Option<SocketAddr>,
+    ) -> Result<()> {
+        if tcp.is_none() && udp.is_none() {
+            anyhow::bail!("nothing to listen on: pass --tcp and/or --udp");
+        }
+        let mut tasks = tokio::task::JoinSet::new();
+        if let Some(addr) = tcp {
+            let listener = TcpListener::bind(addr).await?;
+            info!(
+                "Listening for device frames on tcp://{}",
+                listener.local_addr()?
+            );
+            tasks.spawn(self.clone().serve_tcp(listener));
+        }
+        if let Some(addr) = udp {
+            let socket = UdpSocket::bind(addr).await?;
+            info!(
+                "Listening for device frames on udp://{}",
+                socket.local_addr()?
+            );
+            tasks.spawn(self.clone().serve_udp(socket));
+        }
+        tokio::select! {
+            Some(result) = tasks.join_next() => result?,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping listener");
+                Ok(())
+            }
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            debug!("Device connection from {}", peer);
+            let this = self.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                // bounded, so a peer never sending a newline cannot exhaust memory
+                let mut lines =
+                    FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_FRAME_LEN));
+                loop {
+                    let line = match lines.next().await {
+                        Some(Ok(line)) => line,
+                        None => break,
+                        Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                            let reason = format!("frame longer than {} bytes", MAX_FRAME_LEN);
+                            warn!("Closing device connection {}: {}", peer, reason);
+                            this.dead_letter("tcp", peer, "", &reason);
+                            writer
+                                .write_all(format!("ERR {reason}\n").as_bytes())
+                                .await
+                                .ok();
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            warn!("Device connection {} failed: {}", peer, e);
+                            break;
+                        }
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let reply = this.handle("tcp", peer, &line).await;
+                    if writer
+                        .write_all(format!("{reply}\n").as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                debug!("Device connection from {} closed", peer);
+            });
+        }
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let mut buf = vec![0u8; 4 * MAX_FRAME_LEN];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let datagram = String::from_utf8_lossy(&buf[..len]).into_owned();
+            let mut replies = String::new();
+            for line in datagram.lines().filter(|l| !l.trim().is_empty()) {
+                replies.push_str(&self.handle("udp", peer, line).await);
+                replies.push('\n');
+            }
+            if let Err(e) = socket.send_to(replies.as_bytes(), peer).await {
+                debug!("Cannot reply to {}: {}", peer, e);
+            }
+        }
+    }
+
+    /// Applies one frame, returns the reply line
+    #[tracing::instrument(name = "device_frame", skip(self), level = Level::TRACE)]
+    async fn handle(&self, transport: &str, peer: SocketAddr, line: &str) -> String {
+        match self.apply(line).await {
+            Ok(reply) => format!("OK {reply}"),
+            Err(reason) => {
+                warn!("Rejected frame '{}' from {}: {}", line, peer, reason);
+                self.dead_letter(transport, peer, line, &reason);
+                format!("ERR {reason}")
+            }
+        }
+    }
+
+    async fn apply(&self, line: &str) -> Result<String, String> {
+        let frame = parse_frame(line)?;
+        let device = self
+            .devices
+            .get(frame.device_id)
+            .ok_or_else(|| format!("unknown device '{}'", frame.device_id))?;
+        match frame.event {
+            FrameEvent::Raise => {
+                let text = frame.message.unwrap_or("call");
+                let message = match &device.location {
+                    Some(location) => format!("{} @ {} {}", text, location, device.tag()),
+                    None => format!("{} {}", text, device.tag()),
+                };
+                let alarm = self
+                    .store
+                    .new_alarm(&device.name, device.birth, &message, None)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(alarm.id.to_hex())
+            }
+            FrameEvent::Ack | FrameEvent::Clear => {
+                let resident = self
+                    .store
+                    .find(&device.name, device.birth)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| {
+                        format!("resident of device '{}' not found", device.device_id)
+                    })?;
+                // every active alarm this device raised
+                let alarms = resident
+                    .active_alarms
+                    .iter()
+                    .filter(|a| a.message.ends_with(&device.tag()))
+                    .map(|a| a.id)
+                    .collect::<Vec<_>>();
+                if alarms.is_empty() {
+                    return Err(format!("no active alarm of device '{}'", device.device_id));
+                }
+                let mut done = Vec::new();
+                for id in alarms {
+                    let result = if frame.event == FrameEvent::Ack {
+                        self.store
+                            .ack_alarm(
+                                &device.name,
+                                device.birth,
+                                AlarmRef::Id(id),
+                                &device.device_id,
+                            )
+                            .await
+                            .map(|a| a.is_some())
+                    } else {
+                        self.store
+                            .clear_alarm(&device.name, device.birth, AlarmRef::Id(id), None)
+                            .await
+                            .map(|a| a.is_some())
+                    };
+                    if result.map_err(|e| e.to_string())? {
+                        done.push(id.to_hex());
+                    }
+                }
+                Ok(done.join(" "))
+            }
+        }
+    }
+
+    fn dead_letter(&self, transport: &str, peer: SocketAddr, frame: &str, reason: &str) {
+        let record = DeadLetter {
+            time: bson::DateTime::now(),
+            transport,
+            peer: peer.to_string(),
+            frame,
+            reason,
+        };
+        let mut file = self.dead_letter.lock().unwrap_or_else(|e| e.into_inner());
+        let written = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(file, "{}", line)?));
+        if let Err(e) = written {
+            error!("Cannot write dead-letter log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_frames() {
+        let frame = parse_frame("btn-7 raise  fall in bathroom ").unwrap();
+        assert_eq!(frame.device_id, "btn-7");
+        assert_eq!(frame.event, FrameEvent::Raise);
+        assert_eq!(frame.message, Some("fall in bathroom"));
+
+        let frame = parse_frame("  btn-7 CLEAR").unwrap();
+        assert_eq!(frame.event, FrameEvent::Clear);
+        assert_eq!(frame.message, None);
+        assert_eq!(parse_frame("btn-7 Ack ").unwrap().event, FrameEvent::Ack);
+
+        let frame = parse_frame("btn-7  RAISE\t fall  in bathroom").unwrap();
+        assert_eq!(frame.device_id, "btn-7");
+        assert_eq!(frame.event, FrameEvent::Raise);
+        assert_eq!(frame.message, Some("fall  in bathroom"));
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        assert_eq!(parse_frame("").unwrap_err(), "empty frame");
+        assert_eq!(parse_frame("btn-7").unwrap_err(), "missing event");
+        assert_eq!(parse_frame("btn-7   ").unwrap_err(), "missing event");
+        assert_eq!(
+            parse_frame("btn-7 reset now").unwrap_err(),
+            "unknown event 'RESET'"
+        );
+        let long = format!("btn-7 RAISE {}", "x".repeat(MAX_FRAME_LEN));
+        assert_eq!(
+            parse_frame(&long).unwrap_err(),
+            format!("frame longer than {MAX_FRAME_LEN} bytes")
+        );
+    }
+}
@@ -1,19 +1,30 @@
-use std::{fmt, time::Duration};
+use std::{io::Write as _, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use clap::{Parser, Subcommand, arg, command};
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand, ValueEnum};
 use csv::ReaderBuilder;
-use futures::TryStreamExt as _;
-use mongodb::{
-    Client, Collection, IndexModel,
-    bson::{self, doc},
-    error::{WriteError, WriteFailure},
-    options::{ClientOptions, IndexOptions, ServerApi, ServerApiVersion},
+use mongodb::bson;
+use mongodb_test::{
+    AlarmRef, MemoryStore, MongoStore, NotifyingStore, QueryParams, ReportParams, Resident,
+    ResidentCsv, ResidentStore,
+    config::{Config, EscalationConfig},
+    dump::{self, ExportParams, RestoreParams},
+    escalation::Escalator,
+    heatmap::{Heatmap, HeatmapParams},
+    import::{self, ImportParams},
+    listener::{DeviceMap, Listener},
+    server,
+    sla::{self, SlaParams},
+    store::{
+        migrations,
+        watch::{self, WatchParams},
+    },
+    utils::{self, DateTimeStr, OutputFormat},
 };
 use rand::seq::IteratorRandom as _;
-use tokio::time::Instant;
-use tracing::{Level, debug, error, info, warn};
-use utils::DateTimeStr;
+use tokio::{sync::broadcast, time::Instant};
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -23,29 +34,122 @@ struct Cli {
 
     #[arg(long)]
     upsert: bool,
+
+    #[arg(long, value_enum, default_value_t = Backend::Mongo, help = "Storage backend")]
+    backend: Backend,
+
+    #[arg(
+        long,
+        help = "JSON file to load and persist the memory backend (implies --backend memory)"
+    )]
+    memory_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "TOML config file with connection profiles [default: mongodb-test.toml if present]"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(long, help = "Profile from the config file [default: default_profile]")]
+    profile: Option<String>,
+
+    #[arg(long, help = "MongoDB URI, overrides the profile and MONGODB_URI")]
+    uri: Option<String>,
+
+    #[arg(long, help = "Database name, overrides the profile")]
+    database: Option<String>,
+
+    #[arg(long, help = "Collection name, overrides the profile")]
+    collection: Option<String>,
+
+    #[arg(
+        long,
+        help = "Accept a standalone MongoDB server without transactions, alarm clears are then not atomic"
+    )]
+    allow_standalone: bool,
+
+    #[arg(
+        long,
+        help = "Facility timezone (IANA name, e.g. Europe/Bucharest) for date-only and naive date/time inputs and displayed times [default: UTC]"
+    )]
+    tz: Option<Tz>,
+}
+
+const DEFAULT_CONFIG_FILE: &str = "mongodb-test.toml";
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    Mongo,
+    Memory,
+}
+
+#[derive(Parser)]
+struct QueryCommand {
+    #[command(flatten)]
+    params: QueryParams,
+    #[clap(long, help = "CSV File to Save Results")]
+    csv: Option<String>,
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    #[clap(short, long, help = "File to write the results to [default: stdout]")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct ReportCommand {
+    #[command(flatten)]
+    params: ReportParams,
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    #[clap(short, long, help = "File to write the report to [default: stdout]")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct SlaCommand {
+    #[command(flatten)]
+    params: SlaParams,
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    #[clap(short, long, help = "File to write the summary to [default: stdout]")]
+    output: Option<PathBuf>,
 }
 
 #[derive(Parser)]
-struct QueryParams {
+struct HeatmapCommand {
+    #[command(flatten)]
+    params: HeatmapParams,
+    #[clap(long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    #[clap(short, long, help = "File to write the heatmap to [default: stdout]")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct WatchCommand {
+    #[command(flatten)]
+    params: WatchParams,
     #[clap(
-        default_value_t = 10,
-        help = "Maximum number of alarms to return per resident"
+        long,
+        help = "NDJSON file to append events to, in addition to the terminal"
     )]
-    alarms_limit: i32,
-    #[clap(short, long, help = "From Date (YYYY-MM-DD)")]
-    from_date: Option<String>,
-    #[clap(short, long, help = "To Date (YYYY-MM-DD)")]
-    to_date: Option<String>,
-    #[clap(short, long, help = "Optional Regexp pattern to match resident names")]
-    name: Option<String>,
+    ndjson: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct EscalateCommand {
     #[clap(
-        short,
         long,
-        help = "Optional Regexp pattern to match resident locations"
+        help = "Seconds between scans [default: escalation.interval_sec or 60]"
     )]
-    location: Option<String>,
-    #[clap(long, help = "CSV File to Save Results")]
-    csv: Option<String>,
+    interval: Option<u64>,
+    #[clap(long, help = "Scan once and exit")]
+    once: bool,
+    #[clap(
+        long,
+        help = "NDJSON file to append escalation events to, an event interrupted by a restart is appended again"
+    )]
+    ndjson: Option<PathBuf>,
 }
 
 #[derive(Parser)]
@@ -67,9 +171,16 @@ struct CsvBulkAlarmOptions {
 #[derive(Subcommand)]
 enum CliCommand {
     NewAlarmCsv(CsvBulkAlarmOptions),
+    #[clap(about = "Import residents from a CSV file in batches")]
     InsertCsv {
-        file_path: String,
+        file_path: PathBuf,
+        #[clap(flatten)]
+        params: ImportParams,
     },
+    #[clap(about = "Write residents with their alarms to an NDJSON dump")]
+    Export(ExportParams),
+    #[clap(about = "Restore residents and their alarms from an NDJSON dump")]
+    ImportDump(RestoreParams),
     Insert {
         name: String,
         birth: String,
@@ -88,7 +199,16 @@ enum CliCommand {
     ClearAlarm {
         name: String,
         birth: String,
-        alarm_time: String,
+        #[clap(help = "Alarm id, or the time the alarm was raised")]
+        alarm: String,
+    },
+    AckAlarm {
+        name: String,
+        birth: String,
+        #[clap(help = "Alarm id, or the time the alarm was raised")]
+        alarm: String,
+        #[clap(long, help = "Who acknowledges the alarm [default: $USER]")]
+        by: Option<String>,
     },
     ForceClose {
         name: String,
@@ -97,141 +217,133 @@ enum CliCommand {
     ForceCloseCsv {
         file_path: String,
     },
-    Query(QueryParams),
+    Query(Box<QueryCommand>),
+    #[clap(
+        about = "Alarm duration statistics grouped by location, message, month, weekday or hour"
+    )]
+    Report(ReportCommand),
+    #[clap(about = "Share of alarms cleared within each response-time tier, by location and shift")]
+    Sla(SlaCommand),
+    #[clap(about = "Alarms per weekday and hour of day as a coloured table or CSV, for staffing")]
+    Heatmap(HeatmapCommand),
+    #[clap(about = "Print alarm events as they happen (mongo 6.0+ only)")]
+    Watch(WatchCommand),
+    #[clap(about = "Serve the JSON HTTP API, see /openapi.json")]
+    Serve {
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        #[clap(
+            long,
+            help = "Feed /events from a change stream, also seeing changes made by other processes (mongo 6.0+ only)"
+        )]
+        watch: bool,
+    },
+    #[clap(about = "Accept `DEVICE_ID RAISE|ACK|CLEAR [message]` lines from nurse-call devices")]
+    Listen {
+        #[clap(long, help = "TCP address to listen on, e.g. 0.0.0.0:7000")]
+        tcp: Option<SocketAddr>,
+        #[clap(long, help = "UDP address to listen on, e.g. 0.0.0.0:7000")]
+        udp: Option<SocketAddr>,
+        #[clap(
+            long,
+            help = "CSV device map with device_id,name,birth[,location] columns"
+        )]
+        devices: PathBuf,
+        #[clap(
+            long,
+            default_value = "dead-letter.ndjson",
+            help = "NDJSON log of rejected frames"
+        )]
+        dead_letter: PathBuf,
+    },
+    #[clap(about = "Escalate active alarms by age using the rules from the config file")]
+    Escalate(EscalateCommand),
     SimpleTest,
     Stats,
+    #[clap(about = "Apply pending schema migrations (mongo only)")]
+    Migrate {
+        #[command(subcommand)]
+        command: Option<MigrateCommand>,
+        #[clap(long, help = "Only report what each pending migration would change")]
+        dry_run: bool,
+    },
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct Alarm {
-    time: bson::DateTime,
-    duration_sec: u64,
-    message: String,
-}
-
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct ActiveAlarm {
-    time: bson::DateTime,
-    message: String,
-}
-
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct Resident {
-    name: String,
-    birth: bson::DateTime,
-    location: String,
-    resident_since: bson::DateTime,
-    #[serde(default)]
-    alarms: Vec<Alarm>,
-    #[serde(default)]
-    active_alarms: Vec<ActiveAlarm>,
-}
-
-impl Resident {
-    fn new(name: &str, birth: &str, location: &str, resident_since: &str) -> Result<Self> {
-        Ok(Resident {
-            name: name.to_string(),
-            birth: DateTimeStr::Str(birth).into(),
-            location: location.to_string(),
-            resident_since: DateTimeStr::Str(resident_since).into(),
-            alarms: Vec::new(),
-            active_alarms: Vec::new(),
-        })
-    }
-
-    fn unique_index(&self) -> bson::Document {
-        doc! {
-            "name": &self.name,
-            "birth": &self.birth,
-        }
-    }
-
-    fn update_data(&self) -> bson::Document {
-        doc! {
-            "$set": {
-                "location": &self.location,
-                "resident_since": &self.resident_since,
-            }
-        }
-    }
-}
-
-impl fmt::Display for Resident {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "Resident {{ name: {}, birth: {}, location: {}, resident_since: {} }}",
-            self.name, self.birth, self.location, self.resident_since,
-        )?;
-        for active_alarm in &self.active_alarms {
-            write!(
-                f,
-                "\n  ActiveAlarm {{ time: {}, message: {} }}",
-                active_alarm.time, active_alarm.message,
-            )?;
-        }
-        for alarm in &self.alarms {
-            write!(
-                f,
-                "\n  HistoryAlarm {{ time: {}, duration_sec: {}, message: {} }}",
-                alarm.time, alarm.duration_sec, alarm.message,
-            )?;
-        }
-        Ok(())
-    }
-}
-
-#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
-struct ResidentCsv {
-    name: String,
-    #[serde(with = "utils::serde_helpers::bson_dateonly")]
-    birth: bson::DateTime,
-    location: String,
-    #[serde(with = "utils::serde_helpers::bson_dateonly")]
-    resident_since: bson::DateTime,
+#[derive(Subcommand)]
+enum MigrateCommand {
+    #[clap(about = "List migrations and residents per schema version")]
+    Status,
 }
 
-impl From<ResidentCsv> for Resident {
-    fn from(csv: ResidentCsv) -> Self {
-        Resident {
-            name: csv.name,
-            birth: csv.birth,
-            location: csv.location,
-            resident_since: csv.resident_since,
-            alarms: Vec::new(),
-            active_alarms: Vec::new(),
-        }
+/// Logs the import summary, fails if any row was not imported
+fn check_import(summary: &import::ImportSummary, file_path: &std::path::Path) -> Result<()> {
+    info!("{}", summary);
+    let not_imported = summary.rejected + summary.failed + summary.skipped;
+    if not_imported > 0 {
+        anyhow::bail!(
+            "{} rows of {} not imported",
+            not_imported,
+            file_path.display()
+        );
     }
+    Ok(())
 }
-mod utils;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut cli = Cli::parse();
-    tracing_subscriber::fmt::init();
+    // stdout carries the command output, JSON and CSV must not get log lines mixed in
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     dotenv::dotenv().ok();
-    let mongodb_uri = dotenv::var("MONGODB_URI").expect("MONGODB_URI must be set in .env");
-    let mut client_options = ClientOptions::parse(mongodb_uri).await?;
-
-    // Set the server_api field of the client_options object to set the version of the Stable API on the client
-    let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
-    client_options.server_api = Some(server_api);
-
-    // Get a handle to the cluster
-    let client = Client::with_options(client_options)?;
-
-    // Ping the server to see if you can connect to the cluster
-    client
-        .database("testdb")
-        .run_command(doc! {"ping": 1})
-        .await?;
-
-    let collection: Collection<Resident> = client.database("testdb").collection("test_collection");
-    let unique_index = IndexModel::builder()
-        .keys(doc! { "name": 1, "birth": 1 })
-        .options(Some(IndexOptions::builder().unique(true).build()))
-        .build();
-    collection.create_index(unique_index).await?;
+    if let Some(tz) = cli.tz {
+        utils::set_timezone(tz);
+    }
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            Config::load(DEFAULT_CONFIG_FILE)?
+        }
+        None => Config::default(),
+    };
+    // validating a CSV file reads no store, so it needs no database either
+    if let CliCommand::InsertCsv { file_path, params } = &cli.command
+        && params.validate_only
+    {
+        let summary = import::validate_csv(file_path, params).await?;
+        return check_import(&summary, file_path);
+    }
+    let mut mongo = None;
+    let shared_store: Arc<dyn ResidentStore> = if let Some(path) = &cli.memory_file {
+        Arc::new(MemoryStore::open(path)?)
+    } else if cli.backend == Backend::Memory {
+        Arc::new(MemoryStore::new())
+    } else {
+        let (profile_name, mut profile) = config.profile(cli.profile.as_deref())?;
+        // an explicit --uri wins, MONGODB_URI only fills in a profile without one
+        if let Some(uri) = cli.uri.take() {
+            profile.uri = Some(uri);
+        } else if profile.uri.is_none() {
+            profile.uri = dotenv::var("MONGODB_URI").ok();
+        }
+        if cli.database.is_some() {
+            profile.database = cli.database.take();
+        }
+        if cli.collection.is_some() {
+            profile.collection = cli.collection.take();
+        }
+        profile.allow_standalone |= cli.allow_standalone;
+        info!(
+            "Using profile '{}' namespace {}",
+            profile_name,
+            profile.namespace()
+        );
+        let mongo_store = MongoStore::connect(&profile).await?;
+        mongo = Some(mongo_store.clone());
+        Arc::new(mongo_store)
+    };
+    let store = shared_store.as_ref();
 
     match &mut cli.command {
         CliCommand::Insert {
@@ -242,79 +354,282 @@ async fn main() -> Result<()> {
         } => {
             let resident = Resident::new(name, birth, location, resident_since)?;
             if cli.upsert {
-                test_upsert(&collection, resident).await?;
+                store.upsert(&resident).await?;
             } else {
-                test_insert_or_update(&collection, resident).await?;
+                store.insert(&resident).await?;
             }
         }
         CliCommand::Delete { name, birth } => {
-            test_delete(&collection, name, birth).await?;
+            store
+                .delete(name, DateTimeStr::Str(birth).try_into()?)
+                .await?;
         }
         CliCommand::SimpleTest => {
-            simple_test(&collection).await?;
+            simple_test(store).await?;
         }
         CliCommand::NewAlarm {
             name,
             birth,
             message,
         } => {
-            test_new_alarm(&collection, name, birth, message, None).await?;
-        }
-        CliCommand::ClearAlarm {
+            store
+                .new_alarm(name, DateTimeStr::Str(birth).try_into()?, message, None)
+                .await?;
+        }
+        CliCommand::ClearAlarm { name, birth, alarm } => {
+            store
+                .clear_alarm(
+                    name,
+                    DateTimeStr::Str(birth).try_into()?,
+                    AlarmRef::parse(alarm)?,
+                    None,
+                )
+                .await?;
+        }
+        CliCommand::AckAlarm {
             name,
             birth,
-            alarm_time,
+            alarm,
+            by,
+        } => {
+            let by = by
+                .take()
+                .or_else(|| std::env::var("USER").ok())
+                .unwrap_or_else(|| "cli".to_string());
+            store
+                .ack_alarm(
+                    name,
+                    DateTimeStr::Str(birth).try_into()?,
+                    AlarmRef::parse(alarm)?,
+                    &by,
+                )
+                .await?;
+        }
+        CliCommand::Query(query) => {
+            let time = Instant::now();
+            let results = store.query(&query.params).await?;
+            info!("Query executed in {:?}", time.elapsed());
+            if let Some(cursor) = query.params.next_cursor(&results)? {
+                info!("Next page: --cursor {}", cursor);
+            }
+            if let Some(csv) = &query.csv {
+                utils::bson_to_csv(results, csv)?;
+            } else {
+                write_results(&results, query.format, query.output.as_ref())?;
+            }
+        }
+        CliCommand::Report(report) => {
+            let time = Instant::now();
+            let results = store.report(&report.params).await?;
+            info!(
+                "Report of {} groups executed in {:?}",
+                results.len(),
+                time.elapsed()
+            );
+            write_results(&results, report.format, report.output.as_ref())?;
+        }
+        CliCommand::Sla(command) => {
+            let report = sla::sla(store, &command.params).await?;
+            write_results(&report.rows, command.format, command.output.as_ref())?;
+            if let Some(path) = &command.params.breaches {
+                write_results(&report.breaches, command.params.breaches_format, Some(path))?;
+                info!(
+                    "Wrote {} breaches to {}",
+                    report.breaches.len(),
+                    path.display()
+                );
+            }
+        }
+        CliCommand::Heatmap(command) => {
+            let heatmap = Heatmap::build(store, &command.params).await?;
+            match &command.output {
+                Some(path) => {
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                    heatmap.write(command.format, &mut file, false)?;
+                    file.flush()?;
+                }
+                None => heatmap.write(command.format, &mut std::io::stdout().lock(), true)?,
+            }
+        }
+        CliCommand::Watch(command) => {
+            let Some(mongo) = &mongo else {
+                anyhow::bail!("watch needs the mongo backend");
+            };
+            let mut ndjson = match &command.ndjson {
+                Some(path) => Some(
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?,
+                ),
+                None => None,
+            };
+            watch::watch(mongo, &command.params, |event| {
+                println!("{}", event);
+                if let Some(file) = &mut ndjson {
+                    writeln!(file, "{}", serde_json::to_string(event)?)?;
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        CliCommand::Serve { listen, watch } => {
+            let (events, _) = broadcast::channel(1024);
+            let served: Arc<dyn ResidentStore> = if *watch {
+                let Some(mongo) = mongo.clone() else {
+                    anyhow::bail!("serve --watch needs the mongo backend");
+                };
+                let events = events.clone();
+                tokio::spawn(async move {
+                    let params = WatchParams::default();
+                    let feed = watch::watch(&mongo, &params, |event| {
+                        events.send(event.clone()).ok();
+                        Ok(())
+                    });
+                    if let Err(e) = feed.await {
+                        error!("Change stream stopped: {}", e);
+                    }
+                });
+                shared_store.clone()
+            } else {
+                Arc::new(NotifyingStore::new(shared_store.clone(), events.clone()))
+            };
+            server::serve(served, events, *listen).await?;
+        }
+        CliCommand::Listen {
+            tcp,
+            udp,
+            devices,
+            dead_letter,
         } => {
-            test_clear_alarm(&collection, name, birth, DateTimeStr::Str(alarm_time), None).await?;
+            let listener =
+                Listener::new(shared_store.clone(), DeviceMap::load(devices)?, dead_letter)?;
+            Arc::new(listener).run(*tcp, *udp).await?;
+        }
+        CliCommand::Escalate(command) => {
+            escalate(store, &config.escalation, command).await?;
         }
-        CliCommand::Query(query_params) => {
-            test_query(&collection, query_params).await?;
+        CliCommand::InsertCsv { file_path, params } => {
+            let summary = import::import_csv(store, file_path, cli.upsert, params).await?;
+            check_import(&summary, file_path)?;
         }
-        CliCommand::InsertCsv { file_path } => {
-            test_insert_csv(&collection, file_path, cli.upsert).await?;
+        CliCommand::Export(params) => {
+            dump::export(store, params).await?;
+        }
+        CliCommand::ImportDump(params) => {
+            let summary = dump::restore(store, params).await?;
+            info!("{}", summary);
         }
         CliCommand::NewAlarmCsv(options) => {
-            test_bulk_alarms(&collection, options).await?;
+            test_bulk_alarms(store, options).await?;
         }
         CliCommand::ForceClose { name, birth } => {
-            test_force_close(&collection, name, birth).await?;
+            store
+                .force_close(name, DateTimeStr::Str(birth).try_into()?)
+                .await?;
         }
         CliCommand::ForceCloseCsv { file_path } => {
-            test_force_close_csv(&collection, file_path).await?;
+            test_force_close_csv(store, file_path).await?;
         }
         CliCommand::Stats => {
-            let pipeline = vec![doc! {
-                "$collStats": {
-                    "latencyStats": { "histograms": true },
-                    "storageStats": { "scale": 1024 },
-                    "count" : {},
-                    "queryExecStats" : {}
-                }
-            }];
-            let mut stats = collection.aggregate(pipeline).await?;
-            while let Some(stat) = stats.try_next().await? {
+            for stat in store.stats().await? {
                 println!("{}", serde_json::to_string_pretty(&stat)?);
             }
         }
+        CliCommand::Migrate { command, dry_run } => {
+            let Some(mongo) = &mongo else {
+                anyhow::bail!("migrate needs the mongo backend");
+            };
+            match command {
+                Some(MigrateCommand::Status) => {
+                    utils::bson_table_print(migrations::status(mongo).await?)?;
+                    utils::bson_table_print(migrations::schema_versions(mongo).await?)?;
+                }
+                None => {
+                    migrations::run(mongo, *dry_run).await?;
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
-async fn test_force_close_csv(collection: &Collection<Resident>, file_path: &str) -> Result<()> {
+/// Writes results to `output`, or to stdout if it is None
+fn write_results(
+    results: &[bson::Document],
+    format: OutputFormat,
+    output: Option<&PathBuf>,
+) -> Result<()> {
+    match output {
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            utils::bson_write(results, format, &mut file)?;
+            file.flush()?;
+        }
+        None => utils::bson_write(results, format, &mut std::io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+async fn escalate(
+    store: &dyn ResidentStore,
+    config: &EscalationConfig,
+    command: &EscalateCommand,
+) -> Result<()> {
+    let escalator = Escalator::new(config)?;
+    let mut ndjson = match &command.ndjson {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        None => None,
+    };
+    let period = Duration::from_secs(command.interval.or(config.interval_sec).unwrap_or(60));
+    let mut ticker = tokio::time::interval(period);
+    info!("Escalating active alarms every {:?}", period);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping escalation");
+                return Ok(());
+            }
+        }
+        let scan = escalator.scan(store, bson::DateTime::now(), |event| {
+            warn!("{}", event);
+            if let Some(file) = &mut ndjson {
+                writeln!(file, "{}", serde_json::to_string(event)?)?;
+            }
+            Ok(())
+        });
+        match scan.await {
+            Ok(count) => debug!("Escalation scan emitted {} events", count),
+            // keep the daemon running through transient failures
+            Err(e) if !command.once => error!("Escalation scan failed: {}", e),
+            Err(e) => return Err(e),
+        }
+        if command.once {
+            return Ok(());
+        }
+    }
+}
+
+async fn test_force_close_csv(store: &dyn ResidentStore, file_path: &str) -> Result<()> {
     let mut reader = ReaderBuilder::new()
         .has_headers(true)
         .from_path(file_path)?;
     for result in reader.deserialize::<ResidentCsv>() {
         let record = result?;
-        let birth = record.birth.try_to_rfc3339_string()?[..10].to_string();
-        test_force_close(collection, &record.name, &birth).await?;
+        store.force_close(&record.name, record.birth).await?;
     }
     Ok(())
 }
 
 async fn test_bulk_alarms(
-    collection: &Collection<Resident>,
+    store: &dyn ResidentStore,
     options: &mut CsvBulkAlarmOptions,
 ) -> Result<()> {
     let mut reader = ReaderBuilder::new()
@@ -354,24 +669,22 @@ async fn test_bulk_alarms(
             options.count -= 1;
             continue;
         }
-        let name = record.name.clone();
-        let birth = record.birth.try_to_rfc3339_string()?[..10].to_string();
         let start_time = bson::DateTime::now().saturating_add_duration(Duration::from_secs(
             rand::random::<u64>() % (3 * options.duration),
         ));
         let time = Instant::now();
 
-        match test_new_alarm(
-            collection,
-            &record.name,
-            &birth,
-            "test csv alarm",
-            Some(start_time),
-        )
-        .await
+        match store
+            .new_alarm(
+                &record.name,
+                record.birth,
+                "test csv alarm",
+                Some(start_time),
+            )
+            .await
         {
             Ok(alarm) => {
-                alarms.push((name, birth.clone(), alarm));
+                alarms.push((record.name.clone(), record.birth, alarm));
                 exec_duration += time.elapsed().as_millis() as u64;
                 options.count -= 1;
             }
@@ -391,16 +704,16 @@ async fn test_bulk_alarms(
     );
     if !options.no_clear {
         exec_duration = 0;
-        for (name, birth, alarm_time) in alarms {
+        for (name, birth, alarm) in alarms {
             let time = Instant::now();
-            test_clear_alarm(
-                collection,
-                &name,
-                &birth,
-                DateTimeStr::DateTime(alarm_time),
-                Some(rand::random::<u64>() % options.duration),
-            )
-            .await?;
+            store
+                .clear_alarm(
+                    &name,
+                    birth,
+                    AlarmRef::Id(alarm.id),
+                    Some(rand::random::<u64>() % options.duration),
+                )
+                .await?;
             exec_duration += time.elapsed().as_millis() as u64;
         }
         info!(
@@ -413,395 +726,21 @@ async fn test_bulk_alarms(
     Ok(())
 }
 
-#[tracing::instrument(name = "force_close", skip(collection), level = Level::TRACE)]
-async fn test_force_close(
-    collection: &Collection<Resident>,
-    name: &str,
-    birth: &str,
-) -> Result<()> {
-    let birth_date: bson::DateTime = DateTimeStr::Str(birth).into();
-    let filter = doc! {
-        "name": name,
-        "birth": birth_date,
-    };
-    // get resident
-    if let Some(resident) = collection.find_one(filter).await? {
-        for alarm in resident.active_alarms {
-            test_clear_alarm(
-                collection,
-                name,
-                birth,
-                DateTimeStr::DateTime(alarm.time),
-                None,
-            )
-            .await?;
-        }
-    }
-
-    Ok(())
-}
-
-async fn test_insert_csv(
-    collection: &Collection<Resident>,
-    file_path: &str,
-    upsert: bool,
-) -> Result<()> {
-    let mut reader = ReaderBuilder::new()
-        .has_headers(true)
-        .from_path(file_path)?;
-    for result in reader.deserialize::<ResidentCsv>() {
-        let record: Resident = result?.into();
-        if record.name.is_empty() || record.location.is_empty() {
-            warn!("Skipping record with empty name or location: {}", record);
-            continue;
-        }
-        println!("Importing {}", record);
-        if upsert {
-            test_upsert(collection, record).await?;
-        } else {
-            test_insert_or_update(collection, record).await?;
-        }
-    }
-    Ok(())
-}
-
-#[tracing::instrument(name = "query", skip_all, level = Level::TRACE)]
-async fn test_query(
-    collection: &Collection<Resident>,
-    query_params: &mut QueryParams,
-) -> Result<()> {
-    let mut filter = if let Some(name_pattern) = &query_params.name
-        && let Some(location_pattern) = &query_params.location
-    {
-        doc! {
-            "$or": [
-                { "name": { "$regex": name_pattern, "$options": "i" } },
-                { "location": { "$regex": location_pattern, "$options": "i" } }
-            ]
-        }
-    } else if let Some(name_pattern) = &query_params.name {
-        doc! { "name": { "$regex": name_pattern, "$options": "i" } }
-    } else if let Some(location_pattern) = &query_params.location {
-        doc! { "location": { "$regex": location_pattern, "$options": "i" } }
-    } else {
-        doc! {}
-    };
-    filter.extend(doc! {
-        "$or": [
-            { "active_alarms.0": { "$exists": true } },
-            { "$expr": { "$gt": [ { "$size": "$filteredAlarms" }, 0 ] } }
-        ]
-    });
-    let pipeline = vec![
-        doc! { "$addFields": {
-            "filteredAlarms":
-            if query_params.from_date.is_some() || query_params.to_date.is_some() {
-                doc! {
-                    "$slice": [
-                        {
-                            "$filter": {
-                                "input": { "$ifNull": ["$alarms", []] },
-                                "as": "alarm",
-                                "cond": {
-                                    "$and": [
-                                        if let Some(from_date) = &query_params.from_date {
-                                            doc! { "$gte": [ "$$alarm.time", bson::DateTime::parse_rfc3339_str(from_date.to_string() + "T00:00:00Z")? ] }
-                                        } else {
-                                            doc! { }
-                                        },
-                                        if let Some(to_date) = &query_params.to_date {
-                                            doc! { "$lte": [ "$$alarm.time", bson::DateTime::parse_rfc3339_str(to_date.to_string() + "T23:59:59.999Z")? ] }
-                                        } else {
-                                            doc! { }
-                                        }
-                                    ]
-                                }
-                            }
-                        },
-                        -query_params.alarms_limit
-                    ]
-                }
-            } else {
-                doc! { "$slice": [ { "$ifNull": ["$alarms", []] }, -query_params.alarms_limit ] }
-            }
-        } },
-        doc! { "$match": filter },
-        doc! { "$project": {
-            "name": 1, "location": 1, "birth" : 1,
-            "alarms_count": { "$size": { "$ifNull": ["$filteredAlarms", []] } },
-            "avg_duration": { "$avg": "$filteredAlarms.duration_sec" },
-            "max_duration": { "$max": "$filteredAlarms.duration_sec" },
-            "first": { "$min": "$filteredAlarms.time" },
-            "last": { "$max": "$filteredAlarms.time" },
-            "active_since": { "$min": "$active_alarms.time" },
-            "active_count": { "$size": { "$ifNull": ["$active_alarms", []] } }
-        } },
-        doc! { "$sort": { "location": 1 } },
-    ];
-    tracing::trace!(
-        "Aggregation pipeline: {}",
-        serde_json::to_string(&pipeline).unwrap_or_default()
-    );
-    let time = Instant::now();
-    match collection.aggregate(pipeline).await {
-        Ok(cursor) => {
-            let elapsed = time.elapsed();
-            info!("Query executed in {:?}", elapsed);
-            if let Some(csv) = &query_params.csv {
-                utils::bson_to_csv(cursor, csv).await?;
-            } else {
-                utils::bson_table_print(cursor).await?;
-            }
-        }
-        Err(e) => {
-            error!("Failed to query residents: {}", e);
-        }
-    }
-    Ok(())
-}
-
-#[tracing::instrument(name = "new_alarm", skip_all, fields(name=%name, birth=%birth), level = Level::TRACE)]
-async fn test_new_alarm(
-    collection: &Collection<Resident>,
-    name: &str,
-    birth: &str,
-    message: &str,
-    start_time: Option<bson::DateTime>,
-) -> Result<bson::DateTime> {
-    let birth_date: bson::DateTime = DateTimeStr::Str(birth).into();
-    let filter = doc! {
-        "name": name,
-        "birth": birth_date,
-    };
-    let new_alarm = ActiveAlarm {
-        time: start_time.unwrap_or_else(bson::DateTime::now),
-        message: message.to_string(),
-    };
-    let update = doc! {
-        "$push": { "active_alarms": bson::to_bson(&new_alarm)? }
-    };
-    match collection.update_one(filter, update).await {
-        Ok(update_result) => {
-            if update_result.matched_count > 0 {
-                info!(
-                    "New Alarm\t'{name}' '{birth}' '{}'",
-                    new_alarm.time.try_to_rfc3339_string()?,
-                );
-                Ok(new_alarm.time)
-            } else {
-                anyhow::bail!("No resident found to add alarm.");
-            }
-        }
-        Err(e) => {
-            anyhow::bail!(format!("Failed to add alarm: {}", e));
-        }
-    }
-}
-#[tracing::instrument(name = "clear_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm_time), level = Level::TRACE)]
-async fn test_clear_alarm(
-    collection: &Collection<Resident>,
-    name: &str,
-    birth: &str,
-    alarm_time: DateTimeStr<'_>,
-    duration: Option<u64>,
-) -> Result<()> {
-    let birth_date: bson::DateTime = DateTimeStr::Str(birth).into();
-    let start_time: bson::DateTime = alarm_time.into();
-    let filter = doc! {
-        "name": name,
-        "birth": birth_date,
-    };
-    let mut resident_id_and_alarm = collection.aggregate(vec![
-        doc! { "$match": filter },
-        doc! { "$project": { "id": "$_id", "alarm": { "$filter": { "input": "$active_alarms", "as": "alarm", "cond": { "$eq": [ "$$alarm.time", start_time ] } } } } } 
-    ]).await?;
-    if let Some(resident) = resident_id_and_alarm.try_next().await? {
-        let resident_id = resident.get("_id");
-
-        let alarm_array = resident.get_array("alarm")?;
-        if alarm_array.is_empty() {
-            warn!("No active alarm found with the specified time to clear.");
-            return Ok(());
-        }
-        let alarm_doc = alarm_array[0].as_document().unwrap();
-        let message = alarm_doc.get_str("message").unwrap_or("");
-        let alarm_time = alarm_doc.get_datetime("time").unwrap();
-        let duration = duration.unwrap_or(
-            bson::DateTime::now()
-                .checked_duration_since(*alarm_time)
-                .unwrap_or_default()
-                .as_secs(),
-        );
-        info!(
-            "Clearing alarm for resident id: {:?}, message: {}, start_time: {}, duration_sec: {}",
-            resident_id,
-            message,
-            alarm_time.try_to_rfc3339_string()?,
-            duration
-        );
-
-        // remove alarm from active
-        let filter = doc! {
-            "_id": resident_id
-        };
-        let update = doc! {
-            "$pull": {
-                "active_alarms": {
-                    "time": alarm_time
-                }
-            }
-        };
-        match collection.update_one(filter.clone(), update).await {
-            Ok(update_result) => {
-                if update_result.matched_count > 0 {
-                    debug!(
-                        "Alarm cleared from active for resident. Matched: {} Updated: {}",
-                        update_result.matched_count, update_result.modified_count
-                    );
-                } else {
-                    warn!("No resident found to clear alarm.");
-                }
-            }
-            Err(e) => {
-                error!("Failed to clear alarm: {}", e);
-            }
-        };
-
-        // add alarm to history
-        let history_update = doc! {
-            "$push": {
-                "alarms": {
-                    "time": alarm_time,
-                    "message": message,
-                    "duration_sec": bson::to_bson(&duration)?
-                }
-            }
-        };
-        match collection.update_one(filter, history_update).await {
-            Ok(update_result) => {
-                if update_result.matched_count > 0 {
-                    debug!(
-                        "Alarm added to history for resident. Matched: {} Updated: {}",
-                        update_result.matched_count, update_result.modified_count
-                    );
-                } else {
-                    warn!("No resident found to add alarm to history.");
-                }
-            }
-            Err(e) => {
-                error!("Failed to add alarm to history: {}", e);
-            }
-        };
-    } else {
-        warn!("No resident found to clear alarm.");
-        return Ok(());
-    }
-    Ok(())
-}
-
-// Delete a resident by name and birth date
-#[tracing::instrument(name = "delete", skip_all, fields(name=%name, birth=%birth), level = Level::TRACE)]
-async fn test_delete(collection: &Collection<Resident>, name: &str, birth: &str) -> Result<()> {
-    let birth_date: bson::DateTime = DateTimeStr::Str(birth).into();
-    let filter = doc! {
-        "name": name,
-        "birth": birth_date,
-    };
-    match collection.delete_one(filter).await {
-        Ok(delete_result) => {
-            if delete_result.deleted_count > 0 {
-                info!("Resident deleted successfully.");
-            } else {
-                warn!("No resident found to delete.");
-            }
-        }
-        Err(e) => {
-            error!("Failed to delete resident: {}", e);
-        }
-    }
-    Ok(())
-}
-
-#[tracing::instrument(name = "upsert", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
-async fn test_upsert(collection: &Collection<Resident>, resident: Resident) -> Result<()> {
-    let filter = resident.unique_index();
-    let update = resident.update_data();
-    let options = mongodb::options::UpdateOptions::builder()
-        .upsert(true)
-        .build();
-    match collection
-        .update_one(filter, update)
-        .with_options(options)
-        .await
-    {
-        Ok(update_result) => {
-            if update_result.matched_count > 0 {
-                info!(
-                    "Resident updated Matched: {} Updated: {}",
-                    update_result.matched_count, update_result.modified_count
-                );
-            } else if let Some(upserted_id) = update_result.upserted_id {
-                info!("New resident inserted with id: {}", upserted_id);
-            }
-        }
-        Err(e) => {
-            error!("Failed to upsert resident: {}", e);
-        }
-    }
-    Ok(())
-}
-
-#[tracing::instrument(name = "insert_or_update", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
-async fn test_insert_or_update(
-    collection: &Collection<Resident>,
-    resident: Resident,
-) -> Result<()> {
-    match collection.insert_one(&resident).await {
-        Ok(insert_result) => {
-            info!(
-                "New resident inserted with id: {}",
-                insert_result.inserted_id
-            );
-        }
-        Err(e) => match e.kind.as_ref() {
-            mongodb::error::ErrorKind::Write(write_failure) => match write_failure {
-                WriteFailure::WriteError(WriteError { code: 11000, .. }) => {
-                    warn!(
-                        "Duplicate key error: A resident with the same name and birth date already exists. Updating..."
-                    );
-                    let filter = resident.unique_index();
-                    let update = resident.update_data();
-                    match collection.update_one(filter, update).await {
-                        Ok(update_result) => {
-                            info!(
-                                "Resident updated Matched: {} Updated: {}",
-                                update_result.matched_count, update_result.modified_count
-                            );
-                        }
-                        Err(e) => {
-                            error!("Failed to update resident: {}", e);
-                        }
-                    }
-                }
-                _ => error!("Failed to insert new resident: {}", e),
-            },
-            _ => error!("Failed to insert new resident: {}", e),
-        },
-    }
-    Ok(())
-}
-
-async fn simple_test(collection: &Collection<Resident>) -> Result<()> {
+async fn simple_test(store: &dyn ResidentStore) -> Result<()> {
     let new_resident = Resident::new("John Doe", "1990-01-01", "Room 101", "2020-01-01")?;
-    test_insert_or_update(collection, new_resident).await?;
+    store.insert(&new_resident).await?;
     let updated_resident = Resident::new("John Doe", "1990-01-01", "Room 102", "2021-01-01")?;
-    test_insert_or_update(collection, updated_resident).await?;
+    store.insert(&updated_resident).await?;
     let another_resident = Resident::new("Jane Smith", "1985-05-15", "Room 105", "2019-06-01")?;
-    test_upsert(collection, another_resident).await?;
+    store.upsert(&another_resident).await?;
     let upserted_resident = Resident::new("Jane Smith", "1985-05-15", "Room 106", "2022-07-01")?;
-    test_upsert(collection, upserted_resident).await?;
+    store.upsert(&upserted_resident).await?;
 
-    test_delete(collection, "John Doe", "1990-01-01").await?;
-    test_delete(collection, "Jane Smith", "1985-05-15").await?;
+    store
+        .delete("John Doe", DateTimeStr::Str("1990-01-01").try_into()?)
+        .await?;
+    store
+        .delete("Jane Smith", DateTimeStr::Str("1985-05-15").try_into()?)
+        .await?;
     Ok(())
 }
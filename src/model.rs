@@ -0,0 +1,453 @@
+use std::fmt;
+
+use anyhow::Result;
+use mongodb::bson::{self, doc, oid::ObjectId};
+
+use crate::utils::{self, DateTimeStr};
+
+/// Resident document layout written by this version, see `store::migrations`
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Alarm {
+    /// id of the active alarm this history entry was cleared from
+    #[serde(rename = "_id", default)]
+    pub id: ObjectId,
+    pub time: bson::DateTime,
+    /// seconds from raised to cleared
+    pub duration_sec: u64,
+    pub message: String,
+    /// seconds from raised to acknowledged, None if cleared without acknowledgement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack_duration_sec: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_by: Option<String>,
+    /// highest escalation level reached while active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation_level: Option<u32>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ActiveAlarm {
+    /// alarms stored before ids were introduced get a random id when read,
+    /// those can only be addressed by time
+    #[serde(rename = "_id", default)]
+    pub id: ObjectId,
+    pub time: bson::DateTime,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_at: Option<bson::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_by: Option<String>,
+    /// last escalation level recorded by the `escalate` daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation_level: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalated_at: Option<bson::DateTime>,
+    /// last escalation level whose event was emitted, below `escalation_level` while
+    /// events are outstanding; None if escalated before this was tracked, all emitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation_sent: Option<u32>,
+}
+
+/// Cleared alarm stored in the alarm history collection
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct HistoryAlarm {
+    /// same id as the active alarm it was cleared from
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub resident_id: ObjectId,
+    pub time: bson::DateTime,
+    pub duration_sec: u64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack_duration_sec: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledged_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation_level: Option<u32>,
+}
+
+impl HistoryAlarm {
+    pub fn new(resident_id: ObjectId, alarm: Alarm) -> Self {
+        HistoryAlarm {
+            id: alarm.id,
+            resident_id,
+            time: alarm.time,
+            duration_sec: alarm.duration_sec,
+            message: alarm.message,
+            ack_duration_sec: alarm.ack_duration_sec,
+            acknowledged_by: alarm.acknowledged_by,
+            escalation_level: alarm.escalation_level,
+        }
+    }
+}
+
+impl From<HistoryAlarm> for Alarm {
+    fn from(history: HistoryAlarm) -> Self {
+        Alarm {
+            id: history.id,
+            time: history.time,
+            duration_sec: history.duration_sec,
+            message: history.message,
+            ack_duration_sec: history.ack_duration_sec,
+            acknowledged_by: history.acknowledged_by,
+            escalation_level: history.escalation_level,
+        }
+    }
+}
+
+/// Alarm lifecycle: raised -> acknowledged (optional) -> cleared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmState {
+    Raised,
+    Acknowledged,
+    Cleared,
+}
+
+impl fmt::Display for AlarmState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlarmState::Raised => write!(f, "raised"),
+            AlarmState::Acknowledged => write!(f, "acknowledged"),
+            AlarmState::Cleared => write!(f, "cleared"),
+        }
+    }
+}
+
+/// Alarm state change of a resident, as reported by `watch`
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AlarmEvent {
+    pub event: AlarmState,
+    /// when the alarm was raised, acknowledged or cleared
+    #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub time: bson::DateTime,
+    #[serde(serialize_with = "bson::serde_helpers::serialize_object_id_as_hex_string")]
+    pub alarm_id: ObjectId,
+    #[serde(serialize_with = "bson::serde_helpers::serialize_object_id_as_hex_string")]
+    pub resident_id: ObjectId,
+    pub name: String,
+    pub location: String,
+    pub message: String,
+    #[serde(with = "bson::serde_helpers::bson_datetime_as_rfc3339_string")]
+    pub raised_at: bson::DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acknowledged_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack_duration_sec: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_sec: Option<u64>,
+}
+
+impl AlarmEvent {
+    fn new(
+        event: AlarmState,
+        time: bson::DateTime,
+        resident: &Resident,
+        alarm_id: ObjectId,
+        message: &str,
+        raised_at: bson::DateTime,
+    ) -> Self {
+        AlarmEvent {
+            event,
+            time,
+            alarm_id,
+            resident_id: resident.id.unwrap_or_default(),
+            name: resident.name.clone(),
+            location: resident.location.clone(),
+            message: message.to_string(),
+            raised_at,
+            acknowledged_by: None,
+            ack_duration_sec: None,
+            duration_sec: None,
+        }
+    }
+
+    pub fn raised(resident: &Resident, alarm: &ActiveAlarm) -> Self {
+        let event = AlarmState::Raised;
+        Self::new(
+            event,
+            alarm.time,
+            resident,
+            alarm.id,
+            &alarm.message,
+            alarm.time,
+        )
+    }
+
+    pub fn acknowledged(resident: &Resident, alarm: &ActiveAlarm) -> Self {
+        let time = alarm.acknowledged_at.unwrap_or(alarm.time);
+        let event = AlarmState::Acknowledged;
+        AlarmEvent {
+            acknowledged_by: alarm.acknowledged_by.clone(),
+            ack_duration_sec: Some(
+                time.checked_duration_since(alarm.time)
+                    .unwrap_or_default()
+                    .as_secs(),
+            ),
+            ..Self::new(event, time, resident, alarm.id, &alarm.message, alarm.time)
+        }
+    }
+
+    pub fn cleared(resident: &Resident, alarm: &Alarm) -> Self {
+        let time = alarm
+            .time
+            .saturating_add_duration(std::time::Duration::from_secs(alarm.duration_sec));
+        let event = AlarmState::Cleared;
+        AlarmEvent {
+            acknowledged_by: alarm.acknowledged_by.clone(),
+            ack_duration_sec: alarm.ack_duration_sec,
+            duration_sec: Some(alarm.duration_sec),
+            ..Self::new(event, time, resident, alarm.id, &alarm.message, alarm.time)
+        }
+    }
+}
+
+impl fmt::Display for AlarmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<12} {} ({}) alarm {} '{}'",
+            utils::format_datetime(&self.time, "%Y-%m-%d %H:%M:%S"),
+            self.event.to_string(),
+            self.name,
+            self.location,
+            self.alarm_id,
+            self.message,
+        )?;
+        if let Some(by) = &self.acknowledged_by {
+            write!(f, " by {}", by)?;
+        }
+        if let Some(duration_sec) = self.duration_sec {
+            write!(f, " after {}s", duration_sec)?;
+        }
+        Ok(())
+    }
+}
+
+impl ActiveAlarm {
+    pub fn new(message: &str, time: Option<bson::DateTime>) -> Self {
+        ActiveAlarm {
+            id: ObjectId::new(),
+            time: time.unwrap_or_else(bson::DateTime::now),
+            message: message.to_string(),
+            acknowledged_at: None,
+            acknowledged_by: None,
+            escalation_level: None,
+            escalated_at: None,
+            escalation_sent: None,
+        }
+    }
+
+    /// Escalation levels recorded whose events were not emitted yet
+    pub fn unsent_escalations(&self) -> std::ops::RangeInclusive<u32> {
+        let recorded = self.escalation_level.unwrap_or_default();
+        self.escalation_sent.unwrap_or(recorded) + 1..=recorded
+    }
+
+    pub fn state(&self) -> AlarmState {
+        if self.acknowledged_at.is_some() {
+            AlarmState::Acknowledged
+        } else {
+            AlarmState::Raised
+        }
+    }
+
+    /// history entry for this alarm cleared after `duration_sec`
+    pub fn cleared(self, duration_sec: u64) -> Alarm {
+        let ack_duration_sec = self.acknowledged_at.map(|at| {
+            at.checked_duration_since(self.time)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        Alarm {
+            id: self.id,
+            time: self.time,
+            duration_sec,
+            message: self.message,
+            ack_duration_sec,
+            acknowledged_by: self.acknowledged_by,
+            escalation_level: self.escalation_level,
+        }
+    }
+}
+
+/// Identifies an active alarm of a resident, by id or by the time it was raised
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlarmRef {
+    Id(ObjectId),
+    Time(bson::DateTime),
+}
+
+impl AlarmRef {
+    /// Parses an alarm ObjectId (24 hex digits) or an alarm date/time
+    pub fn parse(s: &str) -> Result<Self> {
+        match ObjectId::parse_str(s) {
+            Ok(id) => Ok(AlarmRef::Id(id)),
+            Err(_) => Ok(AlarmRef::Time(DateTimeStr::Str(s).try_into()?)),
+        }
+    }
+
+    pub fn matches(&self, alarm: &ActiveAlarm) -> bool {
+        match self {
+            AlarmRef::Id(id) => alarm.id == *id,
+            AlarmRef::Time(time) => alarm.time == *time,
+        }
+    }
+
+    /// `$elemMatch` query for the alarm inside an alarms array
+    pub fn elem_match(&self) -> bson::Document {
+        match self {
+            AlarmRef::Id(id) => doc! { "_id": id },
+            AlarmRef::Time(time) => doc! { "time": time },
+        }
+    }
+
+    /// aggregation expression comparing `variable` (e.g. `$$alarm`) with this alarm
+    pub fn expr_eq(&self, variable: &str) -> bson::Document {
+        match self {
+            AlarmRef::Id(id) => doc! { "$eq": [ format!("{variable}._id"), id ] },
+            AlarmRef::Time(time) => doc! { "$eq": [ format!("{variable}.time"), time ] },
+        }
+    }
+}
+
+impl fmt::Display for AlarmRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlarmRef::Id(id) => write!(f, "{}", id),
+            AlarmRef::Time(time) => write!(f, "{}", time),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Resident {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// 0 for documents written before versioning, raised by `migrate`
+    #[serde(default)]
+    pub schema_version: u32,
+    pub name: String,
+    pub birth: bson::DateTime,
+    pub location: String,
+    pub resident_since: bson::DateTime,
+    /// history embedded before it moved to its own collection, emptied by `migrate`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alarms: Vec<Alarm>,
+    #[serde(default)]
+    pub active_alarms: Vec<ActiveAlarm>,
+}
+
+impl Resident {
+    pub fn new(name: &str, birth: &str, location: &str, resident_since: &str) -> Result<Self> {
+        Ok(Resident {
+            id: None,
+            schema_version: SCHEMA_VERSION,
+            name: name.to_string(),
+            birth: DateTimeStr::Str(birth).try_into()?,
+            location: location.to_string(),
+            resident_since: DateTimeStr::Str(resident_since).try_into()?,
+            alarms: Vec::new(),
+            active_alarms: Vec::new(),
+        })
+    }
+
+    /// filter matching the unique (name, birth) index
+    pub fn unique_index(&self) -> bson::Document {
+        doc! {
+            "name": &self.name,
+            "birth": &self.birth,
+        }
+    }
+
+    /// update applied when the resident already exists
+    pub fn update_data(&self) -> bson::Document {
+        doc! {
+            "$set": {
+                "location": &self.location,
+                "resident_since": &self.resident_since,
+            }
+        }
+    }
+}
+
+impl fmt::Display for Resident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Resident {{ name: {}, birth: {}, location: {}, resident_since: {} }}",
+            self.name, self.birth, self.location, self.resident_since,
+        )?;
+        for active_alarm in &self.active_alarms {
+            write!(
+                f,
+                "\n  ActiveAlarm {{ id: {}, time: {}, state: {}, message: {} }}",
+                active_alarm.id,
+                active_alarm.time,
+                active_alarm.state(),
+                active_alarm.message,
+            )?;
+        }
+        for alarm in &self.alarms {
+            write!(
+                f,
+                "\n  HistoryAlarm {{ id: {}, time: {}, duration_sec: {}, message: {} }}",
+                alarm.id, alarm.time, alarm.duration_sec, alarm.message,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ResidentCsv {
+    pub name: String,
+    #[serde(with = "utils::serde_helpers::bson_dateonly")]
+    pub birth: bson::DateTime,
+    pub location: String,
+    #[serde(with = "utils::serde_helpers::bson_dateonly")]
+    pub resident_since: bson::DateTime,
+}
+
+impl From<ResidentCsv> for Resident {
+    fn from(csv: ResidentCsv) -> Self {
+        Resident {
+            id: None,
+            schema_version: SCHEMA_VERSION,
+            name: csv.name,
+            birth: csv.birth,
+            location: csv.location,
+            resident_since: csv.resident_since,
+            alarms: Vec::new(),
+            active_alarms: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alarm_ref_parses_ids_and_times() {
+        let id = ObjectId::new();
+        assert_eq!(AlarmRef::parse(&id.to_hex()).unwrap(), AlarmRef::Id(id));
+        assert_eq!(
+            AlarmRef::parse("2024-07-15T08:30:00Z").unwrap(),
+            AlarmRef::Time(bson::DateTime::parse_rfc3339_str("2024-07-15T08:30:00Z").unwrap())
+        );
+        // 24 characters that are not hex are not an id
+        assert!(AlarmRef::parse("zzzzzzzzzzzzzzzzzzzzzzzz").is_err());
+        assert!(AlarmRef::parse("not an alarm").is_err());
+    }
+
+    #[test]
+    fn alarm_ref_matches() {
+        let alarm = ActiveAlarm::new("call", None);
+        assert!(AlarmRef::Id(alarm.id).matches(&alarm));
+        assert!(AlarmRef::Time(alarm.time).matches(&alarm));
+        assert!(!AlarmRef::Id(ObjectId::new()).matches(&alarm));
+    }
+}
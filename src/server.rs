@@ -0,0 +1,529 @@
+//! JSON HTTP API over a [`ResidentStore`], described by `openapi.json`.
+//!
+//! Responses carry documents as relaxed extended JSON, the same as `mongoexport`:
+//! ids are `{"$oid": "..."}` and dates `{"$date": "2025-01-01T00:00:00Z"}`.
+//! `/events` streams alarm events and active alarm snapshots as Server-Sent Events.
+
+use std::{collections::BTreeMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    Json, Router,
+    extract::{FromRef, Path, Query, State},
+    http::{StatusCode, header},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post},
+};
+use futures::{Stream, StreamExt as _, stream};
+use mongodb::bson;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{BroadcastStream, IntervalStream};
+use tracing::{debug, error, info};
+
+use crate::{
+    model::{AlarmEvent, AlarmRef, Resident},
+    store::{QueryParams, ResidentFilter, ResidentStore, StoreError, WriteOutcome},
+    utils::DateTimeStr,
+};
+
+const OPENAPI: &str = include_str!("openapi.json");
+
+type SharedStore = Arc<dyn ResidentStore>;
+
+#[derive(Clone)]
+struct AppState {
+    store: SharedStore,
+    events: broadcast::Sender<AlarmEvent>,
+}
+
+impl FromRef<AppState> for SharedStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+/// Serves the API on `addr` until Ctrl-C. `events` feeds `/events`, either a
+/// [`NotifyingStore`](crate::store::NotifyingStore) wrapping `store` or a change stream.
+pub async fn serve(
+    store: SharedStore,
+    events: broadcast::Sender<AlarmEvent>,
+    addr: SocketAddr,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(store, events))
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+    Ok(())
+}
+
+pub fn router(store: SharedStore, events: broadcast::Sender<AlarmEvent>) -> Router {
+    Router::new()
+        .route("/openapi.json", get(openapi))
+        .route("/events", get(alarm_events))
+        .route("/residents", post(insert_resident).put(upsert_resident))
+        .route(
+            "/residents/{name}/{birth}",
+            get(find_resident).delete(delete_resident),
+        )
+        .route("/residents/{name}/{birth}/alarms", post(new_alarm))
+        .route(
+            "/residents/{name}/{birth}/alarms/{alarm}/ack",
+            post(ack_alarm),
+        )
+        .route(
+            "/residents/{name}/{birth}/alarms/{alarm}/clear",
+            post(clear_alarm),
+        )
+        .route("/residents/{name}/{birth}/force-close", post(force_close))
+        .route("/query", get(query))
+        .route("/stats", get(stats))
+        .with_state(AppState { store, events })
+}
+
+/// Error response `{"error": "..."}`
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl ToString) -> Self {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    fn not_found(message: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let status = match e.downcast_ref::<StoreError>() {
+            Some(StoreError::NotFound(_)) => StatusCode::NOT_FOUND,
+            Some(StoreError::Duplicate(_)) => StatusCode::CONFLICT,
+            Some(StoreError::Invalid(_)) => StatusCode::BAD_REQUEST,
+            None => {
+                error!("Request failed: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        ApiError::new(status, e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+fn parse_date(s: &str) -> ApiResult<bson::DateTime> {
+    DateTimeStr::Str(s)
+        .try_into()
+        .map_err(|e: anyhow::Error| ApiError::new(StatusCode::BAD_REQUEST, e))
+}
+
+fn parse_alarm(s: &str) -> ApiResult<AlarmRef> {
+    AlarmRef::parse(s).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))
+}
+
+fn to_json(value: impl serde::Serialize) -> ApiResult<Json<serde_json::Value>> {
+    let value = bson::to_bson(&value).map_err(anyhow::Error::from)?;
+    Ok(Json(value.into_relaxed_extjson()))
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+#[derive(serde::Deserialize)]
+struct ResidentBody {
+    name: String,
+    birth: String,
+    location: String,
+    resident_since: String,
+}
+
+impl ResidentBody {
+    fn resident(&self) -> ApiResult<Resident> {
+        Resident::new(
+            &self.name,
+            &self.birth,
+            &self.location,
+            &self.resident_since,
+        )
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))
+    }
+}
+
+fn write_response(outcome: WriteOutcome) -> Response {
+    match outcome {
+        WriteOutcome::Inserted(id) => (
+            StatusCode::CREATED,
+            Json(json!({ "inserted_id": id.into_relaxed_extjson() })),
+        )
+            .into_response(),
+        WriteOutcome::Updated { matched, modified } => {
+            Json(json!({ "matched": matched, "modified": modified })).into_response()
+        }
+    }
+}
+
+async fn insert_resident(
+    State(store): State<SharedStore>,
+    Json(body): Json<ResidentBody>,
+) -> ApiResult<Response> {
+    Ok(write_response(store.insert(&body.resident()?).await?))
+}
+
+async fn upsert_resident(
+    State(store): State<SharedStore>,
+    Json(body): Json<ResidentBody>,
+) -> ApiResult<Response> {
+    Ok(write_response(store.upsert(&body.resident()?).await?))
+}
+
+async fn find_resident(
+    State(store): State<SharedStore>,
+    Path((name, birth)): Path<(String, String)>,
+) -> ApiResult<Json<serde_json::Value>> {
+    match store.find(&name, parse_date(&birth)?).await? {
+        Some(resident) => to_json(resident),
+        None => Err(ApiError::not_found("No resident found.")),
+    }
+}
+
+async fn delete_resident(
+    State(store): State<SharedStore>,
+    Path((name, birth)): Path<(String, String)>,
+) -> ApiResult<StatusCode> {
+    if store.delete(&name, parse_date(&birth)?).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::not_found("No resident found to delete."))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct NewAlarmBody {
+    message: String,
+    /// raise time, now if not set
+    time: Option<String>,
+}
+
+async fn new_alarm(
+    State(store): State<SharedStore>,
+    Path((name, birth)): Path<(String, String)>,
+    Json(body): Json<NewAlarmBody>,
+) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+    let time = match &body.time {
+        Some(time) => Some(parse_date(time)?),
+        None => None,
+    };
+    let alarm = store
+        .new_alarm(&name, parse_date(&birth)?, &body.message, time)
+        .await?;
+    Ok((StatusCode::CREATED, to_json(alarm)?))
+}
+
+#[derive(serde::Deserialize)]
+struct AckBody {
+    by: String,
+}
+
+async fn ack_alarm(
+    State(store): State<SharedStore>,
+    Path((name, birth, alarm)): Path<(String, String, String)>,
+    Json(body): Json<AckBody>,
+) -> ApiResult<Json<serde_json::Value>> {
+    match store
+        .ack_alarm(&name, parse_date(&birth)?, parse_alarm(&alarm)?, &body.by)
+        .await?
+    {
+        Some(alarm) => to_json(alarm),
+        None => Err(ApiError::not_found("No unacknowledged active alarm found.")),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ClearParams {
+    /// seconds the alarm was active, computed from the raise time if not set
+    duration_sec: Option<u64>,
+}
+
+async fn clear_alarm(
+    State(store): State<SharedStore>,
+    Path((name, birth, alarm)): Path<(String, String, String)>,
+    Query(params): Query<ClearParams>,
+) -> ApiResult<Json<serde_json::Value>> {
+    match store
+        .clear_alarm(
+            &name,
+            parse_date(&birth)?,
+            parse_alarm(&alarm)?,
+            params.duration_sec,
+        )
+        .await?
+    {
+        Some(alarm) => to_json(alarm),
+        None => Err(ApiError::not_found("No active alarm found to clear.")),
+    }
+}
+
+async fn force_close(
+    State(store): State<SharedStore>,
+    Path((name, birth)): Path<(String, String)>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let cleared = store.force_close(&name, parse_date(&birth)?).await?;
+    Ok(Json(json!({ "cleared": cleared.len() })))
+}
+
+/// The cursor of the next page is returned in the `X-Next-Cursor` header
+async fn query(
+    State(store): State<SharedStore>,
+    Query(params): Query<QueryParams>,
+) -> ApiResult<Response> {
+    params
+        .date_range()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let results = store.query(&params).await?;
+    let next_cursor = params.next_cursor(&results)?;
+    let mut response = to_json(results)?.into_response();
+    if let Some(cursor) = next_cursor
+        && let Ok(value) = header::HeaderValue::from_str(&cursor)
+    {
+        response.headers_mut().insert(NEXT_CURSOR, value);
+    }
+    Ok(response)
+}
+
+const NEXT_CURSOR: &str = "x-next-cursor";
+
+async fn stats(State(store): State<SharedStore>) -> ApiResult<Json<serde_json::Value>> {
+    to_json(store.stats().await?)
+}
+
+#[derive(serde::Deserialize)]
+struct EventsParams {
+    /// case-insensitive regexp, all locations if not set
+    location: Option<String>,
+    /// seconds between active alarm snapshots, 0 for only the initial one
+    #[serde(default = "default_snapshot_sec")]
+    snapshot_sec: u64,
+}
+
+fn default_snapshot_sec() -> u64 {
+    30
+}
+
+/// `alarm` events as they happen and `snapshot` events with the active alarms
+/// per location, the first one right after connecting
+async fn alarm_events(
+    State(state): State<AppState>,
+    Query(params): Query<EventsParams>,
+) -> ApiResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    let filter = Arc::new(ResidentFilter::new(&None, &params.location)?);
+    let alarms = {
+        let filter = filter.clone();
+        BroadcastStream::new(state.events.subscribe()).filter_map(move |event| {
+            let event = match event {
+                Ok(event) if filter.is_match(&event.name, &event.location) => {
+                    Event::default().event("alarm").json_data(&event).ok()
+                }
+                Ok(_) => None,
+                // the next snapshot brings a lagging client up to date
+                Err(e) => {
+                    debug!("Event subscriber: {}", e);
+                    None
+                }
+            };
+            std::future::ready(event.map(Ok))
+        })
+    };
+    let ticks = match params.snapshot_sec {
+        0 => stream::once(std::future::ready(())).boxed(),
+        sec => IntervalStream::new(tokio::time::interval(Duration::from_secs(sec)))
+            .map(|_| ())
+            .boxed(),
+    };
+    let store = state.store.clone();
+    let snapshots = ticks.then(move |_| {
+        let (store, filter) = (store.clone(), filter.clone());
+        async move {
+            let event = match snapshot(store.as_ref(), &filter).await {
+                Ok(data) => Event::default().event("snapshot").json_data(data),
+                Err(e) => Event::default()
+                    .event("error")
+                    .json_data(json!({ "error": e.to_string() })),
+            };
+            Ok(event.unwrap_or_default())
+        }
+    });
+    Ok(Sse::new(stream::select(alarms, snapshots)).keep_alive(KeepAlive::default()))
+}
+
+/// Active alarms grouped by location
+async fn snapshot(store: &dyn ResidentStore, filter: &ResidentFilter) -> Result<serde_json::Value> {
+    let mut locations: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
+    for resident in store.with_active_alarms().await? {
+        if !filter.matches(&resident) {
+            continue;
+        }
+        let alarms = locations.entry(resident.location.clone()).or_default();
+        for alarm in &resident.active_alarms {
+            alarms.push(json!({
+                "alarm_id": alarm.id.to_hex(),
+                "resident_id": resident.id.map(|id| id.to_hex()),
+                "name": resident.name,
+                "message": alarm.message,
+                "state": alarm.state(),
+                "raised_at": alarm.time.try_to_rfc3339_string()?,
+                "acknowledged_by": alarm.acknowledged_by,
+                "escalation_level": alarm.escalation_level,
+            }));
+        }
+    }
+    Ok(json!({
+        "time": bson::DateTime::now().try_to_rfc3339_string()?,
+        "locations": locations
+            .into_iter()
+            .map(|(location, alarms)| json!({
+                "location": location,
+                "active_count": alarms.len(),
+                "alarms": alarms,
+            }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt as _;
+
+    use super::*;
+    use crate::store::{MemoryStore, NotifyingStore};
+
+    fn app() -> (Router, broadcast::Sender<AlarmEvent>) {
+        let (events, _) = broadcast::channel(16);
+        let store = NotifyingStore::new(Arc::new(MemoryStore::new()), events.clone());
+        (router(Arc::new(store), events.clone()), events)
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str, body: serde_json::Value) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn json_body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn ann() -> serde_json::Value {
+        json!({
+            "name": "Ann",
+            "birth": "1940-05-01",
+            "location": "Room 1",
+            "resident_since": "2020-01-01",
+        })
+    }
+
+    #[tokio::test]
+    async fn store_errors_map_to_statuses() {
+        let (app, _) = app();
+        let created = send(&app, "POST", "/residents", ann()).await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+
+        let missing = send(&app, "GET", "/residents/Bob/1940-05-01", json!(null)).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let alarm = json!({ "message": "call" });
+        let no_resident = send(&app, "POST", "/residents/Bob/1940-05-01/alarms", alarm).await;
+        assert_eq!(no_resident.status(), StatusCode::NOT_FOUND);
+        assert!(json_body(no_resident).await["error"].is_string());
+        let no_alarm = send(
+            &app,
+            "POST",
+            &format!(
+                "/residents/Ann/1940-05-01/alarms/{}/clear",
+                bson::oid::ObjectId::new()
+            ),
+            json!(null),
+        )
+        .await;
+        assert_eq!(no_alarm.status(), StatusCode::NOT_FOUND);
+
+        let bad_date = send(&app, "GET", "/residents/Ann/1940-13-01", json!(null)).await;
+        assert_eq!(bad_date.status(), StatusCode::BAD_REQUEST);
+        let bad_alarm = send(
+            &app,
+            "POST",
+            "/residents/Ann/1940-05-01/alarms/yesterday/ack",
+            json!({ "by": "nurse" }),
+        )
+        .await;
+        assert_eq!(bad_alarm.status(), StatusCode::BAD_REQUEST);
+        let bad_resident = send(&app, "POST", "/residents", json!({ "name": "Ann" })).await;
+        assert!(bad_resident.status().is_client_error());
+        let bad_query = send(&app, "GET", "/query?sort=nothing", json!(null)).await;
+        assert_eq!(bad_query.status(), StatusCode::BAD_REQUEST);
+        for query in ["birth_from=1940-02-30", "resident_since_to=soon"] {
+            let bad_date = send(&app, "GET", &format!("/query?{query}"), json!(null)).await;
+            assert_eq!(bad_date.status(), StatusCode::BAD_REQUEST, "{query}");
+        }
+
+        let duplicate = ApiError::from(anyhow::Error::from(StoreError::Duplicate(
+            "Ann".to_string(),
+        )));
+        assert_eq!(duplicate.status, StatusCode::CONFLICT);
+        let other = ApiError::from(anyhow::anyhow!("connection reset"));
+        assert_eq!(other.status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn events_stream_snapshots_and_alarms() {
+        let (app, _) = app();
+        send(&app, "POST", "/residents", ann()).await;
+        let events = send(&app, "GET", "/events?snapshot_sec=0", json!(null)).await;
+        assert_eq!(events.status(), StatusCode::OK);
+        assert_eq!(events.headers()[header::CONTENT_TYPE], "text/event-stream");
+        let mut frames = events.into_body().into_data_stream();
+        let frame = |bytes: Option<Result<axum::body::Bytes, axum::Error>>| {
+            String::from_utf8(bytes.unwrap().unwrap().to_vec()).unwrap()
+        };
+        let snapshot = frame(frames.next().await);
+        assert!(snapshot.starts_with("event: snapshot\n"), "{snapshot}");
+
+        let raised = send(
+            &app,
+            "POST",
+            "/residents/Ann/1940-05-01/alarms",
+            json!({ "message": "call" }),
+        )
+        .await;
+        assert_eq!(raised.status(), StatusCode::CREATED);
+        let alarm = frame(frames.next().await);
+        assert!(alarm.starts_with("event: alarm\n"), "{alarm}");
+        assert!(alarm.contains("\"call\""), "{alarm}");
+    }
+}
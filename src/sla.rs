@@ -0,0 +1,406 @@
+//! Alarm response-time SLA compliance.
+//!
+//! Alarms raised in the date window fall into tiers by how long they stayed open, with
+//! `--tiers 5m,15m,30m` into 0-5m, 5m-15m, 15m-30m and over 30m. An alarm open longer
+//! than the last tier breaches the SLA. Active alarms already open that long count as
+//! breaches; other active alarms are left out until they are cleared. Results are broken
+//! down by location and by shift, shifts start at the given times in the facility timezone.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use clap::Parser;
+use futures::TryStreamExt as _;
+use mongodb::bson::{self, doc};
+use tracing::{Level, info};
+
+use crate::{
+    model::Resident,
+    store::{AlarmWindow, ResidentStore},
+    utils::{self, OutputFormat},
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct SlaParams {
+    #[clap(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_duration,
+        default_value = "5m,15m,30m",
+        help = "Increasing clear-time tiers like 90s, 5m or 1h30m, alarms open longer than the last one breach the SLA"
+    )]
+    pub tiers: Vec<u64>,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_time_of_day,
+        default_value = "07:00,15:00,23:00",
+        help = "Shift start times (HH:MM) in the facility timezone"
+    )]
+    pub shifts: Vec<NaiveTime>,
+    #[command(flatten)]
+    pub window: AlarmWindow,
+    #[clap(long, help = "File receiving one row per breach")]
+    pub breaches: Option<std::path::PathBuf>,
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub breaches_format: OutputFormat,
+}
+
+/// Parses `90`, `90s`, `5m`, `1h` or combinations like `1h30m` into seconds
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration '{s}': expected e.g. 90s, 5m or 1h30m");
+    if let Ok(seconds) = s.parse::<u64>()
+        && seconds > 0
+    {
+        return Ok(seconds);
+    }
+    let mut seconds = 0u64;
+    let mut number = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value = number.parse::<u64>().map_err(|_| invalid())?;
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|v| seconds.checked_add(v))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(invalid());
+    }
+    Ok(seconds)
+}
+
+fn parse_time_of_day(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M")
+        .map_err(|_| format!("invalid shift start '{s}': expected HH:MM"))
+}
+
+/// `5m`, `1h30m` or `90s`
+fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+    [(hours, "h"), (minutes, "m"), (seconds, "s")]
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect()
+}
+
+/// Summary rows and breach details, see [`sla`]
+#[derive(Debug, Default)]
+pub struct SlaReport {
+    /// compliance of all alarms, then per location and per shift
+    pub rows: Vec<bson::Document>,
+    /// one row per breach, oldest first
+    pub breaches: Vec<bson::Document>,
+}
+
+/// Alarm counts per tier, the last tier holds the breaches
+#[derive(Debug, Clone)]
+struct Tally {
+    tiers: Vec<u64>,
+    open_breaches: u64,
+}
+
+impl Tally {
+    fn new(tiers: usize) -> Self {
+        Tally {
+            tiers: vec![0; tiers + 1],
+            open_breaches: 0,
+        }
+    }
+
+    fn row(&self, by: &str, group: &str, labels: &[String]) -> bson::Document {
+        let alarms = self.tiers.iter().sum::<u64>();
+        let mut row = doc! { "by": by, "group": group, "alarms": alarms as i64 };
+        for (label, count) in labels.iter().zip(&self.tiers) {
+            let percent = if alarms == 0 {
+                0.0
+            } else {
+                *count as f64 * 100.0 / alarms as f64
+            };
+            row.insert(format!("{label} %"), percent);
+        }
+        row.insert("breaches", *self.tiers.last().unwrap_or(&0) as i64);
+        row.insert("open_breaches", self.open_breaches as i64);
+        row
+    }
+}
+
+/// Shift start times in the facility timezone, the last shift runs past midnight
+struct Shifts {
+    starts: Vec<NaiveTime>,
+    labels: Vec<String>,
+    tz: Tz,
+}
+
+impl Shifts {
+    fn new(starts: &[NaiveTime], tz: Tz) -> Self {
+        let mut starts = starts.to_vec();
+        starts.sort();
+        starts.dedup();
+        let labels = (0..starts.len())
+            .map(|i| {
+                let end = starts[(i + 1) % starts.len()];
+                format!("{}-{}", starts[i].format("%H:%M"), end.format("%H:%M"))
+            })
+            .collect();
+        Shifts { starts, labels, tz }
+    }
+
+    /// Index of the shift `time` falls into
+    fn of(&self, time: bson::DateTime) -> usize {
+        let time_of_day = time.to_chrono().with_timezone(&self.tz).time();
+        self.starts
+            .iter()
+            .rposition(|start| *start <= time_of_day)
+            // before the first start is the end of the last, overnight shift
+            .unwrap_or(self.starts.len() - 1)
+    }
+}
+
+/// Computes SLA compliance from the residents and their alarm history
+#[tracing::instrument(name = "sla", skip_all, level = Level::TRACE)]
+pub async fn sla(store: &dyn ResidentStore, params: &SlaParams) -> Result<SlaReport> {
+    if params.tiers.is_empty() || !params.tiers.is_sorted_by(|a, b| a < b) {
+        anyhow::bail!("--tiers must be an increasing list of durations");
+    }
+    if params.shifts.is_empty() {
+        anyhow::bail!("--shifts needs at least one start time");
+    }
+    let filter = params.window.resident_filter()?;
+    let (from_date, to_date) = params.window.date_range()?;
+    let in_window = |time: bson::DateTime| {
+        from_date.is_none_or(|f| time >= f) && to_date.is_none_or(|t| time <= t)
+    };
+    let sla_sec = *params.tiers.last().unwrap_or(&0);
+    let labels = tier_labels(&params.tiers);
+    let shifts = Shifts::new(&params.shifts, utils::timezone());
+    let now = bson::DateTime::now();
+
+    let mut all = Tally::new(params.tiers.len());
+    let mut by_location = BTreeMap::<String, Tally>::new();
+    let mut by_shift = vec![Tally::new(params.tiers.len()); shifts.starts.len()];
+    let mut breaches = Vec::new();
+    // one alarm into every breakdown, and into the breach details if it breached
+    let mut record = |resident: &Resident, alarm: AlarmTime| {
+        let tier = params
+            .tiers
+            .iter()
+            .position(|t| alarm.duration_sec <= *t)
+            .unwrap_or(params.tiers.len());
+        let shift = shifts.of(alarm.time);
+        let location = by_location
+            .entry(resident.location.clone())
+            .or_insert_with(|| Tally::new(params.tiers.len()));
+        for tally in [&mut all, location, &mut by_shift[shift]] {
+            tally.tiers[tier] += 1;
+            tally.open_breaches += u64::from(alarm.open);
+        }
+        if tier == params.tiers.len() {
+            breaches.push(alarm.to_document(resident, &shifts.labels[shift], sla_sec));
+        }
+    };
+
+    let mut stream = store.with_history(&filter, from_date, to_date).await?;
+    while let Some(resident) = stream.try_next().await? {
+        for alarm in &resident.alarms {
+            let alarm_time = AlarmTime {
+                alarm_id: alarm.id,
+                time: alarm.time,
+                duration_sec: alarm.duration_sec,
+                open: false,
+                message: &alarm.message,
+                acknowledged_by: alarm.acknowledged_by.as_deref(),
+            };
+            record(&resident, alarm_time);
+        }
+        for alarm in resident.active_alarms.iter().filter(|a| in_window(a.time)) {
+            let open_sec =
+                (now.timestamp_millis() - alarm.time.timestamp_millis()).max(0) as u64 / 1000;
+            if open_sec <= sla_sec {
+                continue;
+            }
+            let alarm_time = AlarmTime {
+                alarm_id: alarm.id,
+                time: alarm.time,
+                duration_sec: open_sec,
+                open: true,
+                message: &alarm.message,
+                acknowledged_by: alarm.acknowledged_by.as_deref(),
+            };
+            record(&resident, alarm_time);
+        }
+    }
+
+    let mut rows = vec![all.row("all", "all", &labels)];
+    rows.extend(
+        by_location
+            .iter()
+            .map(|(location, tally)| tally.row("location", location, &labels)),
+    );
+    rows.extend(
+        by_shift
+            .iter()
+            .zip(&shifts.labels)
+            .map(|(tally, shift)| tally.row("shift", shift, &labels)),
+    );
+    breaches.sort_by_key(|b| b.get_datetime("time").ok().copied());
+    info!(
+        "{} alarms, {} breaches of the {} SLA",
+        all.tiers.iter().sum::<u64>(),
+        breaches.len(),
+        format_duration(sla_sec)
+    );
+    Ok(SlaReport { rows, breaches })
+}
+
+/// `0-5m`, `5m-15m`, ... `over 30m`
+fn tier_labels(tiers: &[u64]) -> Vec<String> {
+    let mut lower = "0".to_string();
+    let mut labels = Vec::new();
+    for tier in tiers {
+        let upper = format_duration(*tier);
+        labels.push(format!("{lower}-{upper}"));
+        lower = upper;
+    }
+    labels.push(format!("over {lower}"));
+    labels
+}
+
+/// Alarm with the time it was or has been open
+struct AlarmTime<'a> {
+    alarm_id: bson::oid::ObjectId,
+    time: bson::DateTime,
+    duration_sec: u64,
+    /// still active
+    open: bool,
+    message: &'a str,
+    acknowledged_by: Option<&'a str>,
+}
+
+impl AlarmTime<'_> {
+    fn to_document(&self, resident: &Resident, shift: &str, sla_sec: u64) -> bson::Document {
+        doc! {
+            "alarm_id": self.alarm_id,
+            "name": &resident.name,
+            "birth": resident.birth,
+            "location": &resident.location,
+            "shift": shift,
+            "time": self.time,
+            "status": if self.open { "open" } else { "cleared" },
+            "duration": self.duration_sec as i64,
+            "over_duration": self.duration_sec.saturating_sub(sla_sec) as i64,
+            "message": self.message,
+            "acknowledged_by": self.acknowledged_by,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::AlarmRef,
+        store::{MemoryStore, ResidentStore},
+    };
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("90s"), Ok(90));
+        assert_eq!(parse_duration("5m"), Ok(300));
+        assert_eq!(parse_duration("1h30m"), Ok(5400));
+        assert_eq!(parse_duration(" 1h0m5s "), Ok(3605));
+        for bad in ["", "0", "0m", "m", "5x", "5m3", "1.5h", "-5m"] {
+            assert!(parse_duration(bad).is_err(), "{bad}");
+        }
+        assert!(parse_duration(&format!("{}h", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn labels_tiers() {
+        assert_eq!(
+            tier_labels(&[300, 900, 1800]),
+            ["0-5m", "5m-15m", "15m-30m", "over 30m"]
+        );
+        assert_eq!(tier_labels(&[90]), ["0-1m30s", "over 1m30s"]);
+    }
+
+    #[test]
+    fn shifts_wrap_overnight_in_the_timezone() {
+        let starts = ["15:00", "07:00", "23:00"].map(|s| parse_time_of_day(s).unwrap());
+        let shifts = Shifts::new(&starts, chrono_tz::Europe::Bucharest);
+        assert_eq!(shifts.labels, ["07:00-15:00", "15:00-23:00", "23:00-07:00"]);
+        // Bucharest is UTC+3 in summer
+        let of = |time: &str| shifts.of(utils::parse_datetime(time).unwrap());
+        assert_eq!(of("2024-07-15T04:00:00Z"), 0);
+        assert_eq!(of("2024-07-15T11:59:59Z"), 0);
+        assert_eq!(of("2024-07-15T12:00:00Z"), 1);
+        assert_eq!(of("2024-07-15T20:00:00Z"), 2);
+        // before the first start of the day is still the overnight shift
+        assert_eq!(of("2024-07-15T02:00:00Z"), 2);
+        assert_eq!(of("2024-07-14T21:00:00Z"), 2);
+    }
+
+    #[tokio::test]
+    async fn tiers_include_their_bound_and_open_alarms_count_once_past_the_sla() {
+        let store = MemoryStore::new();
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        let cleared = [
+            ("2024-07-15T08:00:00Z", 300),
+            ("2024-07-15T09:00:00Z", 301),
+            ("2024-07-15T10:00:00Z", 1800),
+            ("2024-07-15T11:00:00Z", 1801),
+        ];
+        for (time, duration) in cleared {
+            let time = utils::parse_datetime(time).unwrap();
+            store
+                .new_alarm(&ann.name, ann.birth, "call", Some(time))
+                .await
+                .unwrap();
+            store
+                .clear_alarm(&ann.name, ann.birth, AlarmRef::Time(time), Some(duration))
+                .await
+                .unwrap();
+        }
+        let now = bson::DateTime::now().timestamp_millis();
+        for minutes_ago in [60, 10] {
+            let time = bson::DateTime::from_millis(now - minutes_ago * 60 * 1000);
+            store
+                .new_alarm(&ann.name, ann.birth, "call", Some(time))
+                .await
+                .unwrap();
+        }
+
+        let params = SlaParams::parse_from(["sla", "--tiers", "5m,30m", "--shifts", "07:00"]);
+        let report = sla(&store, &params).await.unwrap();
+        let all = &report.rows[0];
+        // the active alarm open for 10 minutes is not counted yet
+        assert_eq!(all.get_i64("alarms").unwrap(), 5);
+        assert_eq!(all.get_f64("0-5m %").unwrap(), 20.0);
+        assert_eq!(all.get_f64("5m-30m %").unwrap(), 40.0);
+        assert_eq!(all.get_f64("over 30m %").unwrap(), 40.0);
+        assert_eq!(all.get_i64("breaches").unwrap(), 2);
+        assert_eq!(all.get_i64("open_breaches").unwrap(), 1);
+        let statuses: Vec<&str> = report
+            .breaches
+            .iter()
+            .map(|b| b.get_str("status").unwrap())
+            .collect();
+        assert_eq!(statuses, ["cleared", "open"]);
+        assert_eq!(report.breaches[0].get_i64("over_duration").unwrap(), 1);
+    }
+}
@@ -0,0 +1,776 @@
+//! Filter expressions on query results, e.g. `name~"^Ann" and location~"Wing B" and active_count>0`.
+//!
+//! A comparison is `column op value` with `=`, `!=`, `<`, `<=`, `>`, `>=`, `~`
+//! (case-insensitive regexp) or `!~`; comparisons combine with `and`, `or`, `not` and
+//! parentheses. Values are quoted strings, numbers or `null`; date columns take quoted dates
+//! read like `--from-date`. Expressions compile to a `$match` stage and evaluate on rows in
+//! memory with the same semantics: ordering comparisons never match null.
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use mongodb::bson::{self, Bson, doc};
+use regex::{Regex, RegexBuilder};
+
+use super::{StoreError, paging};
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    String,
+    Number,
+    Date,
+}
+
+/// Columns a filter can refer to
+const COLUMNS: [(&str, ColumnType); 16] = [
+    ("name", ColumnType::String),
+    ("location", ColumnType::String),
+    ("birth", ColumnType::Date),
+    ("resident_since", ColumnType::Date),
+    ("age", ColumnType::Number),
+    ("length_of_stay", ColumnType::Number),
+    ("alarms_count", ColumnType::Number),
+    ("avg_duration", ColumnType::Number),
+    ("max_duration", ColumnType::Number),
+    ("avg_ack_duration", ColumnType::Number),
+    ("max_ack_duration", ColumnType::Number),
+    ("first", ColumnType::Date),
+    ("last", ColumnType::Date),
+    ("active_since", ColumnType::Date),
+    ("active_count", ColumnType::Number),
+    ("score", ColumnType::Number),
+];
+
+/// Columns stored on the resident document, usable before the alarms are looked up
+const RESIDENT_COLUMNS: [&str; 4] = ["name", "location", "birth", "resident_since"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Match => "~",
+            Op::NotMatch => "!~",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Not(Box<Node>),
+    Compare {
+        column: String,
+        op: Op,
+        value: Bson,
+    },
+    Regex {
+        column: String,
+        regex: Regex,
+        negated: bool,
+    },
+}
+
+impl Node {
+    fn to_document(&self) -> bson::Document {
+        match self {
+            Node::And(nodes) => {
+                doc! { "$and": nodes.iter().map(Node::to_document).collect::<Vec<_>>() }
+            }
+            Node::Or(nodes) => {
+                doc! { "$or": nodes.iter().map(Node::to_document).collect::<Vec<_>>() }
+            }
+            Node::Not(node) => doc! { "$nor": [node.to_document()] },
+            Node::Compare { column, op, value } => {
+                let condition = match op {
+                    Op::Eq => value.clone(),
+                    Op::Ne => doc! { "$ne": value.clone() }.into(),
+                    Op::Lt => doc! { "$lt": value.clone() }.into(),
+                    Op::Le => doc! { "$lte": value.clone() }.into(),
+                    Op::Gt => doc! { "$gt": value.clone() }.into(),
+                    Op::Ge => doc! { "$gte": value.clone() }.into(),
+                    Op::Match | Op::NotMatch => unreachable!("regexps are Node::Regex"),
+                };
+                doc! { column: condition }
+            }
+            Node::Regex {
+                column,
+                regex,
+                negated,
+            } => {
+                let regex = bson::Regex {
+                    pattern: regex.as_str().to_string(),
+                    options: "i".to_string(),
+                };
+                if *negated {
+                    doc! { column: { "$not": regex } }
+                } else {
+                    doc! { column: regex }
+                }
+            }
+        }
+    }
+
+    fn matches(&self, row: &bson::Document) -> bool {
+        match self {
+            Node::And(nodes) => nodes.iter().all(|n| n.matches(row)),
+            Node::Or(nodes) => nodes.iter().any(|n| n.matches(row)),
+            Node::Not(node) => !node.matches(row),
+            Node::Compare { column, op, value } => {
+                let field = row.get(column);
+                match op {
+                    Op::Eq => paging::compare_bson(field, Some(value)) == Ordering::Equal,
+                    Op::Ne => paging::compare_bson(field, Some(value)) != Ordering::Equal,
+                    // like MongoDB, only values of the same type are ordered
+                    _ => paging::compare_same_type(field, value).is_some_and(|o| match op {
+                        Op::Lt => o.is_lt(),
+                        Op::Le => o.is_le(),
+                        Op::Gt => o.is_gt(),
+                        Op::Ge => o.is_ge(),
+                        _ => false,
+                    }),
+                }
+            }
+            Node::Regex {
+                column,
+                regex,
+                negated,
+            } => {
+                let is_match =
+                    matches!(row.get(column), Some(Bson::String(s)) if regex.is_match(s));
+                is_match != *negated
+            }
+        }
+    }
+
+    fn columns<'a>(&'a self, columns: &mut Vec<&'a str>) {
+        match self {
+            Node::And(nodes) | Node::Or(nodes) => nodes.iter().for_each(|n| n.columns(columns)),
+            Node::Not(node) => node.columns(columns),
+            Node::Compare { column, .. } | Node::Regex { column, .. } => columns.push(column),
+        }
+    }
+
+    fn exceeds_cap(&self, column: &str, cap: f64) -> bool {
+        match self {
+            Node::And(nodes) | Node::Or(nodes) => nodes.iter().any(|n| n.exceeds_cap(column, cap)),
+            Node::Not(node) => node.exceeds_cap(column, cap),
+            Node::Compare {
+                column: c,
+                op,
+                value,
+            } if c == column => {
+                let value = match value {
+                    Bson::Int64(n) => *n as f64,
+                    Bson::Double(n) => *n,
+                    _ => return false,
+                };
+                match op {
+                    Op::Ge | Op::Lt => value > cap,
+                    _ => value >= cap,
+                }
+            }
+            Node::Compare { .. } | Node::Regex { .. } => false,
+        }
+    }
+
+    fn resident_only(&self) -> bool {
+        let mut columns = Vec::new();
+        self.columns(&mut columns);
+        columns.iter().all(|c| RESIDENT_COLUMNS.contains(c))
+    }
+}
+
+/// Parsed and validated filter expression
+#[derive(Debug, Clone)]
+pub struct FilterExpr {
+    root: Node,
+}
+
+impl FilterExpr {
+    pub fn parse(input: &str) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            input,
+            tokens,
+            pos: 0,
+        };
+        let root = parser.parse_or()?;
+        match parser.peek() {
+            (_, Token::End) => Ok(FilterExpr { root }),
+            (at, token) => Err(parser.error(
+                *at,
+                format!(
+                    "expected and, or or the end of the filter, found {}",
+                    token.describe()
+                ),
+            )),
+        }
+    }
+
+    /// Case-insensitive regexp match on a string column, as used by `--name` and `--location`
+    pub fn regex(column: &str, pattern: &str) -> Result<Self> {
+        Ok(FilterExpr {
+            root: Node::Regex {
+                column: column.to_string(),
+                regex: RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| StoreError::Invalid(e.to_string()))?,
+                negated: false,
+            },
+        })
+    }
+
+    /// `column >= value`
+    pub fn at_least(column: &str, value: impl Into<Bson>) -> Self {
+        FilterExpr {
+            root: Node::Compare {
+                column: column.to_string(),
+                op: Op::Ge,
+                value: value.into(),
+            },
+        }
+    }
+
+    /// `column <= value`
+    pub fn at_most(column: &str, value: impl Into<Bson>) -> Self {
+        FilterExpr {
+            root: Node::Compare {
+                column: column.to_string(),
+                op: Op::Le,
+                value: value.into(),
+            },
+        }
+    }
+
+    /// All of `filters`, None if there are none
+    pub fn all(filters: Vec<FilterExpr>) -> Option<Self> {
+        let mut nodes = filters.into_iter().map(|f| f.root).collect::<Vec<_>>();
+        match nodes.len() {
+            0 => None,
+            1 => nodes.pop().map(|root| FilterExpr { root }),
+            _ => Some(FilterExpr {
+                root: Node::And(nodes),
+            }),
+        }
+    }
+
+    /// The expression as a MongoDB query on result rows
+    pub fn to_document(&self) -> bson::Document {
+        self.root.to_document()
+    }
+
+    pub fn matches(&self, row: &bson::Document) -> bool {
+        self.root.matches(row)
+    }
+
+    /// Whether a comparison on `column` can match differently once its values are capped
+    /// at `cap`, like `alarms_count` at `alarms_limit`
+    pub fn exceeds_cap(&self, column: &str, cap: f64) -> bool {
+        self.root.exceeds_cap(column, cap)
+    }
+
+    /// Splits a conjunction into the part that only uses resident columns, which can
+    /// select residents before their alarms are looked up, and the rest
+    pub fn split(self) -> (Option<FilterExpr>, Option<FilterExpr>) {
+        let nodes = match self.root {
+            Node::And(nodes) => nodes,
+            node => vec![node],
+        };
+        let (resident, rest): (Vec<_>, Vec<_>) = nodes.into_iter().partition(Node::resident_only);
+        let wrap = |nodes: Vec<Node>| {
+            FilterExpr::all(nodes.into_iter().map(|root| FilterExpr { root }).collect())
+        };
+        (wrap(resident), wrap(rest))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(String),
+    Op(Op),
+    LParen,
+    RParen,
+    End,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(s) => format!("'{s}'"),
+            Token::Str(s) => format!("\"{s}\""),
+            Token::Number(n) => format!("'{n}'"),
+            Token::Op(op) => format!("'{}'", op.as_str()),
+            Token::LParen => "'('".to_string(),
+            Token::RParen => "')'".to_string(),
+            Token::End => "end of filter".to_string(),
+        }
+    }
+}
+
+fn invalid(input: &str, at: usize, message: impl std::fmt::Display) -> anyhow::Error {
+    StoreError::Invalid(format!(
+        "invalid filter at column {}: {}\n  {}\n  {}^",
+        at + 1,
+        message,
+        input,
+        " ".repeat(at)
+    ))
+    .into()
+}
+
+/// Splits the input into tokens with their character position
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        let token = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                Token::LParen
+            }
+            ')' => {
+                i += 1;
+                Token::RParen
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid(input, start, "unterminated string")),
+                        Some('\\') if chars.get(i + 1).is_some() => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(&q) if q == c => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&other) => {
+                            value.push(other);
+                            i += 1;
+                        }
+                    }
+                }
+                Token::Str(value)
+            }
+            '=' | '!' | '<' | '>' | '~' => {
+                let next = chars.get(i + 1).copied();
+                let (op, len) = match (c, next) {
+                    ('!', Some('=')) => (Op::Ne, 2),
+                    ('!', Some('~')) => (Op::NotMatch, 2),
+                    ('<', Some('=')) => (Op::Le, 2),
+                    ('>', Some('=')) => (Op::Ge, 2),
+                    ('=', Some('=')) => (Op::Eq, 2),
+                    ('=', _) => (Op::Eq, 1),
+                    ('<', _) => (Op::Lt, 1),
+                    ('>', _) => (Op::Gt, 1),
+                    ('~', _) => (Op::Match, 1),
+                    _ => return Err(invalid(input, start, "expected '!=' or '!~'")),
+                };
+                i += len;
+                Token::Op(op)
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                i += 1;
+                while chars
+                    .get(i)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+                {
+                    i += 1;
+                }
+                Token::Number(chars[start..i].iter().collect())
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while chars
+                    .get(i)
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+                {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            }
+            other => return Err(invalid(input, start, format!("unexpected '{other}'"))),
+        };
+        tokens.push((start, token));
+    }
+    tokens.push((chars.len(), Token::End));
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &(usize, Token) {
+        &self.tokens[self.pos]
+    }
+
+    fn next(&mut self) -> (usize, Token) {
+        let token = self.tokens[self.pos].clone();
+        if token.1 != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error(&self, at: usize, message: impl std::fmt::Display) -> anyhow::Error {
+        invalid(self.input, at, message)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if matches!(&self.peek().1, Token::Ident(s) if s.eq_ignore_ascii_case(keyword)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node> {
+        let mut nodes = vec![self.parse_and()?];
+        while self.keyword("or") {
+            nodes.push(self.parse_and()?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            Node::Or(nodes)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Node> {
+        let mut nodes = vec![self.parse_not()?];
+        while self.keyword("and") {
+            nodes.push(self.parse_not()?);
+        }
+        Ok(if nodes.len() == 1 {
+            nodes.remove(0)
+        } else {
+            Node::And(nodes)
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<Node> {
+        if self.keyword("not") {
+            Ok(Node::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node> {
+        match self.next() {
+            (_, Token::LParen) => {
+                let node = self.parse_or()?;
+                match self.next() {
+                    (_, Token::RParen) => Ok(node),
+                    (at, token) => {
+                        Err(self.error(at, format!("expected ')', found {}", token.describe())))
+                    }
+                }
+            }
+            (at, Token::Ident(column)) => self.parse_comparison(at, column),
+            (at, token) => Err(self.error(
+                at,
+                format!("expected a column or '(', found {}", token.describe()),
+            )),
+        }
+    }
+
+    fn parse_comparison(&mut self, column_at: usize, column: String) -> Result<Node> {
+        let Some(&(_, column_type)) = COLUMNS.iter().find(|(c, _)| *c == column) else {
+            return Err(self.error(
+                column_at,
+                format!(
+                    "unknown column '{column}', expected one of {}",
+                    COLUMNS
+                        .iter()
+                        .map(|(c, _)| *c)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ));
+        };
+        let op = match self.next() {
+            (_, Token::Op(op)) => op,
+            (at, token) => {
+                return Err(self.error(
+                    at,
+                    format!(
+                        "expected an operator (=, !=, <, <=, >, >=, ~, !~) after '{column}', found {}",
+                        token.describe()
+                    ),
+                ));
+            }
+        };
+        let (value_at, value) = self.next();
+        let value = match (value, column_type, op) {
+            (Token::Str(pattern), ColumnType::String, Op::Match | Op::NotMatch) => {
+                let regex = RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| self.error(value_at, e))?;
+                return Ok(Node::Regex {
+                    column,
+                    regex,
+                    negated: op == Op::NotMatch,
+                });
+            }
+            (_, _, Op::Match | Op::NotMatch) => {
+                return Err(self.error(
+                    value_at,
+                    format!(
+                        "'{}' needs a string column and a quoted pattern",
+                        op.as_str()
+                    ),
+                ));
+            }
+            (Token::Ident(s), _, Op::Eq | Op::Ne) if s.eq_ignore_ascii_case("null") => Bson::Null,
+            (Token::Str(s), ColumnType::String, _) => Bson::String(s),
+            (Token::Number(n), ColumnType::Number, _) => match n.parse::<i64>() {
+                Ok(i) => Bson::Int64(i),
+                Err(_) => Bson::Double(
+                    n.parse::<f64>()
+                        .map_err(|_| self.error(value_at, format!("invalid number '{n}'")))?,
+                ),
+            },
+            (Token::Str(s), ColumnType::Date, _) => {
+                // a date-only bound covers the whole day, like --to-date
+                let date = if matches!(op, Op::Le | Op::Gt) {
+                    utils::parse_datetime_end(&s)
+                } else {
+                    utils::parse_datetime(&s)
+                };
+                Bson::DateTime(date.map_err(|e| self.error(value_at, e))?)
+            }
+            (Token::End, _, _) => {
+                return Err(self.error(
+                    value_at,
+                    format!("expected a value after '{}'", op.as_str()),
+                ));
+            }
+            (token, column_type, _) => {
+                let expected = match column_type {
+                    ColumnType::String => "a quoted string",
+                    ColumnType::Number => "a number",
+                    ColumnType::Date => "a quoted date",
+                };
+                return Err(self.error(
+                    value_at,
+                    format!(
+                        "'{column}' needs {expected} or null, found {}",
+                        token.describe()
+                    ),
+                ));
+            }
+        };
+        Ok(Node::Compare { column, op, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use super::*;
+    use crate::store::QueryParams;
+
+    /// Evaluates a query from [`FilterExpr::to_document`] on `row` like MongoDB
+    fn mongo_matches(query: &bson::Document, row: &bson::Document) -> bool {
+        query.iter().all(|(key, condition)| {
+            let nested = || condition.as_array().unwrap().iter();
+            let nested_match = |c: &Bson| mongo_matches(c.as_document().unwrap(), row);
+            match key.as_str() {
+                "$and" => nested().all(nested_match),
+                "$or" => nested().any(nested_match),
+                "$nor" => !nested().any(nested_match),
+                column => field_matches(row.get(column), condition),
+            }
+        })
+    }
+
+    fn field_matches(field: Option<&Bson>, condition: &Bson) -> bool {
+        let equal = |value: &Bson| paging::compare_bson(field, Some(value)) == Ordering::Equal;
+        let ordered = |value: &Bson| paging::compare_same_type(field, value);
+        match condition {
+            Bson::Document(ops) => ops.iter().all(|(op, value)| match op.as_str() {
+                "$ne" => !equal(value),
+                "$lt" => ordered(value).is_some_and(Ordering::is_lt),
+                "$lte" => ordered(value).is_some_and(Ordering::is_le),
+                "$gt" => ordered(value).is_some_and(Ordering::is_gt),
+                "$gte" => ordered(value).is_some_and(Ordering::is_ge),
+                "$not" => !field_matches(field, value),
+                _ => panic!("unexpected operator {op}"),
+            }),
+            Bson::RegularExpression(regex) => {
+                let regex = RegexBuilder::new(&regex.pattern)
+                    .case_insensitive(regex.options.contains('i'))
+                    .build()
+                    .unwrap();
+                matches!(field, Some(Bson::String(s)) if regex.is_match(s))
+            }
+            value => equal(value),
+        }
+    }
+
+    fn rows() -> Vec<bson::Document> {
+        let names = [Bson::from("Anna"), Bson::from("bert"), Bson::Null];
+        let counts = [
+            Bson::Int32(0),
+            Bson::Int32(5),
+            Bson::Double(7.5),
+            Bson::Null,
+        ];
+        let mut rows = Vec::new();
+        for name in &names {
+            for count in &counts {
+                rows.push(doc! {
+                    "_id": ObjectId::new(),
+                    "name": name.clone(),
+                    "location": "Wing B",
+                    "alarms_count": count.clone(),
+                });
+            }
+        }
+        rows.push(doc! { "_id": ObjectId::new() });
+        rows
+    }
+
+    #[test]
+    fn query_and_in_memory_evaluation_agree() {
+        let expressions = [
+            r#"name~"^a""#,
+            r#"name!~"^a""#,
+            r#"name="bert""#,
+            r#"name!="bert""#,
+            "name=null",
+            "name!=null",
+            "alarms_count>5",
+            "alarms_count>=5",
+            "alarms_count<5",
+            "alarms_count<=7.5",
+            "alarms_count=0",
+            "alarms_count!=null",
+            "not alarms_count>0",
+            r#"name~"^a" or alarms_count>=5 and location~"wing""#,
+            r#"not (name="Anna" or alarms_count<1)"#,
+        ];
+        let rows = rows();
+        for expression in expressions {
+            let filter = FilterExpr::parse(expression).unwrap();
+            let query = filter.to_document();
+            for row in &rows {
+                assert_eq!(
+                    mongo_matches(&query, row),
+                    filter.matches(row),
+                    "{expression} on {row}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parses_precedence_and_keywords() {
+        let filter = FilterExpr::parse(r#"name="a" OR name="b" and location="c""#).unwrap();
+        assert!(matches!(&filter.root, Node::Or(nodes) if nodes.len() == 2));
+        assert!(matches!(&filter.root, Node::Or(nodes) if matches!(nodes[1], Node::And(_))));
+        let filter = FilterExpr::parse("alarms_count == 3").unwrap();
+        assert!(matches!(
+            filter.root,
+            Node::Compare {
+                op: Op::Eq,
+                value: Bson::Int64(3),
+                ..
+            }
+        ));
+        let filter = FilterExpr::parse(r#"location='Wing \'B\''"#).unwrap();
+        assert!(matches!(
+            filter.root,
+            Node::Compare { value: Bson::String(ref s), .. } if s == "Wing 'B'"
+        ));
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let error = |input: &str| FilterExpr::parse(input).unwrap_err().to_string();
+        assert_eq!(
+            error("nme=1"),
+            "invalid filter at column 1: unknown column 'nme', expected one of \
+             name, location, birth, resident_since, age, length_of_stay, alarms_count, \
+             avg_duration, max_duration, avg_ack_duration, max_ack_duration, first, last, \
+             active_since, active_count, score\n  nme=1\n  ^"
+        );
+        assert_eq!(
+            error("age>\"x\""),
+            "invalid filter at column 5: 'age' needs a number or null, found \"x\"\n  age>\"x\"\n      ^"
+        );
+        assert!(error("age>").contains("column 5: expected a value after '>'"));
+        assert!(error("age>1 age<2").contains("column 7: expected and, or or the end"));
+        assert!(error("(age>1").contains("column 7: expected ')', found end of filter"));
+        assert!(error("name=\"x").contains("column 6: unterminated string"));
+        assert!(error("age ! 1").contains("column 5: expected '!=' or '!~'"));
+        assert!(error("age~\"1\"").contains("'~' needs a string column and a quoted pattern"));
+        assert!(error("name~\"(\"").contains("column 6: regex parse error"));
+    }
+
+    #[test]
+    fn alarm_counts_beyond_the_limit_are_refused() {
+        let query = |filter: &str, alarms_limit: i32| QueryParams {
+            filter: Some(filter.to_string()),
+            alarms_limit,
+            ..Default::default()
+        };
+        for filter in [
+            "alarms_count>20",
+            "alarms_count>=11",
+            "not alarms_count<=10",
+        ] {
+            assert!(query(filter, 10).filter_expr().is_err(), "{filter}");
+            assert!(
+                query(filter, 0).filter_expr().unwrap().is_some(),
+                "{filter}"
+            );
+        }
+        for filter in ["alarms_count>9", "alarms_count>=10", "alarms_count<10"] {
+            assert!(query(filter, 10).filter_expr().is_ok(), "{filter}");
+        }
+        let shortcut = QueryParams {
+            min_alarms: Some(11),
+            ..Default::default()
+        };
+        assert!(shortcut.filter_expr().is_err());
+    }
+}
@@ -0,0 +1,1003 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{Context as _, Result};
+use futures::{StreamExt as _, stream::BoxStream};
+use mongodb::bson::{self, doc, oid::ObjectId};
+use tracing::{Level, info, warn};
+
+use super::{
+    BatchOutcome, GroupBy, QueryParams, ReportParams, ResidentFilter, ResidentStore, StoreError,
+    WriteOutcome, report,
+};
+use crate::{
+    model::{ActiveAlarm, Alarm, AlarmRef, HistoryAlarm, Resident, SCHEMA_VERSION},
+    utils,
+};
+
+/// Residents and alarm history, laid out like the MongoDB collections
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct MemoryData {
+    residents: Vec<Resident>,
+    #[serde(default)]
+    alarm_history: Vec<HistoryAlarm>,
+}
+
+/// Persisted file, either the current layout or the residents array
+/// with embedded history written by older versions
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum MemoryFile {
+    Data(MemoryData),
+    Residents(Vec<Resident>),
+}
+
+impl MemoryData {
+    /// Brings residents to the current schema: assigns missing resident ids and
+    /// moves embedded history to `alarm_history`. Alarm ids are assigned on read.
+    fn normalize(&mut self) {
+        for resident in &mut self.residents {
+            resident.schema_version = SCHEMA_VERSION;
+            let resident_id = *resident.id.get_or_insert_with(ObjectId::new);
+            self.alarm_history.extend(
+                std::mem::take(&mut resident.alarms)
+                    .into_iter()
+                    .map(|alarm| HistoryAlarm::new(resident_id, alarm)),
+            );
+        }
+    }
+
+    fn position(&self, name: &str, birth: bson::DateTime) -> Option<usize> {
+        self.residents
+            .iter()
+            .position(|r| r.name == name && r.birth == birth)
+    }
+
+    /// Inserts the resident or updates location and resident_since of the existing one
+    fn insert_or_update(&mut self, resident: &Resident) -> WriteOutcome {
+        match self.position(&resident.name, resident.birth) {
+            Some(pos) => {
+                let existing = &mut self.residents[pos];
+                let modified = existing.location != resident.location
+                    || existing.resident_since != resident.resident_since;
+                existing.location = resident.location.clone();
+                existing.resident_since = resident.resident_since;
+                WriteOutcome::Updated {
+                    matched: 1,
+                    modified: modified as u64,
+                }
+            }
+            None => {
+                let mut resident = resident.clone();
+                let id = *resident.id.get_or_insert_with(ObjectId::new);
+                resident.schema_version = SCHEMA_VERSION;
+                self.alarm_history.extend(
+                    std::mem::take(&mut resident.alarms)
+                        .into_iter()
+                        .map(|alarm| HistoryAlarm::new(id, alarm)),
+                );
+                self.residents.push(resident);
+                WriteOutcome::Inserted(id.into())
+            }
+        }
+    }
+}
+
+/// [`ResidentStore`] kept in process memory, optionally persisted to a JSON file
+/// after every change
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+    path: Option<PathBuf>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the store from `path` if it exists; changes are saved back to it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut data = if path.exists() {
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            match serde_json::from_str(&contents)
+                .with_context(|| format!("cannot parse {}", path.display()))?
+            {
+                MemoryFile::Data(data) => data,
+                MemoryFile::Residents(residents) => MemoryData {
+                    residents,
+                    alarm_history: Vec::new(),
+                },
+            }
+        } else {
+            MemoryData::default()
+        };
+        data.normalize();
+        info!(
+            "Memory store loaded {} residents and {} history alarms from {}",
+            data.residents.len(),
+            data.alarm_history.len(),
+            path.display()
+        );
+        Ok(MemoryStore {
+            data: Mutex::new(data),
+            path: Some(path),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn persist(&self, data: &MemoryData) -> Result<()> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(data)?)
+                .with_context(|| format!("cannot write {}", tmp.display()))?;
+            std::fs::rename(&tmp, path)
+                .with_context(|| format!("cannot write {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ResidentStore for MemoryStore {
+    #[tracing::instrument(name = "insert_or_update", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
+    async fn insert(&self, resident: &Resident) -> Result<WriteOutcome> {
+        let mut data = self.lock();
+        let outcome = data.insert_or_update(resident);
+        match &outcome {
+            WriteOutcome::Inserted(id) => info!("New resident inserted with id: {}", id),
+            WriteOutcome::Updated { matched, modified } => {
+                warn!(
+                    "Duplicate key error: A resident with the same name and birth date already exists. Updating..."
+                );
+                info!(
+                    "Resident updated Matched: {} Updated: {}",
+                    matched, modified
+                );
+            }
+        }
+        self.persist(&data)?;
+        Ok(outcome)
+    }
+
+    async fn upsert(&self, resident: &Resident) -> Result<WriteOutcome> {
+        // without a server there is no difference between insert-or-update and upsert
+        self.insert(resident).await
+    }
+
+    async fn write_batch(
+        &self,
+        residents: &[Resident],
+        upsert: bool,
+        ordered: bool,
+    ) -> Result<BatchOutcome> {
+        let mut data = self.lock();
+        let mut outcome = BatchOutcome::default();
+        for (index, resident) in residents.iter().enumerate() {
+            // like the update command of the server: an upsert creates a new resident
+            // from the updated fields only, an insert from the whole document, which
+            // fails if its id belongs to another resident
+            let resident = if upsert {
+                Resident {
+                    id: None,
+                    alarms: Vec::new(),
+                    active_alarms: Vec::new(),
+                    ..resident.clone()
+                }
+            } else if let Some(id) = resident.id
+                && data.position(&resident.name, resident.birth).is_none()
+                && data.residents.iter().any(|r| r.id == Some(id))
+            {
+                outcome
+                    .failed
+                    .push((index, format!("duplicate key: _id {} is taken", id)));
+                if ordered {
+                    break;
+                }
+                continue;
+            } else {
+                resident.clone()
+            };
+            match data.insert_or_update(&resident) {
+                WriteOutcome::Inserted(_) => outcome.inserted += 1,
+                WriteOutcome::Updated { .. } => outcome.updated += 1,
+            }
+        }
+        // one write for the whole batch
+        self.persist(&data)?;
+        Ok(outcome)
+    }
+
+    // Delete a resident by name and birth date, together with its alarm history
+    #[tracing::instrument(name = "delete", skip_all, fields(name=%name, birth=%birth), level = Level::TRACE)]
+    async fn delete(&self, name: &str, birth: bson::DateTime) -> Result<bool> {
+        let mut data = self.lock();
+        if let Some(pos) = data.position(name, birth) {
+            let resident = data.residents.remove(pos);
+            let history_count = data.alarm_history.len();
+            data.alarm_history
+                .retain(|alarm| Some(alarm.resident_id) != resident.id);
+            let history_count = history_count - data.alarm_history.len();
+            self.persist(&data)?;
+            info!(
+                "Resident deleted successfully with {} history alarms.",
+                history_count
+            );
+            Ok(true)
+        } else {
+            warn!("No resident found to delete.");
+            Ok(false)
+        }
+    }
+
+    async fn find(&self, name: &str, birth: bson::DateTime) -> Result<Option<Resident>> {
+        let data = self.lock();
+        Ok(data
+            .position(name, birth)
+            .map(|pos| data.residents[pos].clone()))
+    }
+
+    #[tracing::instrument(name = "new_alarm", skip_all, fields(name=%name, birth=%birth), level = Level::TRACE)]
+    async fn new_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        message: &str,
+        start_time: Option<bson::DateTime>,
+    ) -> Result<ActiveAlarm> {
+        let mut data = self.lock();
+        let Some(pos) = data.position(name, birth) else {
+            anyhow::bail!(StoreError::NotFound(
+                "No resident found to add alarm.".to_string()
+            ));
+        };
+        let new_alarm = ActiveAlarm::new(message, start_time);
+        data.residents[pos].active_alarms.push(new_alarm.clone());
+        self.persist(&data)?;
+        info!(
+            "New Alarm\t'{name}' '{birth}' '{}'",
+            new_alarm.time.try_to_rfc3339_string()?,
+        );
+        Ok(new_alarm)
+    }
+
+    #[tracing::instrument(name = "ack_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm), level = Level::TRACE)]
+    async fn ack_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        by: &str,
+    ) -> Result<Option<ActiveAlarm>> {
+        let mut data = self.lock();
+        let Some(active) = data.position(name, birth).and_then(|pos| {
+            data.residents[pos]
+                .active_alarms
+                .iter_mut()
+                .find(|a| alarm.matches(a) && a.acknowledged_at.is_none())
+        }) else {
+            warn!("No unacknowledged active alarm found.");
+            return Ok(None);
+        };
+        active.acknowledged_at = Some(bson::DateTime::now());
+        active.acknowledged_by = Some(by.to_string());
+        let acked = active.clone();
+        self.persist(&data)?;
+        info!(
+            "Acknowledged alarm {} for resident: {} by {}",
+            acked.id, name, by
+        );
+        Ok(Some(acked))
+    }
+
+    #[tracing::instrument(name = "clear_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm), level = Level::TRACE)]
+    async fn clear_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        duration: Option<u64>,
+    ) -> Result<Option<Alarm>> {
+        let mut data = self.lock();
+        let Some(pos) = data.position(name, birth) else {
+            warn!("No active alarm found to clear.");
+            return Ok(None);
+        };
+        let resident = &mut data.residents[pos];
+        let Some(pos) = resident.active_alarms.iter().position(|a| alarm.matches(a)) else {
+            warn!("No active alarm found to clear.");
+            return Ok(None);
+        };
+        let active = resident.active_alarms.remove(pos);
+        let resident_id = resident.id.unwrap_or_default();
+        let duration = duration.unwrap_or(
+            bson::DateTime::now()
+                .checked_duration_since(active.time)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        let cleared = active.cleared(duration);
+        data.alarm_history
+            .push(HistoryAlarm::new(resident_id, cleared.clone()));
+        self.persist(&data)?;
+        info!(
+            "Cleared alarm {} for resident: {}, message: {}, start_time: {}, duration_sec: {}",
+            cleared.id,
+            name,
+            cleared.message,
+            cleared.time.try_to_rfc3339_string()?,
+            duration
+        );
+        Ok(Some(cleared))
+    }
+
+    async fn with_history(
+        &self,
+        filter: &ResidentFilter,
+        from_date: Option<bson::DateTime>,
+        to_date: Option<bson::DateTime>,
+    ) -> Result<BoxStream<'_, Result<Resident>>> {
+        let data = self.lock();
+        let mut residents = data
+            .residents
+            .iter()
+            .filter(|r| filter.matches(r))
+            .cloned()
+            .collect::<Vec<_>>();
+        residents.sort_by(|a, b| (&a.name, a.birth).cmp(&(&b.name, b.birth)));
+        for resident in &mut residents {
+            resident.alarms = data
+                .alarm_history
+                .iter()
+                .filter(|alarm| Some(alarm.resident_id) == resident.id)
+                .filter(|alarm| {
+                    from_date.is_none_or(|f| alarm.time >= f)
+                        && to_date.is_none_or(|t| alarm.time <= t)
+                })
+                .cloned()
+                .map(Alarm::from)
+                .collect();
+            resident.alarms.sort_by_key(|alarm| alarm.time);
+        }
+        Ok(futures::stream::iter(residents.into_iter().map(Ok)).boxed())
+    }
+
+    #[tracing::instrument(name = "restore", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
+    async fn restore(&self, resident: &Resident) -> Result<bool> {
+        let mut data = self.lock();
+        let mut resident = resident.clone();
+        let alarms = std::mem::take(&mut resident.alarms);
+        resident.schema_version = SCHEMA_VERSION;
+        let (resident_id, inserted) = match data.position(&resident.name, resident.birth) {
+            Some(pos) => {
+                resident.id = data.residents[pos].id;
+                data.residents[pos] = resident;
+                (data.residents[pos].id.unwrap_or_default(), false)
+            }
+            None => {
+                let id = *resident.id.get_or_insert_with(ObjectId::new);
+                data.residents.push(resident);
+                (id, true)
+            }
+        };
+        let stored = data
+            .alarm_history
+            .iter()
+            .map(|alarm| alarm.id)
+            .collect::<HashSet<_>>();
+        data.alarm_history.extend(
+            alarms
+                .into_iter()
+                .filter(|alarm| !stored.contains(&alarm.id))
+                .map(|alarm| HistoryAlarm::new(resident_id, alarm)),
+        );
+        self.persist(&data)?;
+        Ok(inserted)
+    }
+
+    async fn delete_matching(&self, filter: &ResidentFilter) -> Result<u64> {
+        let mut data = self.lock();
+        let (deleted, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut data.residents)
+            .into_iter()
+            .partition(|r| filter.matches(r));
+        data.residents = kept;
+        let deleted_ids = deleted.iter().filter_map(|r| r.id).collect::<HashSet<_>>();
+        data.alarm_history
+            .retain(|alarm| !deleted_ids.contains(&alarm.resident_id));
+        self.persist(&data)?;
+        Ok(deleted.len() as u64)
+    }
+
+    async fn with_active_alarms(&self) -> Result<Vec<Resident>> {
+        let data = self.lock();
+        Ok(data
+            .residents
+            .iter()
+            .filter(|r| !r.active_alarms.is_empty())
+            .cloned()
+            .collect())
+    }
+
+    #[tracing::instrument(name = "escalate_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm_id, level=level), level = Level::TRACE)]
+    async fn escalate_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<bool> {
+        let mut data = self.lock();
+        let Some(active) = data.position(name, birth).and_then(|pos| {
+            data.residents[pos].active_alarms.iter_mut().find(|a| {
+                a.id == alarm_id
+                    && a.escalation_level.unwrap_or_default() + 1 == level
+                    && a.unsent_escalations().is_empty()
+            })
+        }) else {
+            return Ok(false);
+        };
+        active.escalation_level = Some(level);
+        active.escalated_at = Some(bson::DateTime::now());
+        active.escalation_sent = Some(level - 1);
+        self.persist(&data)?;
+        Ok(true)
+    }
+
+    async fn escalation_sent(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<()> {
+        let mut data = self.lock();
+        let Some(active) = data.position(name, birth).and_then(|pos| {
+            data.residents[pos]
+                .active_alarms
+                .iter_mut()
+                .find(|a| a.id == alarm_id)
+        }) else {
+            return Ok(());
+        };
+        active.escalation_sent = active.escalation_sent.max(Some(level));
+        self.persist(&data)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "query", skip_all, level = Level::TRACE)]
+    async fn query(&self, query_params: &QueryParams) -> Result<Vec<bson::Document>> {
+        let filter = query_params.filter_expr()?;
+        let search = query_params.text_search()?;
+        let (from_date, to_date) = query_params.date_range()?;
+        let today = utils::today();
+        let data = self.lock();
+        let results = data
+            .residents
+            .iter()
+            .filter_map(|r| {
+                let mut filtered = data
+                    .alarm_history
+                    .iter()
+                    .filter(|a| Some(a.resident_id) == r.id)
+                    .filter(|a| from_date.is_none_or(|from| a.time >= from))
+                    .filter(|a| to_date.is_none_or(|to| a.time <= to))
+                    .collect::<Vec<_>>();
+                filtered.sort_by_key(|a| std::cmp::Reverse(a.time));
+                // every alarm in the date window is searched, not only the most recent ones
+                let matched = search.as_ref().map(|search| {
+                    let history = filtered.iter().map(|a| (a.id, &a.message));
+                    let active = r.active_alarms.iter().map(|a| (a.id, &a.message));
+                    history
+                        .chain(active)
+                        .filter_map(|(id, message)| Some((id, search.score(message)?)))
+                        .collect::<Vec<_>>()
+                });
+                if matched.as_ref().is_some_and(|m| m.is_empty()) {
+                    return None;
+                }
+                // most recent alarms_limit history alarms in the date window
+                if query_params.alarms_limit > 0 {
+                    filtered.truncate(query_params.alarms_limit as usize);
+                }
+                if r.active_alarms.is_empty() && filtered.is_empty() {
+                    return None;
+                }
+                let durations = filtered.iter().map(|a| a.duration_sec);
+                let ack_durations = filtered
+                    .iter()
+                    .filter_map(|a| a.ack_duration_sec)
+                    .collect::<Vec<_>>();
+                let mut row = doc! {
+                    "_id": r.id,
+                    "name": &r.name,
+                    "birth": r.birth,
+                    "location": &r.location,
+                    "resident_since": r.resident_since,
+                    "age": today
+                        .years_since(utils::local_date(r.birth))
+                        .map_or(0, |years| years as i32),
+                    "length_of_stay": (today - utils::local_date(r.resident_since)).num_days(),
+                    "alarms_count": filtered.len() as i32,
+                    "avg_duration": if filtered.is_empty() {
+                        bson::Bson::Null
+                    } else {
+                        bson::Bson::Double(
+                            durations.clone().sum::<u64>() as f64 / filtered.len() as f64,
+                        )
+                    },
+                    "max_duration": durations.max().map(|d| d as i64),
+                    "avg_ack_duration": if ack_durations.is_empty() {
+                        bson::Bson::Null
+                    } else {
+                        bson::Bson::Double(
+                            ack_durations.iter().sum::<u64>() as f64 / ack_durations.len() as f64,
+                        )
+                    },
+                    "max_ack_duration": ack_durations.iter().max().map(|d| *d as i64),
+                    "first": filtered.iter().map(|a| a.time).min(),
+                    "last": filtered.iter().map(|a| a.time).max(),
+                    "active_since": r.active_alarms.iter().map(|a| a.time).min(),
+                    "active_count": r.active_alarms.len() as i32,
+                    "active_alarm_ids": r.active_alarms.iter().map(|a| a.id).collect::<Vec<_>>(),
+                };
+                if let Some(matched) = matched {
+                    row.insert(
+                        "matched_alarm_ids",
+                        matched.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                    );
+                    row.insert("score", matched.iter().map(|(_, score)| score).sum::<f64>());
+                }
+                Some(row)
+            })
+            .filter(|row| filter.as_ref().is_none_or(|f| f.matches(row)))
+            .collect::<Vec<_>>();
+        query_params.paginate(results)
+    }
+
+    #[tracing::instrument(name = "report", skip_all, level = Level::TRACE)]
+    async fn report(&self, params: &ReportParams) -> Result<Vec<bson::Document>> {
+        let filter = params.window.resident_filter()?;
+        let (from_date, to_date) = params.window.date_range()?;
+        let data = self.lock();
+        let residents = data
+            .residents
+            .iter()
+            .filter_map(|r| Some((r.id?, r)))
+            .collect::<HashMap<_, _>>();
+        // sorted by key like the MongoDB $sort, alarms of deleted residents have no location
+        let mut groups = BTreeMap::<Option<String>, (Vec<i64>, HashSet<ObjectId>)>::new();
+        for alarm in &data.alarm_history {
+            if from_date.is_some_and(|from| alarm.time < from)
+                || to_date.is_some_and(|to| alarm.time > to)
+            {
+                continue;
+            }
+            let resident = residents.get(&alarm.resident_id);
+            if !filter.is_empty() && !resident.is_some_and(|r| filter.matches(r)) {
+                continue;
+            }
+            let key = match params.group_by.time_format() {
+                Some(format) => Some(
+                    alarm
+                        .time
+                        .to_chrono()
+                        .with_timezone(&utils::timezone())
+                        .format(format)
+                        .to_string(),
+                ),
+                None if params.group_by == GroupBy::Message => Some(alarm.message.clone()),
+                None => resident.map(|r| r.location.clone()),
+            };
+            let (durations, affected) = groups.entry(key).or_default();
+            durations.push(alarm.duration_sec as i64);
+            affected.insert(alarm.resident_id);
+        }
+        let mut rows = groups
+            .into_iter()
+            .map(|(key, (mut durations, affected))| {
+                durations.sort_unstable();
+                let total = durations.iter().sum::<i64>();
+                doc! {
+                    params.group_by.column(): key,
+                    "count": durations.len() as i64,
+                    "total_duration": total,
+                    "avg_duration": total as f64 / durations.len() as f64,
+                    "median_duration": report::percentile(&durations, 0.5),
+                    "p90_duration": report::percentile(&durations, 0.9),
+                    "max_duration": durations.last().copied(),
+                    "residents": affected.len() as i32,
+                }
+            })
+            .collect::<Vec<_>>();
+        report::finish_rows(params.group_by, &mut rows);
+        Ok(rows)
+    }
+
+    async fn stats(&self) -> Result<Vec<bson::Document>> {
+        let data = self.lock();
+        Ok(vec![doc! {
+            "backend": "memory",
+            "file": self.path.as_ref().map(|p| p.display().to_string()),
+            "count": data.residents.len() as i64,
+            "active_alarms": data.residents.iter().map(|r| r.active_alarms.len()).sum::<usize>() as i64,
+            "alarm_history": data.alarm_history.len() as i64,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt as _;
+
+    use super::*;
+    use crate::store::AlarmWindow;
+
+    async fn store_with_ann() -> (MemoryStore, Resident) {
+        let store = MemoryStore::new();
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        (store, ann)
+    }
+
+    #[tokio::test]
+    async fn alarm_lifecycle() {
+        let (store, ann) = store_with_ann().await;
+        let raised = store
+            .new_alarm(&ann.name, ann.birth, "fall", None)
+            .await
+            .unwrap();
+
+        let acked = store
+            .ack_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), "nurse")
+            .await
+            .unwrap()
+            .expect("raised alarm is acknowledged");
+        assert_eq!(acked.acknowledged_by.as_deref(), Some("nurse"));
+        // an acknowledged alarm cannot be acknowledged again
+        let again = store
+            .ack_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), "other")
+            .await
+            .unwrap();
+        assert!(again.is_none());
+
+        let cleared = store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), Some(90))
+            .await
+            .unwrap()
+            .expect("active alarm is cleared");
+        assert_eq!(cleared.id, raised.id);
+        assert_eq!(cleared.duration_sec, 90);
+        assert_eq!(cleared.acknowledged_by.as_deref(), Some("nurse"));
+        assert!(cleared.ack_duration_sec.is_some());
+
+        let resident = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        assert!(resident.active_alarms.is_empty());
+        let cleared_again = store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), None)
+            .await
+            .unwrap();
+        assert!(cleared_again.is_none());
+
+        let rows = store.query(&QueryParams::default()).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get_i32("alarms_count").unwrap(), 1);
+        assert_eq!(rows[0].get_i64("max_duration").unwrap(), 90);
+        assert_eq!(rows[0].get_i32("active_count").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn clear_by_time() {
+        let (store, ann) = store_with_ann().await;
+        let time = utils::parse_datetime("2024-07-15T08:30").unwrap();
+        store
+            .new_alarm(&ann.name, ann.birth, "call", Some(time))
+            .await
+            .unwrap();
+        let cleared = store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Time(time), None)
+            .await
+            .unwrap()
+            .expect("alarm raised at that time is cleared");
+        assert_eq!(cleared.time, time);
+        assert!(cleared.ack_duration_sec.is_none());
+    }
+
+    #[tokio::test]
+    async fn with_history_keeps_alarms_in_the_window() {
+        let (store, ann) = store_with_ann().await;
+        for time in ["2024-07-14T08:00", "2024-07-15T08:00", "2024-07-16T08:00"] {
+            let time = utils::parse_datetime(time).unwrap();
+            store
+                .new_alarm(&ann.name, ann.birth, "call", Some(time))
+                .await
+                .unwrap();
+            store
+                .clear_alarm(&ann.name, ann.birth, AlarmRef::Time(time), Some(60))
+                .await
+                .unwrap();
+        }
+        store
+            .new_alarm(&ann.name, ann.birth, "fall", None)
+            .await
+            .unwrap();
+        let filter = ResidentFilter::new(&None, &None).unwrap();
+        let from = utils::parse_datetime("2024-07-15T00:00").ok();
+        let residents: Vec<Resident> = store
+            .with_history(&filter, from, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(residents[0].alarms.len(), 2);
+        assert!(residents[0].alarms.is_sorted_by_key(|alarm| alarm.time));
+        assert_eq!(residents[0].active_alarms.len(), 1);
+        let exported: Vec<Resident> = store
+            .export(&filter)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(exported[0].alarms.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn force_close_clears_every_active_alarm() {
+        let (store, ann) = store_with_ann().await;
+        for message in ["call", "fall", "door"] {
+            store
+                .new_alarm(&ann.name, ann.birth, message, None)
+                .await
+                .unwrap();
+        }
+        let cleared = store.force_close(&ann.name, ann.birth).await.unwrap();
+        assert_eq!(cleared.len(), 3);
+        assert!(
+            store
+                .force_close(&ann.name, ann.birth)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let resident = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        assert!(resident.active_alarms.is_empty());
+        let exported = store
+            .export(&ResidentFilter::default())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].as_ref().unwrap().alarms.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn alarms_need_a_resident() {
+        let store = MemoryStore::new();
+        let birth = utils::parse_datetime("1940-05-01").unwrap();
+        let err = store
+            .new_alarm("Nobody", birth, "call", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StoreError>(),
+            Some(StoreError::NotFound(_))
+        ));
+        assert!(store.force_close("Nobody", birth).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn insert_updates_an_existing_resident() {
+        let (store, mut ann) = store_with_ann().await;
+        ann.location = "Room 2".to_string();
+        let outcome = store.insert(&ann).await.unwrap();
+        assert!(matches!(
+            outcome,
+            WriteOutcome::Updated {
+                matched: 1,
+                modified: 1
+            }
+        ));
+        let resident = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        assert_eq!(resident.location, "Room 2");
+        assert!(store.delete(&ann.name, ann.birth).await.unwrap());
+        assert!(!store.delete(&ann.name, ann.birth).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn write_batch_upsert_writes_the_updated_fields_only() {
+        let (store, ann) = store_with_ann().await;
+        store
+            .new_alarm(&ann.name, ann.birth, "call", None)
+            .await
+            .unwrap();
+        let ann = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        assert!(store.delete(&ann.name, ann.birth).await.unwrap());
+
+        let outcome = store
+            .write_batch(std::slice::from_ref(&ann), true, false)
+            .await
+            .unwrap();
+        assert_eq!((outcome.inserted, outcome.updated), (1, 0));
+        let upserted = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        assert!(upserted.active_alarms.is_empty());
+        assert_ne!(upserted.id, ann.id);
+
+        assert!(store.delete(&ann.name, ann.birth).await.unwrap());
+        let outcome = store
+            .write_batch(std::slice::from_ref(&ann), false, false)
+            .await
+            .unwrap();
+        assert_eq!(outcome.inserted, 1);
+        let inserted = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        assert_eq!(inserted.active_alarms.len(), 1);
+        assert_eq!(inserted.id, ann.id);
+    }
+
+    #[tokio::test]
+    async fn ordered_write_batch_stops_at_the_first_failure() {
+        let (store, ann) = store_with_ann().await;
+        let ann = store.find(&ann.name, ann.birth).await.unwrap().unwrap();
+        // a new resident reusing the id of Ann
+        let mut bob = Resident::new("Bob", "1941-02-03", "Room 2", "2021-01-01").unwrap();
+        bob.id = ann.id;
+        let carl = Resident::new("Carl", "1942-03-04", "Room 3", "2022-01-01").unwrap();
+        let batch = [ann.clone(), bob, carl.clone()];
+
+        let outcome = store.write_batch(&batch, false, true).await.unwrap();
+        assert_eq!((outcome.inserted, outcome.updated), (0, 1));
+        assert_eq!(outcome.failed.len(), 1);
+        assert_eq!(outcome.failed[0].0, 1);
+        assert!(store.find(&carl.name, carl.birth).await.unwrap().is_none());
+
+        let outcome = store.write_batch(&batch, false, false).await.unwrap();
+        assert_eq!((outcome.inserted, outcome.updated), (1, 1));
+        assert_eq!(outcome.failed.len(), 1);
+        assert!(store.find(&carl.name, carl.birth).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn message_search_reports_matched_alarms_by_relevance() {
+        let (store, ann) = store_with_ann().await;
+        let bob = Resident::new("Bob", "1941-02-03", "Room 2", "2021-01-01").unwrap();
+        let carl = Resident::new("Carl", "1942-03-04", "Room 3", "2022-01-01").unwrap();
+        store.insert(&bob).await.unwrap();
+        store.insert(&carl).await.unwrap();
+        let time = utils::parse_datetime("2024-07-15T08:30").unwrap();
+        let fall = store
+            .new_alarm(&ann.name, ann.birth, "fall in bathroom", Some(time))
+            .await
+            .unwrap();
+        store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Id(fall.id), Some(60))
+            .await
+            .unwrap();
+        store
+            .new_alarm(&ann.name, ann.birth, "door open", None)
+            .await
+            .unwrap();
+        store
+            .new_alarm(&bob.name, bob.birth, "door open", None)
+            .await
+            .unwrap();
+        let carl_fall = store
+            .new_alarm(&carl.name, carl.birth, "Fall", None)
+            .await
+            .unwrap();
+
+        let params = QueryParams {
+            message: Some("fall".to_string()),
+            ..Default::default()
+        };
+        let rows = store.query(&params).await.unwrap();
+        // no sort given: most relevant first, Bob has no matching alarm
+        let names: Vec<&str> = rows.iter().map(|r| r.get_str("name").unwrap()).collect();
+        assert_eq!(names, ["Carl", "Ann"]);
+        assert_eq!(rows[0].get_f64("score").unwrap(), 1.0);
+        assert_eq!(
+            rows[0].get_array("matched_alarm_ids").unwrap(),
+            &vec![bson::Bson::ObjectId(carl_fall.id)]
+        );
+        // only the cleared fall matched, not the active door alarm
+        assert!((rows[1].get_f64("score").unwrap() - (0.5 + 0.5 / 3.0)).abs() < 1e-9);
+        assert_eq!(
+            rows[1].get_array("matched_alarm_ids").unwrap(),
+            &vec![bson::Bson::ObjectId(fall.id)]
+        );
+
+        let params = QueryParams {
+            sort: Some("name".to_string()),
+            ..params
+        };
+        let rows = store.query(&params).await.unwrap();
+        let names: Vec<&str> = rows.iter().map(|r| r.get_str("name").unwrap()).collect();
+        assert_eq!(names, ["Ann", "Carl"]);
+    }
+
+    #[tokio::test]
+    async fn report_statistics_of_known_durations() {
+        let (store, ann) = store_with_ann().await;
+        let bob = Resident::new("Bob", "1941-02-03", "Room 1", "2021-01-01").unwrap();
+        let carl = Resident::new("Carl", "1942-03-04", "Room 2", "2022-01-01").unwrap();
+        store.insert(&bob).await.unwrap();
+        store.insert(&carl).await.unwrap();
+        let alarms = [
+            (&ann, "2024-07-01T08:00", 30),
+            (&ann, "2024-07-02T08:00", 10),
+            (&ann, "2024-07-03T08:00", 100),
+            (&ann, "2024-07-04T08:00", 20),
+            (&ann, "2024-07-05T08:00", 40),
+            (&bob, "2024-07-01T09:00", 90),
+            (&bob, "2024-07-02T09:00", 60),
+            (&bob, "2024-07-03T09:00", 50),
+            (&bob, "2024-07-04T09:00", 80),
+            (&bob, "2024-07-05T09:00", 70),
+            (&carl, "2024-07-01T10:00", 5),
+            // outside the window
+            (&carl, "2024-08-01T10:00", 500),
+        ];
+        for (resident, time, duration) in alarms {
+            let time = utils::parse_datetime(time).unwrap();
+            store
+                .new_alarm(&resident.name, resident.birth, "call", Some(time))
+                .await
+                .unwrap();
+            store
+                .clear_alarm(
+                    &resident.name,
+                    resident.birth,
+                    AlarmRef::Time(time),
+                    Some(duration),
+                )
+                .await
+                .unwrap();
+        }
+
+        let params = ReportParams {
+            group_by: GroupBy::Location,
+            window: AlarmWindow {
+                from_date: Some("2024-07-01".to_string()),
+                to_date: Some("2024-07-31".to_string()),
+                ..Default::default()
+            },
+        };
+        let rows = store.report(&params).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        let room_1 = &rows[0];
+        assert_eq!(room_1.get_str("location").unwrap(), "Room 1");
+        assert_eq!(room_1.get_i64("count").unwrap(), 10);
+        assert_eq!(room_1.get_i64("total_duration").unwrap(), 550);
+        assert_eq!(room_1.get_f64("avg_duration").unwrap(), 55.0);
+        assert_eq!(room_1.get_i64("median_duration").unwrap(), 50);
+        assert_eq!(room_1.get_i64("p90_duration").unwrap(), 90);
+        assert_eq!(room_1.get_i64("max_duration").unwrap(), 100);
+        assert_eq!(room_1.get_i32("residents").unwrap(), 2);
+        let room_2 = &rows[1];
+        assert_eq!(room_2.get_str("location").unwrap(), "Room 2");
+        assert_eq!(room_2.get_i64("count").unwrap(), 1);
+        assert_eq!(room_2.get_i64("median_duration").unwrap(), 5);
+        assert_eq!(room_2.get_i64("p90_duration").unwrap(), 5);
+        assert_eq!(room_2.get_i32("residents").unwrap(), 1);
+
+        let params = ReportParams {
+            group_by: GroupBy::Weekday,
+            ..params
+        };
+        let rows = store.report(&params).await.unwrap();
+        // 2024-07-01 is a Monday
+        let days: Vec<&str> = rows.iter().map(|r| r.get_str("weekday").unwrap()).collect();
+        assert_eq!(days, ["Mon", "Tue", "Wed", "Thu", "Fri"]);
+        assert_eq!(rows[0].get_i64("count").unwrap(), 3);
+        assert_eq!(rows[0].get_i32("residents").unwrap(), 3);
+    }
+}
@@ -0,0 +1,248 @@
+//! Ordered schema migrations for the MongoDB backend.
+//!
+//! Each migration brings resident documents to its `version`. Applied migrations are
+//! recorded in the `schema_migrations` collection and skipped on later runs, and every
+//! migration only touches documents still in the old layout, so an interrupted run can
+//! simply be repeated.
+
+use anyhow::Result;
+use futures::{FutureExt as _, TryStreamExt as _, future::BoxFuture};
+use mongodb::bson::{self, Bson, doc, oid::ObjectId};
+use tracing::{Level, info};
+
+use super::MongoStore;
+use crate::model::SCHEMA_VERSION;
+
+pub const MIGRATIONS_COLLECTION: &str = "schema_migrations";
+
+type MigrationFn = for<'a> fn(&'a MongoStore, bool) -> BoxFuture<'a, Result<u64>>;
+
+pub struct Migration {
+    /// resident `schema_version` once applied
+    pub version: u32,
+    pub name: &'static str,
+    pub description: &'static str,
+    /// returns the number of residents changed, or that would change on a dry run
+    run: MigrationFn,
+}
+
+/// Registered migrations, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "move_alarm_history",
+        description: "move alarm history embedded in residents to the history collection",
+        run: move_alarm_history,
+    },
+    Migration {
+        version: 2,
+        name: "active_alarm_ids",
+        description: "assign ids to active alarms raised before alarms had ids",
+        run: active_alarm_ids,
+    },
+];
+
+const _: () = assert!(MIGRATIONS[MIGRATIONS.len() - 1].version == SCHEMA_VERSION);
+
+fn move_alarm_history(store: &MongoStore, dry_run: bool) -> BoxFuture<'_, Result<u64>> {
+    async move {
+        let (residents, _) = store.migrate_history(dry_run).await?;
+        Ok(residents)
+    }
+    .boxed()
+}
+
+fn active_alarm_ids(store: &MongoStore, dry_run: bool) -> BoxFuture<'_, Result<u64>> {
+    async move {
+        let collection = store.collection().clone_with_type::<bson::Document>();
+        let mut cursor = collection
+            .find(doc! { "active_alarms": { "$elemMatch": { "_id": { "$exists": false } } } })
+            .projection(doc! { "active_alarms": 1 })
+            .await?;
+        let mut residents = 0;
+        while let Some(resident) = cursor.try_next().await? {
+            let resident_id = resident.get_object_id("_id")?;
+            let active_alarms = resident.get_array("active_alarms")?;
+            let with_ids = active_alarms
+                .iter()
+                .map(|alarm| match alarm {
+                    Bson::Document(alarm) if !alarm.contains_key("_id") => {
+                        let mut alarm = alarm.clone();
+                        alarm.insert("_id", ObjectId::new());
+                        Bson::Document(alarm)
+                    }
+                    alarm => alarm.clone(),
+                })
+                .collect::<Vec<_>>();
+            if !dry_run {
+                // only replaces the array if no alarm was raised or cleared in the meantime
+                let result = collection
+                    .update_one(
+                        doc! { "_id": resident_id, "active_alarms": active_alarms.clone() },
+                        doc! { "$set": { "active_alarms": with_ids } },
+                    )
+                    .await?;
+                if result.modified_count == 0 {
+                    anyhow::bail!(
+                        "active alarms of resident id: {} changed during the migration, run it again",
+                        resident_id
+                    );
+                }
+            }
+            residents += 1;
+        }
+        Ok(residents)
+    }
+    .boxed()
+}
+
+/// Applies the pending migrations in order, returns how many were applied.
+/// A dry run only reports what each pending migration would change.
+#[tracing::instrument(name = "migrate", skip(store), level = Level::TRACE)]
+pub async fn run(store: &MongoStore, dry_run: bool) -> Result<usize> {
+    let records = store
+        .database()
+        .collection::<bson::Document>(MIGRATIONS_COLLECTION);
+    let applied = records
+        .distinct("_id", doc! {})
+        .await?
+        .into_iter()
+        .filter_map(|version| version.as_i64())
+        .collect::<Vec<_>>();
+    let mut count = 0;
+    for migration in pending(&applied) {
+        let affected = (migration.run)(store, dry_run).await?;
+        if dry_run {
+            info!(
+                "Would apply migration {} {}: {} residents",
+                migration.version, migration.name, affected
+            );
+            continue;
+        }
+        store
+            .collection()
+            .update_many(
+                doc! { "schema_version": { "$not": { "$gte": migration.version as i64 } } },
+                doc! { "$set": { "schema_version": migration.version as i64 } },
+            )
+            .await?;
+        records
+            .insert_one(doc! {
+                "_id": migration.version as i64,
+                "name": migration.name,
+                "applied_at": bson::DateTime::now(),
+                "affected": affected as i64,
+            })
+            .await?;
+        info!(
+            "Applied migration {} {}: {} residents",
+            migration.version, migration.name, affected
+        );
+        count += 1;
+    }
+    if count == 0 && !dry_run {
+        info!("Schema is up to date at version {}", SCHEMA_VERSION);
+    }
+    Ok(count)
+}
+
+/// Migrations whose version is not in `applied`, in order
+fn pending(applied: &[i64]) -> impl Iterator<Item = &'static Migration> + '_ {
+    MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&(migration.version as i64)))
+}
+
+/// One row per registered migration with its applied time
+pub async fn status(store: &MongoStore) -> Result<Vec<bson::Document>> {
+    let records: Vec<bson::Document> = store
+        .database()
+        .collection::<bson::Document>(MIGRATIONS_COLLECTION)
+        .find(doc! {})
+        .await?
+        .try_collect()
+        .await?;
+    Ok(status_rows(&records))
+}
+
+/// Status rows of [`status`] from the `schema_migrations` records
+fn status_rows(records: &[bson::Document]) -> Vec<bson::Document> {
+    MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let record = records
+                .iter()
+                .find(|r| r.get("_id").and_then(Bson::as_i64) == Some(migration.version as i64));
+            doc! {
+                "version": migration.version as i64,
+                "name": migration.name,
+                "description": migration.description,
+                "status": if record.is_some() { "applied" } else { "pending" },
+                "applied_at": record.and_then(|r| r.get_datetime("applied_at").ok().copied()),
+                "affected": record.and_then(|r| r.get_i64("affected").ok()),
+            }
+        })
+        .collect()
+}
+
+/// Number of residents per `schema_version`, 0 for documents without one
+pub async fn schema_versions(store: &MongoStore) -> Result<Vec<bson::Document>> {
+    Ok(store
+        .collection()
+        .aggregate(vec![
+            doc! { "$group": {
+                "_id": { "$ifNull": [ "$schema_version", 0 ] },
+                "residents": { "$sum": 1 },
+            } },
+            doc! { "$project": { "_id": 0, "schema_version": "$_id", "residents": 1 } },
+            doc! { "$sort": { "schema_version": 1 } },
+        ])
+        .await?
+        .try_collect()
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_numbered_in_order_up_to_the_schema_version() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1, "{}", migration.name);
+        }
+        assert_eq!(MIGRATIONS.last().map(|m| m.version), Some(SCHEMA_VERSION));
+        let mut names = MIGRATIONS.iter().map(|m| m.name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn applied_migrations_are_not_pending() {
+        let versions = |applied: &[i64]| pending(applied).map(|m| m.version).collect::<Vec<_>>();
+        assert_eq!(versions(&[]), [1, 2]);
+        assert_eq!(versions(&[1]), [2]);
+        // a second run after everything was applied has nothing to do
+        assert!(versions(&[1, 2]).is_empty());
+    }
+
+    #[test]
+    fn status_rows_show_applied_and_pending_migrations() {
+        let applied_at = bson::DateTime::from_millis(1_700_000_000_000);
+        let records = [doc! {
+            "_id": 1_i64,
+            "name": "move_alarm_history",
+            "applied_at": applied_at,
+            "affected": 12_i64,
+        }];
+        let rows = status_rows(&records);
+        assert_eq!(rows.len(), MIGRATIONS.len());
+        assert_eq!(rows[0].get_str("status").unwrap(), "applied");
+        assert_eq!(rows[0].get_datetime("applied_at").unwrap(), &applied_at);
+        assert_eq!(rows[0].get_i64("affected").unwrap(), 12);
+        assert_eq!(rows[1].get_i64("version").unwrap(), 2);
+        assert_eq!(rows[1].get_str("status").unwrap(), "pending");
+        assert_eq!(rows[1].get("applied_at"), Some(&Bson::Null));
+    }
+}
@@ -0,0 +1,530 @@
+use anyhow::Result;
+use clap::Parser;
+use futures::stream::BoxStream;
+use mongodb::bson::{self, doc, oid::ObjectId};
+use regex::{Regex, RegexBuilder};
+use tracing::warn;
+
+use crate::{
+    model::{ActiveAlarm, Alarm, AlarmRef, Resident},
+    utils,
+};
+
+pub mod filter;
+pub mod memory;
+pub mod migrations;
+pub mod mongo;
+pub mod notify;
+pub mod paging;
+pub mod report;
+pub mod search;
+pub mod watch;
+
+pub use filter::FilterExpr;
+pub use memory::MemoryStore;
+pub use mongo::MongoStore;
+pub use notify::NotifyingStore;
+pub use paging::{Cursor, SortSpec};
+pub use report::{GroupBy, ReportParams};
+pub use search::TextSearch;
+
+#[derive(Parser, Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct QueryParams {
+    #[clap(
+        default_value_t = 10,
+        help = "Most recent history alarms per resident in the date window, 0 for all of them"
+    )]
+    pub alarms_limit: i32,
+    #[clap(short, long, help = "From Date (YYYY-MM-DD)")]
+    pub from_date: Option<String>,
+    #[clap(short, long, help = "To Date (YYYY-MM-DD)")]
+    pub to_date: Option<String>,
+    #[clap(short, long, help = "Optional Regexp pattern to match resident names")]
+    pub name: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Optional Regexp pattern to match resident locations"
+    )]
+    pub location: Option<String>,
+    #[clap(long, help = "Only residents at least this many years old")]
+    pub min_age: Option<u32>,
+    #[clap(long, help = "Only residents at most this many years old")]
+    pub max_age: Option<u32>,
+    #[clap(long, help = "Only residents born on or after this date (YYYY-MM-DD)")]
+    pub birth_from: Option<String>,
+    #[clap(long, help = "Only residents born on or before this date (YYYY-MM-DD)")]
+    pub birth_to: Option<String>,
+    #[clap(
+        long,
+        help = "Only residents admitted on or after this date (YYYY-MM-DD)"
+    )]
+    pub resident_since_from: Option<String>,
+    #[clap(
+        long,
+        help = "Only residents admitted on or before this date (YYYY-MM-DD)"
+    )]
+    pub resident_since_to: Option<String>,
+    #[clap(
+        long,
+        help = "Only residents with at least this many alarms in the date window"
+    )]
+    pub min_alarms: Option<u32>,
+    #[clap(
+        long,
+        help = "Only residents with at most this many alarms in the date window"
+    )]
+    pub max_alarms: Option<u32>,
+    #[clap(
+        long,
+        help = "Filter expression on the result columns, e.g. 'name~\"^Ann\" and active_count>0'"
+    )]
+    pub filter: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Only residents with alarm messages matching these words, e.g. 'fall \"bathroom door\" -test'"
+    )]
+    pub message: Option<String>,
+    #[clap(
+        long,
+        help = "Sort column with optional direction, e.g. max_duration:desc [default: location]"
+    )]
+    pub sort: Option<String>,
+    #[clap(long, help = "Maximum number of residents to return")]
+    pub limit: Option<u64>,
+    #[clap(long, default_value_t = 0, help = "Number of residents to skip")]
+    pub skip: u64,
+    #[clap(
+        long,
+        help = "Continue after the last resident of a previous page, the next cursor is logged with every full page"
+    )]
+    pub cursor: Option<String>,
+}
+
+impl Default for QueryParams {
+    fn default() -> Self {
+        QueryParams {
+            alarms_limit: 10,
+            from_date: None,
+            to_date: None,
+            name: None,
+            location: None,
+            min_age: None,
+            max_age: None,
+            birth_from: None,
+            birth_to: None,
+            resident_since_from: None,
+            resident_since_to: None,
+            min_alarms: None,
+            max_alarms: None,
+            filter: None,
+            message: None,
+            sort: None,
+            limit: None,
+            skip: 0,
+            cursor: None,
+        }
+    }
+}
+
+impl QueryParams {
+    /// Alarm time window from the `from_date` / `to_date` bounds (inclusive),
+    /// date-only bounds cover the whole day in the facility timezone
+    pub fn date_range(&self) -> Result<(Option<bson::DateTime>, Option<bson::DateTime>)> {
+        date_range(&self.from_date, &self.to_date)
+    }
+
+    /// `--filter` and the shortcut options, all of which must match
+    pub fn filter_expr(&self) -> Result<Option<FilterExpr>> {
+        let mut filters = Vec::new();
+        if let Some(name) = &self.name {
+            filters.push(FilterExpr::regex("name", name)?);
+        }
+        if let Some(location) = &self.location {
+            filters.push(FilterExpr::regex("location", location)?);
+        }
+        // ages are birth date bounds, so residents are selected before the alarm lookup
+        let today = utils::today();
+        let years_ago = |years: u32| {
+            today
+                .checked_sub_months(chrono::Months::new(years.saturating_mul(12)))
+                .ok_or_else(|| StoreError::Invalid(format!("age {years} is out of range")))
+        };
+        // an unparsable bound is bad input like an age out of range
+        let bound = |option: &str, parsed: Result<bson::DateTime>| {
+            parsed.map_err(|e| StoreError::Invalid(format!("{option}: {e}")))
+        };
+        if let Some(min_age) = self.min_age {
+            let born_by = years_ago(min_age)?;
+            filters.push(FilterExpr::at_most(
+                "birth",
+                bound("min_age", utils::parse_datetime_end(&born_by.to_string()))?,
+            ));
+        }
+        if let Some(max_age) = self.max_age {
+            let born_after = years_ago(max_age.saturating_add(1))?;
+            let born_from = born_after.succ_opt().unwrap_or(born_after);
+            filters.push(FilterExpr::at_least(
+                "birth",
+                bound("max_age", utils::parse_datetime(&born_from.to_string()))?,
+            ));
+        }
+        let dates = [
+            ("birth", &self.birth_from, &self.birth_to),
+            (
+                "resident_since",
+                &self.resident_since_from,
+                &self.resident_since_to,
+            ),
+        ];
+        for (column, from, to) in dates {
+            if let Some(from) = from {
+                let from = bound(&format!("{column}_from"), utils::parse_datetime(from))?;
+                filters.push(FilterExpr::at_least(column, from));
+            }
+            if let Some(to) = to {
+                let to = bound(&format!("{column}_to"), utils::parse_datetime_end(to))?;
+                filters.push(FilterExpr::at_most(column, to));
+            }
+        }
+        if let Some(min) = self.min_alarms {
+            filters.push(FilterExpr::at_least("alarms_count", i64::from(min)));
+        }
+        if let Some(max) = self.max_alarms {
+            filters.push(FilterExpr::at_most("alarms_count", i64::from(max)));
+        }
+        if let Some(filter) = &self.filter {
+            filters.push(FilterExpr::parse(filter)?);
+        }
+        let filter = FilterExpr::all(filters);
+        // alarms_count only counts up to alarms_limit alarms
+        let limit = self.alarms_limit;
+        if limit > 0
+            && let Some(filter) = &filter
+            && filter.exceeds_cap("alarms_count", limit as f64)
+        {
+            anyhow::bail!(StoreError::Invalid(format!(
+                "alarm counts are capped at ALARMS_LIMIT {limit}: raise it above the alarm count bounds or pass 0 to count every alarm"
+            )));
+        }
+        Ok(filter)
+    }
+
+    pub fn text_search(&self) -> Result<Option<TextSearch>> {
+        self.message.as_deref().map(TextSearch::parse).transpose()
+    }
+
+    /// `--sort`, by default most relevant first with a message search, else by location
+    pub fn sort_spec(&self) -> Result<SortSpec> {
+        match (&self.sort, &self.message) {
+            (Some(sort), _) => SortSpec::parse(sort),
+            (None, Some(_)) => Ok(SortSpec {
+                column: "score".to_string(),
+                descending: true,
+            }),
+            (None, None) => Ok(SortSpec::default()),
+        }
+    }
+
+    pub fn page_cursor(&self) -> Result<Option<Cursor>> {
+        match &self.cursor {
+            Some(token) => Ok(Some(Cursor::decode(token, &self.sort_spec()?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Cursor of the page after `results`, None if `results` was the last page
+    pub fn next_cursor(&self, results: &[bson::Document]) -> Result<Option<String>> {
+        match (self.limit, results.last()) {
+            (Some(limit), Some(last)) if limit > 0 && results.len() as u64 >= limit => {
+                Ok(Cursor::after(&self.sort_spec()?, last).map(|c| c.encode()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Sorts and pages complete query results, for backends that cannot do it in the query
+    pub(crate) fn paginate(&self, mut rows: Vec<bson::Document>) -> Result<Vec<bson::Document>> {
+        let sort = self.sort_spec()?;
+        rows.sort_by(|a, b| sort.compare(a, b));
+        if let Some(cursor) = self.page_cursor()? {
+            rows.retain(|row| cursor.is_before(row));
+        }
+        let rows = rows.into_iter().skip(self.skip as usize);
+        Ok(match self.limit {
+            Some(limit) if limit > 0 => rows.take(limit as usize).collect(),
+            _ => rows.collect(),
+        })
+    }
+}
+
+/// Inclusive time window, a date-only `to_date` covers the whole day
+pub(crate) fn date_range(
+    from_date: &Option<String>,
+    to_date: &Option<String>,
+) -> Result<(Option<bson::DateTime>, Option<bson::DateTime>)> {
+    let from = match from_date {
+        Some(from_date) => Some(utils::parse_datetime(from_date)?),
+        None => None,
+    };
+    let to = match to_date {
+        Some(to_date) => Some(utils::parse_datetime_end(to_date)?),
+        None => None,
+    };
+    Ok((from, to))
+}
+
+/// Alarm date window and resident patterns of the history reports
+#[derive(Parser, Debug, Clone, Default)]
+pub struct AlarmWindow {
+    #[clap(short, long, help = "From Date (YYYY-MM-DD)")]
+    pub from_date: Option<String>,
+    #[clap(short, long, help = "To Date (YYYY-MM-DD)")]
+    pub to_date: Option<String>,
+    #[clap(short, long, help = "Optional Regexp pattern to match resident names")]
+    pub name: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Optional Regexp pattern to match resident locations"
+    )]
+    pub location: Option<String>,
+}
+
+impl AlarmWindow {
+    /// Alarm time window, date-only bounds cover the whole day
+    pub fn date_range(&self) -> Result<(Option<bson::DateTime>, Option<bson::DateTime>)> {
+        date_range(&self.from_date, &self.to_date)
+    }
+
+    pub fn resident_filter(&self) -> Result<ResidentFilter> {
+        ResidentFilter::new(&self.name, &self.location)
+    }
+}
+
+/// Case-insensitive name and location patterns, a resident must match both
+#[derive(Debug, Clone, Default)]
+pub struct ResidentFilter {
+    name: Option<Regex>,
+    location: Option<Regex>,
+}
+
+impl ResidentFilter {
+    pub fn new(name: &Option<String>, location: &Option<String>) -> Result<Self> {
+        let pattern = |p: &Option<String>| -> Result<Option<Regex>> {
+            Ok(match p {
+                Some(p) => Some(
+                    RegexBuilder::new(p)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(|e| StoreError::Invalid(e.to_string()))?,
+                ),
+                None => None,
+            })
+        };
+        Ok(ResidentFilter {
+            name: pattern(name)?,
+            location: pattern(location)?,
+        })
+    }
+
+    /// Whether every resident matches
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.location.is_none()
+    }
+
+    pub fn matches(&self, resident: &Resident) -> bool {
+        self.is_match(&resident.name, &resident.location)
+    }
+
+    pub fn is_match(&self, name: &str, location: &str) -> bool {
+        self.name.as_ref().is_none_or(|re| re.is_match(name))
+            && self
+                .location
+                .as_ref()
+                .is_none_or(|re| re.is_match(location))
+    }
+
+    /// The same selection as a MongoDB query
+    pub fn to_document(&self) -> bson::Document {
+        let mut query = doc! {};
+        if let Some(re) = &self.name {
+            query.insert("name", doc! { "$regex": re.as_str(), "$options": "i" });
+        }
+        if let Some(re) = &self.location {
+            query.insert("location", doc! { "$regex": re.as_str(), "$options": "i" });
+        }
+        query
+    }
+}
+
+/// Store failures callers may want to tell apart, e.g. to pick an HTTP status.
+/// Carried inside [`anyhow::Error`], find them with `downcast_ref`.
+#[derive(Debug, Clone)]
+pub enum StoreError {
+    NotFound(String),
+    Duplicate(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::NotFound(msg) | StoreError::Duplicate(msg) | StoreError::Invalid(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Result of inserting or upserting a resident
+#[derive(Debug, Clone)]
+pub enum WriteOutcome {
+    Inserted(bson::Bson),
+    Updated { matched: u64, modified: u64 },
+}
+
+/// Result of [`ResidentStore::write_batch`]
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    pub inserted: u64,
+    pub updated: u64,
+    /// position in the batch and reason of every resident that was not written
+    pub failed: Vec<(usize, String)>,
+}
+
+/// Storage operations for residents and their alarms
+#[async_trait::async_trait]
+pub trait ResidentStore: Send + Sync {
+    /// Inserts a new resident, updating location and resident_since if one
+    /// with the same name and birth date already exists
+    async fn insert(&self, resident: &Resident) -> Result<WriteOutcome>;
+
+    /// Updates location and resident_since, inserting the resident if missing
+    async fn upsert(&self, resident: &Resident) -> Result<WriteOutcome>;
+
+    /// Writes many residents with the semantics of [`insert`](Self::insert), or of
+    /// [`upsert`](Self::upsert) if `upsert` is set. `ordered` stops at the first failed
+    /// resident, otherwise the remaining ones are still written.
+    async fn write_batch(
+        &self,
+        residents: &[Resident],
+        upsert: bool,
+        ordered: bool,
+    ) -> Result<BatchOutcome>;
+
+    /// Deletes a resident, returns false if none matched
+    async fn delete(&self, name: &str, birth: bson::DateTime) -> Result<bool>;
+
+    async fn find(&self, name: &str, birth: bson::DateTime) -> Result<Option<Resident>>;
+
+    /// Raises a new active alarm for the resident
+    async fn new_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        message: &str,
+        start_time: Option<bson::DateTime>,
+    ) -> Result<ActiveAlarm>;
+
+    /// Marks an active alarm as acknowledged by `by`, returns None if no such
+    /// unacknowledged active alarm exists
+    async fn ack_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        by: &str,
+    ) -> Result<Option<ActiveAlarm>>;
+
+    /// Moves the active alarm into history keeping its id,
+    /// returns None if no such active alarm exists
+    async fn clear_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        duration: Option<u64>,
+    ) -> Result<Option<Alarm>>;
+
+    /// Clears all active alarms of a resident, returns the alarms cleared
+    async fn force_close(&self, name: &str, birth: bson::DateTime) -> Result<Vec<Alarm>> {
+        let mut cleared = Vec::new();
+        if let Some(resident) = self.find(name, birth).await? {
+            for alarm in resident.active_alarms {
+                if let Some(alarm) = self
+                    .clear_alarm(name, birth, AlarmRef::Id(alarm.id), None)
+                    .await?
+                {
+                    cleared.push(alarm);
+                } else {
+                    warn!("Alarm {} was already cleared.", alarm.id);
+                }
+            }
+        }
+        Ok(cleared)
+    }
+
+    /// Residents matching `filter` with their alarm history embedded in `alarms`,
+    /// oldest alarm first
+    async fn export(&self, filter: &ResidentFilter) -> Result<BoxStream<'_, Result<Resident>>> {
+        self.with_history(filter, None, None).await
+    }
+
+    /// Like [`export`](Self::export) with only the history alarms raised between
+    /// `from_date` and `to_date` (inclusive), the store selects them. Active alarms are
+    /// all kept.
+    async fn with_history(
+        &self,
+        filter: &ResidentFilter,
+        from_date: Option<bson::DateTime>,
+        to_date: Option<bson::DateTime>,
+    ) -> Result<BoxStream<'_, Result<Resident>>>;
+
+    /// Writes an exported resident and its embedded history. A resident with the same
+    /// name and birth date is replaced, history alarms already stored are kept.
+    /// Returns true if the resident was not stored before.
+    async fn restore(&self, resident: &Resident) -> Result<bool>;
+
+    /// Deletes the residents matching `filter` with their alarm history,
+    /// returns the number of residents deleted
+    async fn delete_matching(&self, filter: &ResidentFilter) -> Result<u64>;
+
+    /// Residents with at least one active alarm
+    async fn with_active_alarms(&self) -> Result<Vec<Resident>>;
+
+    /// Records escalation `level` on an active alarm at `level - 1` without outstanding
+    /// events, so every level is recorded once even with concurrent daemons. Returns false
+    /// if the alarm is no longer active or another caller already recorded the level.
+    async fn escalate_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<bool>;
+
+    /// Marks the events of the escalation levels up to `level` of an active alarm as emitted
+    async fn escalation_sent(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<()>;
+
+    /// Per resident alarm summary: counts, durations, first/last alarm and active alarms
+    async fn query(&self, params: &QueryParams) -> Result<Vec<bson::Document>>;
+
+    /// Alarm history in the date window grouped by `params.group_by`, one row per group
+    /// in key order with count, total, average, median, p90 and max duration and the
+    /// number of distinct residents
+    async fn report(&self, params: &ReportParams) -> Result<Vec<bson::Document>>;
+
+    /// Backend specific storage statistics
+    async fn stats(&self) -> Result<Vec<bson::Document>>;
+}
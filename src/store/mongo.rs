@@ -0,0 +1,1263 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use futures::{
+    FutureExt as _, StreamExt as _, TryStreamExt as _, future::BoxFuture, stream::BoxStream,
+};
+use mongodb::{
+    Client, ClientSession, Collection, Database, IndexModel,
+    bson::{self, doc, oid::ObjectId},
+    error::{
+        TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT, WriteError, WriteFailure,
+    },
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, IndexOptions, ReturnDocument, ServerApi,
+        ServerApiVersion,
+    },
+};
+use tracing::{Level, debug, error, info, warn};
+
+use super::{
+    BatchOutcome, GroupBy, QueryParams, ReportParams, ResidentFilter, ResidentStore, StoreError,
+    TextSearch, WriteOutcome, report, search::TEXT_INDEX,
+};
+use crate::{
+    config::Profile,
+    model::{ActiveAlarm, Alarm, AlarmRef, HistoryAlarm, Resident, SCHEMA_VERSION},
+    utils,
+};
+
+/// Residents whose history [`MongoStore::with_history`] fetches with one query
+const HISTORY_PAGE: usize = 100;
+
+/// [`ResidentStore`] backed by a MongoDB residents collection with
+/// cleared alarms kept in a separate history collection
+#[derive(Clone)]
+pub struct MongoStore {
+    client: Client,
+    collection: Collection<Resident>,
+    history: Collection<HistoryAlarm>,
+    /// false on a standalone server, which only replica sets and sharded clusters support
+    transactions: bool,
+}
+
+impl MongoStore {
+    /// Connects to the cluster, checks it with a ping and ensures the unique (name, birth) index,
+    /// the alarm history indexes and the text indexes on alarm messages
+    pub async fn connect(profile: &Profile) -> Result<Self> {
+        let Some(uri) = &profile.uri else {
+            anyhow::bail!("no MongoDB URI: set MONGODB_URI, pass --uri or add uri to the profile");
+        };
+        let mut client_options = ClientOptions::parse(uri).await?;
+        profile.apply(&mut client_options)?;
+
+        // Set the server_api field of the client_options object to set the version of the Stable API on the client
+        let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
+        client_options.server_api = Some(server_api);
+
+        // Get a handle to the cluster
+        let client = Client::with_options(client_options)?;
+
+        // Ping the server to see if you can connect to the cluster
+        let database = client.database(profile.database());
+        database.run_command(doc! {"ping": 1}).await?;
+        let hello = database.run_command(doc! {"hello": 1}).await?;
+        let transactions = hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid");
+        if !transactions {
+            if !profile.allow_standalone {
+                anyhow::bail!(
+                    "standalone MongoDB server without transactions: clearing alarms would not be atomic, use a replica set or pass --allow-standalone"
+                );
+            }
+            warn!(
+                "Standalone MongoDB server without transactions: clearing alarms, deleting and restoring residents write residents and history one after the other, an interrupted write can leave them out of step"
+            );
+        }
+
+        let collection: Collection<Resident> = database.collection(profile.collection());
+        let unique_index = IndexModel::builder()
+            .keys(doc! { "name": 1, "birth": 1 })
+            .options(Some(IndexOptions::builder().unique(true).build()))
+            .build();
+        collection.create_index(unique_index).await?;
+        collection
+            .create_index(text_index("active_alarms.message"))
+            .await?;
+
+        let history: Collection<HistoryAlarm> = database.collection(&profile.history_collection());
+        history
+            .create_indexes([
+                IndexModel::builder()
+                    .keys(doc! { "resident_id": 1, "time": -1 })
+                    .build(),
+                IndexModel::builder().keys(doc! { "time": 1 }).build(),
+                text_index("message"),
+            ])
+            .await?;
+        Ok(MongoStore {
+            client,
+            collection,
+            history,
+            transactions,
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn collection(&self) -> &Collection<Resident> {
+        &self.collection
+    }
+
+    pub fn history(&self) -> &Collection<HistoryAlarm> {
+        &self.history
+    }
+
+    /// Database holding the residents collection
+    pub fn database(&self) -> Database {
+        self.client.database(&self.collection.namespace().db)
+    }
+
+    /// Runs `op` inside a transaction, retrying on transient transaction errors.
+    /// `op` returning None aborts the transaction. A standalone server has no transactions,
+    /// with `allow_standalone` `op` runs once in a plain session there and its writes are
+    /// not atomic.
+    async fn in_transaction<T, F>(&self, mut op: F) -> Result<Option<T>>
+    where
+        F: for<'s> FnMut(&'s mut ClientSession) -> BoxFuture<'s, Result<Option<T>>>,
+    {
+        let mut session = self.client.start_session().await?;
+        if !self.transactions {
+            return op(&mut session).await;
+        }
+        'retry: loop {
+            session.start_transaction().await?;
+            let value = match op(&mut session).await {
+                Ok(Some(value)) => value,
+                Ok(None) => {
+                    session.abort_transaction().await?;
+                    return Ok(None);
+                }
+                Err(e) => {
+                    session.abort_transaction().await.ok();
+                    if has_label(&e, TRANSIENT_TRANSACTION_ERROR) {
+                        debug!("Retrying transaction: {}", e);
+                        continue 'retry;
+                    }
+                    return Err(e);
+                }
+            };
+            loop {
+                match session.commit_transaction().await {
+                    Ok(()) => return Ok(Some(value)),
+                    Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => continue,
+                    Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) => continue 'retry,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+
+    /// Alarms matching `search` by resident id with the summed text score: history alarms
+    /// in `time_range`, most recent first, then active alarms
+    async fn text_matches(
+        &self,
+        search: &TextSearch,
+        time_range: &bson::Document,
+    ) -> Result<HashMap<ObjectId, (Vec<ObjectId>, f64)>> {
+        let mut matches = HashMap::<ObjectId, (Vec<ObjectId>, f64)>::new();
+        let mut history_match = doc! { "$text": { "$search": search.as_str() } };
+        if !time_range.is_empty() {
+            history_match.insert("time", time_range.clone());
+        }
+        let mut cursor = self
+            .history
+            .aggregate([
+                doc! { "$match": history_match },
+                doc! { "$addFields": { "score": { "$meta": "textScore" } } },
+                doc! { "$sort": { "time": -1 } },
+                doc! { "$group": {
+                    "_id": "$resident_id",
+                    "ids": { "$push": "$_id" },
+                    "score": { "$sum": "$score" },
+                } },
+            ])
+            .await?;
+        while let Some(group) = cursor.try_next().await? {
+            let ids = group
+                .get_array("ids")?
+                .iter()
+                .filter_map(bson::Bson::as_object_id)
+                .collect();
+            matches.insert(group.get_object_id("_id")?, (ids, group.get_f64("score")?));
+        }
+
+        // the index only tells which residents match, not which of their active alarms
+        let mut cursor = self
+            .collection
+            .clone_with_type::<bson::Document>()
+            .find(doc! { "$text": { "$search": search.as_str() } })
+            .projection(doc! {
+                "active_alarms._id": 1,
+                "active_alarms.message": 1,
+                "score": { "$meta": "textScore" },
+            })
+            .await?;
+        while let Some(resident) = cursor.try_next().await? {
+            let active = resident
+                .get_array("active_alarms")
+                .map(|alarms| alarms.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(bson::Bson::as_document)
+                .filter(|a| {
+                    a.get_str("message")
+                        .is_ok_and(|m| search.score(m).is_some())
+                })
+                .filter_map(|a| a.get_object_id("_id").ok())
+                .collect::<Vec<_>>();
+            if active.is_empty() {
+                continue;
+            }
+            let (ids, score) = matches.entry(resident.get_object_id("_id")?).or_default();
+            ids.extend(active);
+            *score += resident.get_f64("score").unwrap_or_default();
+        }
+        Ok(matches)
+    }
+
+    /// Moves alarm history embedded in resident documents into the history collection,
+    /// one transaction per resident so it can be re-run after an interruption. Alarms
+    /// already in the history collection are skipped, which also covers an interrupted
+    /// run on a standalone server without transactions.
+    /// Returns the number of residents and alarms (to be) moved.
+    #[tracing::instrument(name = "migrate_history", skip(self), level = Level::TRACE)]
+    pub async fn migrate_history(&self, dry_run: bool) -> Result<(u64, u64)> {
+        let mut cursor = self
+            .collection
+            .clone_with_type::<bson::Document>()
+            .find(doc! { "alarms.0": { "$exists": true } })
+            .projection(doc! { "_id": 1, "alarms": 1 })
+            .await?;
+        let (mut residents, mut alarms) = (0, 0);
+        while let Some(resident) = cursor.try_next().await? {
+            let resident_id = resident.get_object_id("_id")?;
+            let embedded = resident
+                .get_array("alarms")?
+                .iter()
+                .filter_map(|alarm| alarm.as_document())
+                .map(|alarm| bson::from_document::<Alarm>(alarm.clone()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let count = embedded.len() as u64;
+            if !dry_run {
+                let collection = self.collection.clone();
+                let history = self.history.clone();
+                let records = embedded
+                    .into_iter()
+                    .map(|alarm| HistoryAlarm::new(resident_id, alarm))
+                    .collect::<Vec<_>>();
+                self.in_transaction(|session| {
+                    let (collection, history, records) =
+                        (collection.clone(), history.clone(), records.clone());
+                    async move {
+                        let ids = records.iter().map(|r| r.id).collect::<Vec<_>>();
+                        let stored = history
+                            .distinct("_id", doc! { "_id": { "$in": ids } })
+                            .session(&mut *session)
+                            .await?;
+                        let records = records
+                            .into_iter()
+                            .filter(|r| !stored.contains(&bson::Bson::ObjectId(r.id)))
+                            .collect::<Vec<_>>();
+                        if !records.is_empty() {
+                            history.insert_many(records).session(&mut *session).await?;
+                        }
+                        collection
+                            .update_one(
+                                doc! { "_id": resident_id },
+                                doc! { "$unset": { "alarms": "" } },
+                            )
+                            .session(&mut *session)
+                            .await?;
+                        Ok(Some(()))
+                    }
+                    .boxed()
+                })
+                .await?;
+            }
+            info!(
+                "{} {} alarms of resident id: {}",
+                if dry_run { "Would move" } else { "Moved" },
+                count,
+                resident_id
+            );
+            residents += 1;
+            alarms += count;
+        }
+        Ok((residents, alarms))
+    }
+}
+
+#[async_trait::async_trait]
+impl ResidentStore for MongoStore {
+    #[tracing::instrument(name = "insert_or_update", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
+    async fn insert(&self, resident: &Resident) -> Result<WriteOutcome> {
+        match self.collection.insert_one(resident).await {
+            Ok(insert_result) => {
+                info!(
+                    "New resident inserted with id: {}",
+                    insert_result.inserted_id
+                );
+                Ok(WriteOutcome::Inserted(insert_result.inserted_id))
+            }
+            Err(e) => match e.kind.as_ref() {
+                mongodb::error::ErrorKind::Write(WriteFailure::WriteError(WriteError {
+                    code: 11000,
+                    ..
+                })) => {
+                    warn!(
+                        "Duplicate key error: A resident with the same name and birth date already exists. Updating..."
+                    );
+                    let filter = resident.unique_index();
+                    let update = resident.update_data();
+                    match self.collection.update_one(filter, update).await {
+                        Ok(update_result) => {
+                            info!(
+                                "Resident updated Matched: {} Updated: {}",
+                                update_result.matched_count, update_result.modified_count
+                            );
+                            Ok(WriteOutcome::Updated {
+                                matched: update_result.matched_count,
+                                modified: update_result.modified_count,
+                            })
+                        }
+                        Err(e) => anyhow::bail!(format!("Failed to update resident: {}", e)),
+                    }
+                }
+                _ => anyhow::bail!(format!("Failed to insert new resident: {}", e)),
+            },
+        }
+    }
+
+    #[tracing::instrument(name = "upsert", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
+    async fn upsert(&self, resident: &Resident) -> Result<WriteOutcome> {
+        let filter = resident.unique_index();
+        let mut update = resident.update_data();
+        update.insert(
+            "$setOnInsert",
+            doc! { "schema_version": resident.schema_version },
+        );
+        let options = mongodb::options::UpdateOptions::builder()
+            .upsert(true)
+            .build();
+        match self
+            .collection
+            .update_one(filter, update)
+            .with_options(options)
+            .await
+        {
+            Ok(update_result) => {
+                if let Some(upserted_id) = update_result.upserted_id {
+                    info!("New resident inserted with id: {}", upserted_id);
+                    Ok(WriteOutcome::Inserted(upserted_id))
+                } else {
+                    info!(
+                        "Resident updated Matched: {} Updated: {}",
+                        update_result.matched_count, update_result.modified_count
+                    );
+                    Ok(WriteOutcome::Updated {
+                        matched: update_result.matched_count,
+                        modified: update_result.modified_count,
+                    })
+                }
+            }
+            // two concurrent upserts of a new resident, the unique index rejects one
+            Err(e) if is_duplicate_key(&e) => anyhow::bail!(StoreError::Duplicate(format!(
+                "Failed to upsert resident: {}",
+                e
+            ))),
+            Err(e) => anyhow::bail!(format!("Failed to upsert resident: {}", e)),
+        }
+    }
+
+    /// One `update` command per batch with the write concern of the profile, every statement
+    /// an upsert. Plain inserts put the rest of the resident document in `$setOnInsert`.
+    #[tracing::instrument(name = "write_batch", skip_all, fields(residents=residents.len()), level = Level::TRACE)]
+    async fn write_batch(
+        &self,
+        residents: &[Resident],
+        upsert: bool,
+        ordered: bool,
+    ) -> Result<BatchOutcome> {
+        let mut updates = Vec::with_capacity(residents.len());
+        for resident in residents {
+            let mut update = resident.update_data();
+            let on_insert = if upsert {
+                doc! { "schema_version": resident.schema_version }
+            } else {
+                let mut document = bson::to_document(resident)?;
+                for key in ["name", "birth", "location", "resident_since"] {
+                    document.remove(key);
+                }
+                document
+            };
+            update.insert("$setOnInsert", on_insert);
+            updates.push(doc! {
+                "q": resident.unique_index(),
+                "u": update,
+                "upsert": true,
+            });
+        }
+        let mut command = doc! {
+            "update": self.collection.name(),
+            "updates": updates,
+            "ordered": ordered,
+        };
+        // run_command does not apply the write concern of the profile
+        if let Some(write_concern) = self.collection.write_concern() {
+            command.insert("writeConcern", bson::to_bson(write_concern)?);
+        }
+        let reply = self.database().run_command(command).await?;
+        if let Ok(error) = reply.get_document("writeConcernError") {
+            anyhow::bail!("Write concern error: {}", error);
+        }
+        let count = |key: &str| match reply.get(key) {
+            Some(bson::Bson::Int32(n)) => *n as u64,
+            Some(bson::Bson::Int64(n)) => *n as u64,
+            _ => 0,
+        };
+        let inserted = reply.get_array("upserted").map_or(0, |a| a.len() as u64);
+        let mut failed = Vec::new();
+        for error in reply.get_array("writeErrors").into_iter().flatten() {
+            if let bson::Bson::Document(error) = error {
+                let index = match error.get("index") {
+                    Some(bson::Bson::Int32(n)) => *n as usize,
+                    Some(bson::Bson::Int64(n)) => *n as usize,
+                    _ => continue,
+                };
+                let message = error.get_str("errmsg").unwrap_or("write error");
+                failed.push((index, message.to_string()));
+            }
+        }
+        Ok(BatchOutcome {
+            inserted,
+            updated: count("n").saturating_sub(inserted),
+            failed,
+        })
+    }
+
+    // Delete a resident by name and birth date, together with its alarm history
+    #[tracing::instrument(name = "delete", skip_all, fields(name=%name, birth=%birth), level = Level::TRACE)]
+    async fn delete(&self, name: &str, birth: bson::DateTime) -> Result<bool> {
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+        };
+        let collection = self.collection.clone();
+        let history = self.history.clone();
+        match self
+            .in_transaction(|session| {
+                let (collection, history, filter) =
+                    (collection.clone(), history.clone(), filter.clone());
+                async move {
+                    let Some(resident) = collection
+                        .find_one_and_delete(filter)
+                        .session(&mut *session)
+                        .await?
+                    else {
+                        return Ok(None);
+                    };
+                    let history_result = history
+                        .delete_many(doc! { "resident_id": resident.id })
+                        .session(&mut *session)
+                        .await?;
+                    Ok(Some(history_result.deleted_count))
+                }
+                .boxed()
+            })
+            .await
+        {
+            Ok(Some(history_count)) => {
+                info!(
+                    "Resident deleted successfully with {} history alarms.",
+                    history_count
+                );
+                Ok(true)
+            }
+            Ok(None) => {
+                warn!("No resident found to delete.");
+                Ok(false)
+            }
+            Err(e) => anyhow::bail!(format!("Failed to delete resident: {}", e)),
+        }
+    }
+
+    async fn find(&self, name: &str, birth: bson::DateTime) -> Result<Option<Resident>> {
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+        };
+        Ok(self.collection.find_one(filter).await?)
+    }
+
+    #[tracing::instrument(name = "new_alarm", skip_all, fields(name=%name, birth=%birth), level = Level::TRACE)]
+    async fn new_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        message: &str,
+        start_time: Option<bson::DateTime>,
+    ) -> Result<ActiveAlarm> {
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+        };
+        let new_alarm = ActiveAlarm::new(message, start_time);
+        let update = doc! {
+            "$push": { "active_alarms": bson::to_bson(&new_alarm)? }
+        };
+        match self.collection.update_one(filter, update).await {
+            Ok(update_result) => {
+                if update_result.matched_count > 0 {
+                    info!(
+                        "New Alarm\t'{name}' '{birth}' '{}'",
+                        new_alarm.time.try_to_rfc3339_string()?,
+                    );
+                    Ok(new_alarm)
+                } else {
+                    anyhow::bail!(StoreError::NotFound(
+                        "No resident found to add alarm.".to_string()
+                    ));
+                }
+            }
+            Err(e) => {
+                anyhow::bail!(format!("Failed to add alarm: {}", e));
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "ack_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm), level = Level::TRACE)]
+    async fn ack_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        by: &str,
+    ) -> Result<Option<ActiveAlarm>> {
+        let mut elem_match = alarm.elem_match();
+        elem_match.insert("acknowledged_at", doc! { "$exists": false });
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+            "active_alarms": { "$elemMatch": elem_match },
+        };
+        let update = doc! { "$set": {
+            "active_alarms.$.acknowledged_at": bson::DateTime::now(),
+            "active_alarms.$.acknowledged_by": by,
+        } };
+        let options = FindOneAndUpdateOptions::builder()
+            .projection(doc! { "active_alarms": { "$elemMatch": alarm.elem_match() } })
+            .return_document(ReturnDocument::After)
+            .build();
+        match self
+            .collection
+            .clone_with_type::<bson::Document>()
+            .find_one_and_update(filter, update)
+            .with_options(options)
+            .await
+        {
+            Ok(Some(resident)) => {
+                let Some(active) = resident
+                    .get_array("active_alarms")?
+                    .first()
+                    .and_then(|alarm| alarm.as_document())
+                else {
+                    anyhow::bail!("Acknowledged alarm missing from the resident document");
+                };
+                let acked: ActiveAlarm = bson::from_document(active.clone())?;
+                info!(
+                    "Acknowledged alarm {} for resident id: {:?} by {}",
+                    acked.id,
+                    resident.get("_id"),
+                    by
+                );
+                Ok(Some(acked))
+            }
+            Ok(None) => {
+                warn!("No unacknowledged active alarm found.");
+                Ok(None)
+            }
+            Err(e) => anyhow::bail!(format!("Failed to acknowledge alarm: {}", e)),
+        }
+    }
+
+    #[tracing::instrument(name = "clear_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm), level = Level::TRACE)]
+    async fn clear_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        duration: Option<u64>,
+    ) -> Result<Option<Alarm>> {
+        let now = bson::DateTime::now();
+        // only matches while the alarm is still active, so concurrent clears move it at most once
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+            "active_alarms": { "$elemMatch": alarm.elem_match() },
+        };
+        // remove the first matching alarm from active
+        let update = vec![
+            doc! { "$set": {
+                "_cleared": { "$first": { "$filter": {
+                    "input": "$active_alarms", "as": "alarm", "cond": alarm.expr_eq("$$alarm"), "limit": 1
+                } } }
+            } },
+            doc! { "$set": {
+                "active_alarms": { "$filter": { "input": "$active_alarms", "as": "alarm", "cond": { "$ne": [ "$$alarm", "$_cleared" ] } } }
+            } },
+            doc! { "$unset": "_cleared" },
+        ];
+        let options = FindOneAndUpdateOptions::builder()
+            .projection(doc! { "active_alarms": { "$elemMatch": alarm.elem_match() } })
+            .return_document(ReturnDocument::Before)
+            .build();
+        let collection = self.collection.clone_with_type::<bson::Document>();
+        let history = self.history.clone();
+        // the removal from active and the history insert commit together
+        match self
+            .in_transaction(|session| {
+                let (collection, history, filter, update, options) = (
+                    collection.clone(),
+                    history.clone(),
+                    filter.clone(),
+                    update.clone(),
+                    options.clone(),
+                );
+                async move {
+                    let Some(resident) = collection
+                        .find_one_and_update(filter, update)
+                        .with_options(options)
+                        .session(&mut *session)
+                        .await?
+                    else {
+                        return Ok(None);
+                    };
+                    let resident_id = resident.get_object_id("_id")?;
+                    let Some(active) = resident
+                        .get_array("active_alarms")?
+                        .first()
+                        .and_then(|alarm| alarm.as_document())
+                    else {
+                        anyhow::bail!("Cleared alarm missing from the resident document");
+                    };
+                    let active: ActiveAlarm = bson::from_document(active.clone())?;
+                    let duration = duration.unwrap_or(
+                        now.checked_duration_since(active.time)
+                            .unwrap_or_default()
+                            .as_secs(),
+                    );
+                    let cleared = active.cleared(duration);
+                    history
+                        .insert_one(HistoryAlarm::new(resident_id, cleared.clone()))
+                        .session(&mut *session)
+                        .await?;
+                    Ok(Some((resident_id, cleared)))
+                }
+                .boxed()
+            })
+            .await
+        {
+            Ok(Some((resident_id, cleared))) => {
+                info!(
+                    "Cleared alarm {} for resident id: {}, message: {}, start_time: {}, duration_sec: {}",
+                    cleared.id,
+                    resident_id,
+                    cleared.message,
+                    cleared.time.try_to_rfc3339_string()?,
+                    cleared.duration_sec
+                );
+                Ok(Some(cleared))
+            }
+            Ok(None) => {
+                warn!("No active alarm found to clear.");
+                Ok(None)
+            }
+            Err(e) => {
+                anyhow::bail!(format!("Failed to clear alarm: {}", e));
+            }
+        }
+    }
+
+    /// Moves all active alarms to history in one transaction if the server has them, also covers
+    /// alarms stored without an id
+    #[tracing::instrument(name = "force_close", skip(self), level = Level::TRACE)]
+    async fn force_close(&self, name: &str, birth: bson::DateTime) -> Result<Vec<Alarm>> {
+        let now = bson::DateTime::now();
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+            "active_alarms.0": { "$exists": true },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::Before)
+            .build();
+        let collection = self.collection.clone();
+        let history = self.history.clone();
+        match self
+            .in_transaction(|session| {
+                let (collection, history, filter, options) = (
+                    collection.clone(),
+                    history.clone(),
+                    filter.clone(),
+                    options.clone(),
+                );
+                async move {
+                    let Some(resident) = collection
+                        .find_one_and_update(filter, doc! { "$set": { "active_alarms": [] } })
+                        .with_options(options)
+                        .session(&mut *session)
+                        .await?
+                    else {
+                        return Ok(None);
+                    };
+                    let Some(resident_id) = resident.id else {
+                        anyhow::bail!("Resident document without _id");
+                    };
+                    let records = resident
+                        .active_alarms
+                        .into_iter()
+                        .map(|alarm| {
+                            let duration = now
+                                .checked_duration_since(alarm.time)
+                                .unwrap_or_default()
+                                .as_secs();
+                            HistoryAlarm::new(resident_id, alarm.cleared(duration))
+                        })
+                        .collect::<Vec<_>>();
+                    history.insert_many(&records).session(&mut *session).await?;
+                    let cleared = records.into_iter().map(Alarm::from).collect::<Vec<_>>();
+                    Ok(Some((resident_id, cleared)))
+                }
+                .boxed()
+            })
+            .await
+        {
+            Ok(Some((resident_id, cleared))) => {
+                info!(
+                    "Closed {} alarms for resident id: {}",
+                    cleared.len(),
+                    resident_id
+                );
+                Ok(cleared)
+            }
+            Ok(None) => Ok(Vec::new()),
+            Err(e) => anyhow::bail!(format!("Failed to close alarms: {}", e)),
+        }
+    }
+
+    /// History is read with one query per page of [`HISTORY_PAGE`] residents instead of a
+    /// `$lookup`, so it is not bound by the document size limit. Alarms still embedded by a
+    /// resident not yet migrated are kept.
+    async fn with_history(
+        &self,
+        filter: &ResidentFilter,
+        from_date: Option<bson::DateTime>,
+        to_date: Option<bson::DateTime>,
+    ) -> Result<BoxStream<'_, Result<Resident>>> {
+        let mut time_range = doc! {};
+        if let Some(from_date) = from_date {
+            time_range.insert("$gte", from_date);
+        }
+        if let Some(to_date) = to_date {
+            time_range.insert("$lte", to_date);
+        }
+        let in_window = move |time: bson::DateTime| {
+            from_date.is_none_or(|f| time >= f) && to_date.is_none_or(|t| time <= t)
+        };
+        let cursor = self
+            .collection
+            .find(filter.to_document())
+            .sort(doc! { "name": 1, "birth": 1 })
+            .await?;
+        let history = self.history.clone();
+        Ok(cursor
+            .map_err(anyhow::Error::from)
+            .try_chunks(HISTORY_PAGE)
+            .map_err(|e| e.1)
+            .and_then(move |mut residents| {
+                let history = history.clone();
+                let ids: Vec<ObjectId> = residents.iter().filter_map(|r| r.id).collect();
+                let mut history_match = doc! { "resident_id": { "$in": ids } };
+                if !time_range.is_empty() {
+                    history_match.insert("time", time_range.clone());
+                }
+                async move {
+                    let mut by_resident: HashMap<ObjectId, Vec<Alarm>> = HashMap::new();
+                    let mut cursor = history.find(history_match).sort(doc! { "time": 1 }).await?;
+                    while let Some(alarm) = cursor.try_next().await? {
+                        by_resident
+                            .entry(alarm.resident_id)
+                            .or_default()
+                            .push(Alarm::from(alarm));
+                    }
+                    for resident in &mut residents {
+                        let Some(id) = resident.id else {
+                            continue;
+                        };
+                        let mut alarms = by_resident.remove(&id).unwrap_or_default();
+                        if !resident.alarms.is_empty() {
+                            let stored: std::collections::HashSet<ObjectId> =
+                                alarms.iter().map(|alarm| alarm.id).collect();
+                            alarms.extend(std::mem::take(&mut resident.alarms).into_iter().filter(
+                                |alarm| !stored.contains(&alarm.id) && in_window(alarm.time),
+                            ));
+                            alarms.sort_by_key(|alarm| alarm.time);
+                        }
+                        resident.alarms = alarms;
+                    }
+                    Ok(futures::stream::iter(residents.into_iter().map(Ok)))
+                }
+            })
+            .try_flatten()
+            .boxed())
+    }
+
+    /// Resident and history are written in one transaction if the server has them, history alarms already
+    /// stored are left as they are
+    #[tracing::instrument(name = "restore", skip_all, fields(name=%resident.name, birth=%resident.birth), level = Level::TRACE)]
+    async fn restore(&self, resident: &Resident) -> Result<bool> {
+        let mut resident = resident.clone();
+        let alarms = std::mem::take(&mut resident.alarms);
+        resident.schema_version = SCHEMA_VERSION;
+        let collection = self.collection.clone();
+        let history = self.history.clone();
+        let restored = self
+            .in_transaction(|session| {
+                let (collection, history, mut resident, alarms) = (
+                    collection.clone(),
+                    history.clone(),
+                    resident.clone(),
+                    alarms.clone(),
+                );
+                async move {
+                    let existing = collection
+                        .find_one(resident.unique_index())
+                        .session(&mut *session)
+                        .await?;
+                    let (resident_id, inserted) = match existing.and_then(|r| r.id) {
+                        Some(id) => {
+                            // without _id the stored one is kept
+                            resident.id = None;
+                            collection
+                                .replace_one(doc! { "_id": id }, &resident)
+                                .session(&mut *session)
+                                .await?;
+                            (id, false)
+                        }
+                        None => {
+                            let id = *resident.id.get_or_insert_with(ObjectId::new);
+                            collection
+                                .insert_one(&resident)
+                                .session(&mut *session)
+                                .await?;
+                            (id, true)
+                        }
+                    };
+                    if !alarms.is_empty() {
+                        let ids = alarms.iter().map(|a| a.id).collect::<Vec<_>>();
+                        let stored = history
+                            .distinct("_id", doc! { "_id": { "$in": ids } })
+                            .session(&mut *session)
+                            .await?;
+                        let records = alarms
+                            .into_iter()
+                            .filter(|a| !stored.contains(&bson::Bson::ObjectId(a.id)))
+                            .map(|a| HistoryAlarm::new(resident_id, a))
+                            .collect::<Vec<_>>();
+                        if !records.is_empty() {
+                            history.insert_many(records).session(&mut *session).await?;
+                        }
+                    }
+                    Ok(Some(inserted))
+                }
+                .boxed()
+            })
+            .await?;
+        Ok(restored.unwrap_or_default())
+    }
+
+    #[tracing::instrument(name = "delete_matching", skip_all, level = Level::TRACE)]
+    async fn delete_matching(&self, filter: &ResidentFilter) -> Result<u64> {
+        let filter = filter.to_document();
+        let collection = self.collection.clone();
+        let history = self.history.clone();
+        let deleted = self
+            .in_transaction(|session| {
+                let (collection, history, filter) =
+                    (collection.clone(), history.clone(), filter.clone());
+                async move {
+                    let ids = collection
+                        .distinct("_id", filter)
+                        .session(&mut *session)
+                        .await?;
+                    history
+                        .delete_many(doc! { "resident_id": { "$in": &ids } })
+                        .session(&mut *session)
+                        .await?;
+                    let result = collection
+                        .delete_many(doc! { "_id": { "$in": ids } })
+                        .session(&mut *session)
+                        .await?;
+                    Ok(Some(result.deleted_count))
+                }
+                .boxed()
+            })
+            .await?;
+        Ok(deleted.unwrap_or_default())
+    }
+
+    async fn with_active_alarms(&self) -> Result<Vec<Resident>> {
+        Ok(self
+            .collection
+            .find(doc! { "active_alarms.0": { "$exists": true } })
+            .projection(doc! { "alarms": 0 })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    #[tracing::instrument(name = "escalate_alarm", skip_all, fields(name=%name, birth=%birth, alarm=%alarm_id, level=level), level = Level::TRACE)]
+    async fn escalate_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<bool> {
+        // null also matches alarms never escalated
+        let previous = match level {
+            1 => bson::Bson::Null,
+            _ => bson::Bson::Int64(level as i64 - 1),
+        };
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+            "active_alarms": { "$elemMatch": {
+                "_id": alarm_id,
+                "escalation_level": &previous,
+                // no outstanding events, null for alarms escalated before they were tracked
+                "escalation_sent": { "$in": [&previous, bson::Bson::Null] },
+            } },
+        };
+        let update = doc! { "$set": {
+            "active_alarms.$.escalation_level": level as i64,
+            "active_alarms.$.escalated_at": bson::DateTime::now(),
+            "active_alarms.$.escalation_sent": level as i64 - 1,
+        } };
+        match self.collection.update_one(filter, update).await {
+            Ok(update_result) => Ok(update_result.modified_count > 0),
+            Err(e) => anyhow::bail!(format!("Failed to escalate alarm: {}", e)),
+        }
+    }
+
+    async fn escalation_sent(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<()> {
+        let filter = doc! {
+            "name": name,
+            "birth": birth,
+            "active_alarms._id": alarm_id,
+        };
+        let update = doc! { "$max": { "active_alarms.$.escalation_sent": level as i64 } };
+        if let Err(e) = self.collection.update_one(filter, update).await {
+            anyhow::bail!(format!("Failed to mark escalation sent: {}", e));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "query", skip_all, level = Level::TRACE)]
+    async fn query(&self, query_params: &QueryParams) -> Result<Vec<bson::Document>> {
+        // resident columns select residents before the lookup, the rest filters the results
+        let (resident_filter, result_filter) = match query_params.filter_expr()? {
+            Some(filter) => filter.split(),
+            None => (None, None),
+        };
+        let sort = query_params.sort_spec()?;
+        let cursor = query_params.page_cursor()?;
+        let (from_date, to_date) = query_params.date_range()?;
+        let mut time_range = doc! {};
+        if let Some(from_date) = from_date {
+            time_range.insert("$gte", from_date);
+        }
+        if let Some(to_date) = to_date {
+            time_range.insert("$lte", to_date);
+        }
+        let text_matches = match query_params.text_search()? {
+            Some(search) => Some(self.text_matches(&search, &time_range).await?),
+            None => None,
+        };
+        let mut resident_match = resident_filter.map(|f| f.to_document()).unwrap_or_default();
+        if let Some(matches) = &text_matches {
+            resident_match.insert(
+                "_id",
+                doc! { "$in": matches.keys().copied().collect::<Vec<_>>() },
+            );
+        }
+        // most recent alarms_limit history alarms in the date window
+        let mut alarms_pipeline = vec![];
+        if !time_range.is_empty() {
+            alarms_pipeline.push(doc! { "$match": { "time": time_range } });
+        }
+        alarms_pipeline.push(doc! { "$sort": { "time": -1 } });
+        if query_params.alarms_limit > 0 {
+            alarms_pipeline.push(doc! { "$limit": query_params.alarms_limit });
+        }
+        let timezone = utils::timezone().name();
+        let mut pipeline = vec![
+            doc! { "$match": resident_match },
+            doc! { "$lookup": {
+                "from": self.history.name(),
+                "localField": "_id",
+                "foreignField": "resident_id",
+                "pipeline": alarms_pipeline,
+                "as": "filteredAlarms"
+            } },
+            doc! { "$match": {
+                "$or": [
+                    { "active_alarms.0": { "$exists": true } },
+                    { "filteredAlarms.0": { "$exists": true } }
+                ]
+            } },
+            doc! { "$project": {
+                "name": 1, "location": 1, "birth" : 1, "resident_since": 1,
+                // completed years, one less before the birthday
+                "age": { "$subtract": [
+                    { "$subtract": [
+                        { "$year": { "date": "$$NOW", "timezone": timezone } },
+                        { "$year": { "date": "$birth", "timezone": timezone } }
+                    ] },
+                    { "$cond": [
+                        { "$lt": [
+                            { "$dateToString": { "format": "%m-%d", "date": "$$NOW", "timezone": timezone } },
+                            { "$dateToString": { "format": "%m-%d", "date": "$birth", "timezone": timezone } }
+                        ] },
+                        1,
+                        0
+                    ] }
+                ] },
+                "length_of_stay": { "$dateDiff": {
+                    "startDate": "$resident_since",
+                    "endDate": "$$NOW",
+                    "unit": "day",
+                    "timezone": timezone
+                } },
+                "alarms_count": { "$size": "$filteredAlarms" },
+                "avg_duration": { "$avg": "$filteredAlarms.duration_sec" },
+                "max_duration": { "$max": "$filteredAlarms.duration_sec" },
+                "avg_ack_duration": { "$avg": "$filteredAlarms.ack_duration_sec" },
+                "max_ack_duration": { "$max": "$filteredAlarms.ack_duration_sec" },
+                "first": { "$min": "$filteredAlarms.time" },
+                "last": { "$max": "$filteredAlarms.time" },
+                "active_since": { "$min": "$active_alarms.time" },
+                "active_count": { "$size": { "$ifNull": ["$active_alarms", []] } },
+                "active_alarm_ids": "$active_alarms._id"
+            } },
+        ];
+        // with a message search, scores are attached to the matched residents here and the
+        // rows are filtered, sorted and paged like the memory store does
+        if text_matches.is_none() {
+            if let Some(filter) = &result_filter {
+                pipeline.push(doc! { "$match": filter.to_document() });
+            }
+            if let Some(cursor) = cursor {
+                pipeline.push(doc! { "$match": cursor.to_match() });
+            }
+            pipeline.push(doc! { "$sort": sort.to_document() });
+            if query_params.skip > 0 {
+                pipeline.push(doc! { "$skip": query_params.skip as i64 });
+            }
+            if let Some(limit) = query_params.limit.filter(|l| *l > 0) {
+                pipeline.push(doc! { "$limit": limit as i64 });
+            }
+        }
+        tracing::trace!(
+            "Aggregation pipeline: {}",
+            serde_json::to_string(&pipeline).unwrap_or_default()
+        );
+        let mut rows: Vec<bson::Document> = match self.collection.aggregate(pipeline).await {
+            Ok(cursor) => cursor.try_collect().await?,
+            Err(e) => {
+                error!("Failed to query residents: {}", e);
+                return Err(e.into());
+            }
+        };
+        let Some(mut matches) = text_matches else {
+            return Ok(rows);
+        };
+        for row in &mut rows {
+            if let Some((alarm_ids, score)) = matches.remove(&row.get_object_id("_id")?) {
+                row.insert("matched_alarm_ids", alarm_ids);
+                row.insert("score", score);
+            }
+        }
+        if let Some(filter) = &result_filter {
+            rows.retain(|row| filter.matches(row));
+        }
+        query_params.paginate(rows)
+    }
+
+    #[tracing::instrument(name = "report", skip_all, level = Level::TRACE)]
+    async fn report(&self, params: &ReportParams) -> Result<Vec<bson::Document>> {
+        let filter = params.window.resident_filter()?;
+        let (from_date, to_date) = params.window.date_range()?;
+        let mut time_range = doc! {};
+        if let Some(from_date) = from_date {
+            time_range.insert("$gte", from_date);
+        }
+        if let Some(to_date) = to_date {
+            time_range.insert("$lte", to_date);
+        }
+        let mut alarm_match = doc! {};
+        if !time_range.is_empty() {
+            alarm_match.insert("time", time_range);
+        }
+        if !filter.is_empty() {
+            let resident_ids = self
+                .collection
+                .distinct("_id", filter.to_document())
+                .await?;
+            alarm_match.insert("resident_id", doc! { "$in": resident_ids });
+        }
+        let mut pipeline = vec![doc! { "$match": alarm_match }];
+        let key = match params.group_by.time_format() {
+            Some(format) => bson::Bson::from(doc! { "$dateToString": {
+                "format": format,
+                "date": "$time",
+                "timezone": utils::timezone().name(),
+            } }),
+            None if params.group_by == GroupBy::Message => "$message".into(),
+            None => {
+                pipeline.push(doc! { "$lookup": {
+                    "from": self.collection.name(),
+                    "localField": "resident_id",
+                    "foreignField": "_id",
+                    "pipeline": [{ "$project": { "location": 1 } }],
+                    "as": "resident"
+                } });
+                doc! { "$first": "$resident.location" }.into()
+            }
+        };
+        // nearest rank among the durations of the group, ranked by a window over each group
+        // so no group has to hold all its durations in one document
+        let rank = |p: f64| {
+            let target = doc! { "$ceil": { "$multiply": [p, "$group_count"] } };
+            doc! { "$max": { "$cond": [
+                { "$eq": ["$rank", target] },
+                "$duration_sec",
+                bson::Bson::Null
+            ] } }
+        };
+        pipeline.extend([
+            doc! { "$set": { "key": key } },
+            doc! { "$setWindowFields": {
+                "partitionBy": "$key",
+                "sortBy": { "duration_sec": 1 },
+                "output": {
+                    "rank": { "$documentNumber": {} },
+                    "group_count": { "$count": {} },
+                },
+            } },
+            doc! { "$group": {
+                "_id": "$key",
+                "count": { "$sum": 1_i64 },
+                "total_duration": { "$sum": "$duration_sec" },
+                "avg_duration": { "$avg": "$duration_sec" },
+                "median_duration": rank(0.5),
+                "p90_duration": rank(0.9),
+                "max_duration": { "$max": "$duration_sec" },
+                "residents": { "$addToSet": "$resident_id" },
+            } },
+            doc! { "$sort": { "_id": 1 } },
+            doc! { "$project": {
+                "_id": 0,
+                params.group_by.column(): "$_id",
+                "count": "$count",
+                "total_duration": "$total_duration",
+                "avg_duration": "$avg_duration",
+                "median_duration": "$median_duration",
+                "p90_duration": "$p90_duration",
+                "max_duration": "$max_duration",
+                "residents": { "$size": "$residents" },
+            } },
+        ]);
+        tracing::trace!(
+            "Report pipeline: {}",
+            serde_json::to_string(&pipeline).unwrap_or_default()
+        );
+        let mut rows: Vec<bson::Document> = self
+            .history
+            .aggregate(pipeline)
+            .await?
+            .try_collect()
+            .await?;
+        report::finish_rows(params.group_by, &mut rows);
+        Ok(rows)
+    }
+
+    async fn stats(&self) -> Result<Vec<bson::Document>> {
+        let pipeline = vec![doc! {
+            "$collStats": {
+                "latencyStats": { "histograms": true },
+                "storageStats": { "scale": 1024 },
+                "count" : {},
+                "queryExecStats" : {}
+            }
+        }];
+        let mut stats: Vec<bson::Document> = self
+            .collection
+            .aggregate(pipeline.clone())
+            .await?
+            .try_collect()
+            .await?;
+        stats.extend(
+            self.history
+                .aggregate(pipeline)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?,
+        );
+        Ok(stats)
+    }
+}
+
+/// Text index on alarm messages. Without a language words match without stemming,
+/// the same as [`TextSearch`] used by the memory backend.
+fn text_index(field: &str) -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! { field: "text" })
+        .options(Some(
+            IndexOptions::builder()
+                .name(TEXT_INDEX.to_string())
+                .default_language("none".to_string())
+                .build(),
+        ))
+        .build()
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        mongodb::error::ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))
+    )
+}
+
+fn has_label(e: &anyhow::Error, label: &str) -> bool {
+    e.downcast_ref::<mongodb::error::Error>()
+        .is_some_and(|e| e.contains_label(label))
+}
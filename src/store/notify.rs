@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::stream::BoxStream;
+use mongodb::bson::{self, oid::ObjectId};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use super::{BatchOutcome, QueryParams, ReportParams, ResidentFilter, ResidentStore, WriteOutcome};
+use crate::model::{ActiveAlarm, Alarm, AlarmEvent, AlarmRef, Resident};
+
+/// [`ResidentStore`] decorator publishing an [`AlarmEvent`] for every alarm raised,
+/// acknowledged or cleared through it. Changes made by other processes are not seen,
+/// use a change stream (`watch`) for those.
+pub struct NotifyingStore {
+    inner: Arc<dyn ResidentStore>,
+    events: broadcast::Sender<AlarmEvent>,
+}
+
+impl NotifyingStore {
+    pub fn new(inner: Arc<dyn ResidentStore>, events: broadcast::Sender<AlarmEvent>) -> Self {
+        NotifyingStore { inner, events }
+    }
+
+    /// Looks up the resident for the event, skipped if it was deleted in the meantime.
+    /// The change is already written, so a failed lookup only loses the event.
+    async fn publish(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        event: impl FnOnce(&Resident) -> AlarmEvent,
+    ) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        match self.inner.find(name, birth).await {
+            Ok(Some(resident)) => {
+                // no subscriber left is not an error
+                self.events.send(event(&resident)).ok();
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Cannot publish alarm event of {}: {}", name, e),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ResidentStore for NotifyingStore {
+    async fn insert(&self, resident: &Resident) -> Result<WriteOutcome> {
+        self.inner.insert(resident).await
+    }
+
+    async fn upsert(&self, resident: &Resident) -> Result<WriteOutcome> {
+        self.inner.upsert(resident).await
+    }
+
+    async fn write_batch(
+        &self,
+        residents: &[Resident],
+        upsert: bool,
+        ordered: bool,
+    ) -> Result<BatchOutcome> {
+        self.inner.write_batch(residents, upsert, ordered).await
+    }
+
+    async fn delete(&self, name: &str, birth: bson::DateTime) -> Result<bool> {
+        self.inner.delete(name, birth).await
+    }
+
+    async fn find(&self, name: &str, birth: bson::DateTime) -> Result<Option<Resident>> {
+        self.inner.find(name, birth).await
+    }
+
+    async fn new_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        message: &str,
+        start_time: Option<bson::DateTime>,
+    ) -> Result<ActiveAlarm> {
+        let alarm = self
+            .inner
+            .new_alarm(name, birth, message, start_time)
+            .await?;
+        self.publish(name, birth, |r| AlarmEvent::raised(r, &alarm))
+            .await;
+        Ok(alarm)
+    }
+
+    async fn ack_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        by: &str,
+    ) -> Result<Option<ActiveAlarm>> {
+        let acked = self.inner.ack_alarm(name, birth, alarm, by).await?;
+        if let Some(acked) = &acked {
+            self.publish(name, birth, |r| AlarmEvent::acknowledged(r, acked))
+                .await;
+        }
+        Ok(acked)
+    }
+
+    async fn clear_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm: AlarmRef,
+        duration: Option<u64>,
+    ) -> Result<Option<Alarm>> {
+        let cleared = self.inner.clear_alarm(name, birth, alarm, duration).await?;
+        if let Some(cleared) = &cleared {
+            self.publish(name, birth, |r| AlarmEvent::cleared(r, cleared))
+                .await;
+        }
+        Ok(cleared)
+    }
+
+    async fn force_close(&self, name: &str, birth: bson::DateTime) -> Result<Vec<Alarm>> {
+        let closed = self.inner.force_close(name, birth).await?;
+        for cleared in &closed {
+            self.publish(name, birth, |r| AlarmEvent::cleared(r, cleared))
+                .await;
+        }
+        Ok(closed)
+    }
+
+    async fn with_history(
+        &self,
+        filter: &ResidentFilter,
+        from_date: Option<bson::DateTime>,
+        to_date: Option<bson::DateTime>,
+    ) -> Result<BoxStream<'_, Result<Resident>>> {
+        self.inner.with_history(filter, from_date, to_date).await
+    }
+
+    async fn restore(&self, resident: &Resident) -> Result<bool> {
+        self.inner.restore(resident).await
+    }
+
+    async fn delete_matching(&self, filter: &ResidentFilter) -> Result<u64> {
+        self.inner.delete_matching(filter).await
+    }
+
+    async fn with_active_alarms(&self) -> Result<Vec<Resident>> {
+        self.inner.with_active_alarms().await
+    }
+
+    async fn escalate_alarm(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<bool> {
+        self.inner
+            .escalate_alarm(name, birth, alarm_id, level)
+            .await
+    }
+
+    async fn escalation_sent(
+        &self,
+        name: &str,
+        birth: bson::DateTime,
+        alarm_id: ObjectId,
+        level: u32,
+    ) -> Result<()> {
+        self.inner
+            .escalation_sent(name, birth, alarm_id, level)
+            .await
+    }
+
+    async fn query(&self, params: &QueryParams) -> Result<Vec<bson::Document>> {
+        self.inner.query(params).await
+    }
+
+    async fn report(&self, params: &ReportParams) -> Result<Vec<bson::Document>> {
+        self.inner.report(params).await
+    }
+
+    async fn stats(&self) -> Result<Vec<bson::Document>> {
+        self.inner.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::model::AlarmState;
+    use crate::store::MemoryStore;
+
+    fn next(events: &mut broadcast::Receiver<AlarmEvent>) -> AlarmEvent {
+        events.try_recv().expect("an event is published")
+    }
+
+    #[tokio::test]
+    async fn each_alarm_change_publishes_one_event() {
+        let (sender, mut events) = broadcast::channel(16);
+        let store = NotifyingStore::new(Arc::new(MemoryStore::new()), sender);
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        let raised = store
+            .new_alarm(&ann.name, ann.birth, "fall", None)
+            .await
+            .unwrap();
+        let event = next(&mut events);
+        assert_eq!(event.event, AlarmState::Raised);
+        assert_eq!(event.alarm_id, raised.id);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        store
+            .ack_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), "nurse")
+            .await
+            .unwrap();
+        assert_eq!(next(&mut events).event, AlarmState::Acknowledged);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), None)
+            .await
+            .unwrap();
+        assert_eq!(next(&mut events).event, AlarmState::Cleared);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        // nothing changed, nothing published
+        store
+            .clear_alarm(&ann.name, ann.birth, AlarmRef::Id(raised.id), None)
+            .await
+            .unwrap();
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn force_close_publishes_the_closed_alarms() {
+        let (sender, mut events) = broadcast::channel(16);
+        let store = NotifyingStore::new(Arc::new(MemoryStore::new()), sender);
+        let ann = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        store.insert(&ann).await.unwrap();
+        for message in ["fall", "call"] {
+            store
+                .new_alarm(&ann.name, ann.birth, message, None)
+                .await
+                .unwrap();
+            assert_eq!(next(&mut events).event, AlarmState::Raised);
+        }
+
+        let closed = store.force_close(&ann.name, ann.birth).await.unwrap();
+        assert_eq!(closed.len(), 2);
+        for alarm in &closed {
+            let event = next(&mut events);
+            assert_eq!(event.event, AlarmState::Cleared);
+            assert_eq!(event.alarm_id, alarm.id);
+        }
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        let closed = store.force_close(&ann.name, ann.birth).await.unwrap();
+        assert!(closed.is_empty());
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
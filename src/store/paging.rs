@@ -0,0 +1,310 @@
+//! Sort order and keyset pagination of query results.
+//!
+//! Rows are ordered by the sort column, then by `_id` in the same direction, so the order
+//! is total and stable. A cursor holds the sort key of the last row of a page; the next
+//! page starts right after it even if rows were added or removed in the meantime.
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use mongodb::bson::{self, Bson, doc, oid::ObjectId};
+
+use super::StoreError;
+
+/// Query result columns that can be sorted on
+pub const SORT_COLUMNS: [&str; 16] = [
+    "name",
+    "birth",
+    "location",
+    "resident_since",
+    "age",
+    "length_of_stay",
+    "alarms_count",
+    "avg_duration",
+    "max_duration",
+    "avg_ack_duration",
+    "max_ack_duration",
+    "first",
+    "last",
+    "active_since",
+    "active_count",
+    "score",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortSpec {
+    pub column: String,
+    pub descending: bool,
+}
+
+impl Default for SortSpec {
+    fn default() -> Self {
+        SortSpec {
+            column: "location".to_string(),
+            descending: false,
+        }
+    }
+}
+
+impl SortSpec {
+    /// Parses `column`, `column:asc` or `column:desc`
+    pub fn parse(s: &str) -> Result<Self> {
+        let (column, direction) = s.split_once(':').unwrap_or((s, "asc"));
+        let descending = match direction.to_ascii_lowercase().as_str() {
+            "asc" => false,
+            "desc" => true,
+            _ => anyhow::bail!(StoreError::Invalid(format!(
+                "invalid sort direction '{direction}': expected asc or desc"
+            ))),
+        };
+        if !SORT_COLUMNS.contains(&column) {
+            anyhow::bail!(StoreError::Invalid(format!(
+                "cannot sort on '{column}': expected one of {}",
+                SORT_COLUMNS.join(", ")
+            )));
+        }
+        Ok(SortSpec {
+            column: column.to_string(),
+            descending,
+        })
+    }
+
+    /// `$sort` stage document
+    pub fn to_document(&self) -> bson::Document {
+        let direction = if self.descending { -1 } else { 1 };
+        let mut sort = bson::Document::new();
+        sort.insert(self.column.as_str(), direction);
+        sort.insert("_id", direction);
+        sort
+    }
+
+    /// Compares two result rows in this order
+    pub fn compare(&self, a: &bson::Document, b: &bson::Document) -> Ordering {
+        let ordering = compare_bson(a.get(&self.column), b.get(&self.column))
+            .then_with(|| compare_bson(a.get("_id"), b.get("_id")));
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// Position after the last row of a page
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    sort: SortSpec,
+    value: Bson,
+    id: ObjectId,
+}
+
+impl Cursor {
+    /// Cursor after `row`, None if the row has no `_id`
+    pub fn after(sort: &SortSpec, row: &bson::Document) -> Option<Self> {
+        Some(Cursor {
+            sort: sort.clone(),
+            value: row.get(&sort.column).cloned().unwrap_or(Bson::Null),
+            id: row.get_object_id("_id").ok()?,
+        })
+    }
+
+    /// Opaque token, the hex encoded BSON of the sort key
+    pub fn encode(&self) -> String {
+        let token = doc! {
+            "s": &self.sort.column,
+            "d": self.sort.descending,
+            "v": self.value.clone(),
+            "i": self.id,
+        };
+        let mut bytes = Vec::new();
+        // writing into a Vec cannot fail
+        token.to_writer(&mut bytes).ok();
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Reads a token written by [`encode`](Self::encode), checking it was issued for `sort`
+    pub fn decode(token: &str, sort: &SortSpec) -> Result<Self> {
+        let invalid = || StoreError::Invalid(format!("invalid cursor '{token}'"));
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| {
+                token
+                    .get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let token_doc = bson::Document::from_reader(bytes.as_slice()).map_err(|_| invalid())?;
+        let cursor = Cursor {
+            sort: SortSpec {
+                column: token_doc.get_str("s").map_err(|_| invalid())?.to_string(),
+                descending: token_doc.get_bool("d").map_err(|_| invalid())?,
+            },
+            value: token_doc.get("v").cloned().unwrap_or(Bson::Null),
+            id: token_doc.get_object_id("i").map_err(|_| invalid())?,
+        };
+        if &cursor.sort != sort {
+            anyhow::bail!(StoreError::Invalid(format!(
+                "cursor was issued for sorting on {}:{}",
+                cursor.sort.column,
+                if cursor.sort.descending {
+                    "desc"
+                } else {
+                    "asc"
+                }
+            )));
+        }
+        Ok(cursor)
+    }
+
+    /// Whether `row` comes after the cursor
+    pub fn is_before(&self, row: &bson::Document) -> bool {
+        let last = doc! { &self.sort.column: self.value.clone(), "_id": self.id };
+        self.sort.compare(&last, row) == Ordering::Less
+    }
+
+    /// `$match` stage selecting the rows after the cursor. Null sorts before every other
+    /// value, like in MongoDB, and comparison operators never match it.
+    pub fn to_match(&self) -> bson::Document {
+        let column = self.sort.column.as_str();
+        let (after, id_after) = if self.sort.descending {
+            ("$lt", doc! { "$lt": self.id })
+        } else {
+            ("$gt", doc! { "$gt": self.id })
+        };
+        let same_key = doc! { column: self.value.clone(), "_id": id_after };
+        let alternatives = match (&self.value, self.sort.descending) {
+            // every non-null value comes after null
+            (Bson::Null, false) => vec![same_key, doc! { column: { "$ne": Bson::Null } }],
+            // nothing but other nulls comes after null
+            (Bson::Null, true) => vec![same_key],
+            (value, false) => vec![same_key, doc! { column: { after: value.clone() } }],
+            (value, true) => vec![
+                same_key,
+                doc! { column: { after: value.clone() } },
+                doc! { column: Bson::Null },
+            ],
+        };
+        doc! { "$or": alternatives }
+    }
+}
+
+/// Orders values like MongoDB for the types found in query results:
+/// null, then numbers, strings, object ids and dates
+pub(super) fn compare_bson(a: Option<&Bson>, b: Option<&Bson>) -> Ordering {
+    fn number(value: &Bson) -> f64 {
+        match value {
+            Bson::Int32(n) => *n as f64,
+            Bson::Int64(n) => *n as f64,
+            Bson::Double(n) => *n,
+            _ => 0.0,
+        }
+    }
+    match (a, b) {
+        (Some(Bson::String(a)), Some(Bson::String(b))) => a.cmp(b),
+        (Some(Bson::ObjectId(a)), Some(Bson::ObjectId(b))) => a.cmp(b),
+        (Some(Bson::DateTime(a)), Some(Bson::DateTime(b))) => a.cmp(b),
+        (Some(a), Some(b)) if rank(Some(a)) == 1 && rank(Some(b)) == 1 => {
+            number(a).total_cmp(&number(b))
+        }
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Like [`compare_bson`] but None unless both values are non-null and of the same type,
+/// the values MongoDB range operators compare
+pub(super) fn compare_same_type(a: Option<&Bson>, b: &Bson) -> Option<Ordering> {
+    let rank_a = rank(a);
+    (rank_a != 0 && rank_a == rank(Some(b))).then(|| compare_bson(a, Some(b)))
+}
+
+fn rank(value: Option<&Bson>) -> u8 {
+    match value {
+        None | Some(Bson::Null) => 0,
+        Some(Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_)) => 1,
+        Some(Bson::String(_)) => 2,
+        Some(Bson::ObjectId(_)) => 3,
+        Some(Bson::DateTime(_)) => 4,
+        Some(_) => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates the `$or` of [`Cursor::to_match`] on `row` with MongoDB semantics
+    fn mongo_matches(filter: &bson::Document, row: &bson::Document) -> bool {
+        let condition = |field: &str, condition: &Bson| {
+            let value = row.get(field);
+            let equal = |other: &Bson| compare_bson(value, Some(other)) == Ordering::Equal;
+            match condition {
+                Bson::Document(ops) => ops.iter().all(|(op, other)| match op.as_str() {
+                    "$gt" => compare_same_type(value, other) == Some(Ordering::Greater),
+                    "$lt" => compare_same_type(value, other) == Some(Ordering::Less),
+                    "$ne" => !equal(other),
+                    _ => panic!("unexpected operator {op}"),
+                }),
+                other => equal(other),
+            }
+        };
+        filter.get_array("$or").unwrap().iter().any(|alternative| {
+            let alternative = alternative.as_document().unwrap();
+            alternative.iter().all(|(field, c)| condition(field, c))
+        })
+    }
+
+    fn rows() -> Vec<bson::Document> {
+        let values = [
+            Bson::Null,
+            Bson::Int32(3),
+            Bson::Double(3.5),
+            Bson::Int64(7),
+        ];
+        let mut rows: Vec<_> = values
+            .iter()
+            .flat_map(|value| {
+                (0..2).map(|_| doc! { "_id": ObjectId::new(), "avg_duration": value.clone() })
+            })
+            .collect();
+        // a row without the column sorts like null
+        rows.push(doc! { "_id": ObjectId::new() });
+        rows
+    }
+
+    #[test]
+    fn cursor_round_trips_through_its_token() {
+        let sort = SortSpec::parse("avg_duration:desc").unwrap();
+        for row in rows() {
+            let cursor = Cursor::after(&sort, &row).unwrap();
+            let decoded = Cursor::decode(&cursor.encode(), &sort).unwrap();
+            assert_eq!(decoded.sort, cursor.sort);
+            assert_eq!(decoded.value, cursor.value);
+            assert_eq!(decoded.id, cursor.id);
+        }
+        let cursor = Cursor::after(&sort, &rows()[1]).unwrap();
+        let other = SortSpec::parse("avg_duration").unwrap();
+        assert!(Cursor::decode(&cursor.encode(), &other).is_err());
+        assert!(Cursor::decode("zz", &sort).is_err());
+        assert!(Cursor::decode("abc", &sort).is_err());
+    }
+
+    #[test]
+    fn to_match_agrees_with_is_before() {
+        let rows = rows();
+        for spec in ["avg_duration", "avg_duration:desc"] {
+            let sort = SortSpec::parse(spec).unwrap();
+            for last in &rows {
+                let cursor = Cursor::after(&sort, last).unwrap();
+                let filter = cursor.to_match();
+                for row in &rows {
+                    assert_eq!(
+                        mongo_matches(&filter, row),
+                        cursor.is_before(row),
+                        "{spec} after {last} for {row}"
+                    );
+                }
+            }
+        }
+    }
+}
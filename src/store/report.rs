@@ -0,0 +1,80 @@
+//! Alarm history statistics grouped by location, message or time of the alarm.
+//!
+//! Times are grouped in the facility timezone. Median and p90 use the nearest-rank
+//! method, so they are always durations that actually occurred.
+
+use clap::{Parser, ValueEnum};
+use mongodb::bson::{self, Bson};
+
+use super::AlarmWindow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// current location of the resident
+    Location,
+    Message,
+    /// YYYY-MM
+    Month,
+    /// Mon to Sun
+    Weekday,
+    /// 00 to 23
+    Hour,
+}
+
+impl GroupBy {
+    /// Name of the group column
+    pub fn column(self) -> &'static str {
+        match self {
+            GroupBy::Location => "location",
+            GroupBy::Message => "message",
+            GroupBy::Month => "month",
+            GroupBy::Weekday => "weekday",
+            GroupBy::Hour => "hour",
+        }
+    }
+
+    /// strftime format of the alarm time giving the group key, in key order
+    pub(crate) fn time_format(self) -> Option<&'static str> {
+        match self {
+            GroupBy::Location | GroupBy::Message => None,
+            GroupBy::Month => Some("%Y-%m"),
+            // ISO weekday 1 to 7, named by finish_rows
+            GroupBy::Weekday => Some("%u"),
+            GroupBy::Hour => Some("%H"),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ReportParams {
+    #[clap(value_enum, help = "Group alarms by")]
+    pub group_by: GroupBy,
+    #[command(flatten)]
+    pub window: AlarmWindow,
+}
+
+/// Nearest-rank percentile `p` (0 to 1) of ascending `sorted` values
+pub(crate) fn percentile(sorted: &[i64], p: f64) -> Option<i64> {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+/// Names weekday keys, the rows are already in key order
+pub(crate) fn finish_rows(group_by: GroupBy, rows: &mut [bson::Document]) {
+    if group_by != GroupBy::Weekday {
+        return;
+    }
+    const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    for row in rows {
+        let name = match row.get(group_by.column()) {
+            Some(Bson::String(day)) => day
+                .parse::<usize>()
+                .ok()
+                .and_then(|day| WEEKDAYS.get(day.wrapping_sub(1))),
+            _ => None,
+        };
+        if let Some(name) = name {
+            row.insert(group_by.column(), *name);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Text search over alarm messages.
+//!
+//! Follows MongoDB `$text` on the message indexes, which have no language: words match
+//! case-insensitively without stemming or stop words, any word may match, every quoted
+//! phrase must match and `-word` excludes a message. Used by the memory backend, and by
+//! the MongoDB backend to tell which active alarm of a matching resident matched.
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use super::StoreError;
+
+/// Name of the text indexes on alarm messages
+pub const TEXT_INDEX: &str = "message_text";
+
+#[derive(Debug, Clone)]
+pub struct TextSearch {
+    search: String,
+    terms: Vec<String>,
+    phrases: Vec<Vec<String>>,
+    excluded: Vec<String>,
+}
+
+impl TextSearch {
+    /// Parses words, `"quoted phrases"` and `-excluded` words
+    pub fn parse(search: &str) -> Result<Self> {
+        let mut text_search = TextSearch {
+            search: search.trim().to_string(),
+            terms: Vec::new(),
+            phrases: Vec::new(),
+            excluded: Vec::new(),
+        };
+        for (i, part) in search.split('"').enumerate() {
+            // odd parts are inside quotes, an unclosed quote runs to the end
+            if i % 2 == 1 {
+                let phrase = words(part);
+                if !phrase.is_empty() {
+                    text_search.phrases.push(phrase);
+                }
+                continue;
+            }
+            for token in part.split_whitespace() {
+                match token.strip_prefix('-') {
+                    Some(excluded) => text_search.excluded.extend(words(excluded)),
+                    None => text_search.terms.extend(words(token)),
+                }
+            }
+        }
+        if text_search.terms.is_empty() && text_search.phrases.is_empty() {
+            anyhow::bail!(StoreError::Invalid(format!(
+                "message search '{search}' has no word to look for"
+            )));
+        }
+        Ok(text_search)
+    }
+
+    /// The search as given, for `$text`
+    pub fn as_str(&self) -> &str {
+        &self.search
+    }
+
+    /// Relevance of `message`, None if it does not match. Every matched word adds
+    /// between 0.5 and 1, more for words making up more of the message.
+    pub fn score(&self, message: &str) -> Option<f64> {
+        let message = words(message);
+        if self.excluded.iter().any(|w| message.contains(w)) {
+            return None;
+        }
+        if !self
+            .phrases
+            .iter()
+            .all(|p| message.windows(p.len()).any(|w| w == p.as_slice()))
+        {
+            return None;
+        }
+        let searched = self
+            .terms
+            .iter()
+            .chain(self.phrases.iter().flatten())
+            .collect::<HashSet<_>>();
+        let score = searched
+            .iter()
+            .map(|word| message.iter().filter(|w| w == word).count())
+            .filter(|count| *count > 0)
+            .map(|count| 0.5 + 0.5 * count as f64 / message.len() as f64)
+            .sum::<f64>();
+        (score > 0.0).then_some(score)
+    }
+}
+
+/// Lowercase words, split at everything but letters and digits
+fn words(s: &str) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_words_phrases_and_exclusions() {
+        let search = TextSearch::parse(r#" Fall -bathroom "called  nurse" "#).unwrap();
+        assert_eq!(search.as_str(), r#"Fall -bathroom "called  nurse""#);
+        assert_eq!(search.terms, ["fall"]);
+        assert_eq!(search.phrases, [["called", "nurse"]]);
+        assert_eq!(search.excluded, ["bathroom"]);
+
+        let err = TextSearch::parse("-fall \"\"").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<StoreError>(),
+            Some(StoreError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn scores_matching_messages() {
+        let search = TextSearch::parse("fall call").unwrap();
+        assert_eq!(search.score("FALL"), Some(1.0));
+        // words making up less of the message count less
+        let score = search.score("fall in bathroom").unwrap();
+        assert!((score - (0.5 + 0.5 / 3.0)).abs() < 1e-9, "{score}");
+        // every matched word adds to the score
+        assert!(search.score("fall, call").unwrap() > search.score("fall").unwrap());
+        assert_eq!(search.score("falling"), None);
+        assert_eq!(search.score("door open"), None);
+    }
+
+    #[test]
+    fn phrases_must_match_and_exclusions_reject() {
+        let search = TextSearch::parse(r#"fall "called nurse" -door"#).unwrap();
+        assert_eq!(search.score("fall"), None);
+        assert_eq!(search.score("nurse called"), None);
+        assert!(search.score("Called the nurse? no: called nurse").is_some());
+        assert_eq!(search.score("called nurse at the door"), None);
+    }
+}
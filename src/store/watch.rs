@@ -0,0 +1,324 @@
+//! Live alarm events from a MongoDB change stream.
+//!
+//! Raised and acknowledged alarms are found by diffing `active_alarms` of the resident
+//! before and after each change. Both come from the pre- and post-images stored with the
+//! change (MongoDB 6.0+), so they show the resident as it was at that change even when the
+//! watcher resumes later or the alarm has been cleared since. An alarm removed from
+//! `active_alarms` is reported as cleared once its history entry is inserted, which also
+//! carries the durations; history written by `migrate` or a restore removes no active
+//! alarm and is not reported.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use futures::TryStreamExt as _;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId},
+    change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken},
+    options::{FullDocumentBeforeChangeType, FullDocumentType},
+};
+use tracing::{Level, debug, info, warn};
+
+use super::{MongoStore, ResidentFilter};
+use crate::model::{AlarmEvent, HistoryAlarm, Resident, SCHEMA_VERSION};
+
+#[derive(Parser, Debug, Clone, Default)]
+pub struct WatchParams {
+    #[clap(short, long, help = "Optional Regexp pattern to match resident names")]
+    pub name: Option<String>,
+    #[clap(
+        short,
+        long,
+        help = "Optional Regexp pattern to match resident locations"
+    )]
+    pub location: Option<String>,
+    #[clap(
+        long,
+        help = "File keeping the resume token, a restarted watcher continues after the last event"
+    )]
+    pub resume_file: Option<PathBuf>,
+}
+
+/// Watches residents and alarm history, calling `emit` for every alarm event of a
+/// matching resident. Runs until the change stream is invalidated or fails.
+#[tracing::instrument(name = "watch", skip_all, level = Level::TRACE)]
+pub async fn watch(
+    store: &MongoStore,
+    params: &WatchParams,
+    mut emit: impl FnMut(&AlarmEvent) -> Result<()>,
+) -> Result<()> {
+    let filter = ResidentFilter::new(&params.name, &params.location)?;
+    let resume_token = match &params.resume_file {
+        Some(path) if path.exists() => Some(load_resume_token(path)?),
+        _ => None,
+    };
+
+    let outdated = store
+        .collection()
+        .count_documents(doc! { "schema_version": { "$not": { "$gte": SCHEMA_VERSION as i64 } } })
+        .await?;
+    if outdated > 0 {
+        // alarms stored without ids get a new one on every read and would be reported again
+        warn!(
+            "{} residents are below schema version {}, run migrate first",
+            outdated, SCHEMA_VERSION
+        );
+    }
+    let collection = store.collection().name().to_string();
+    let history = store.history().name().to_string();
+    store
+        .database()
+        .run_command(doc! {
+            "collMod": &collection,
+            "changeStreamPreAndPostImages": { "enabled": true },
+        })
+        .await
+        .context("watch needs MongoDB 6.0 or later to keep change stream pre- and post-images")?;
+    // alarms removed from active alarms of matching residents, by id, until their history
+    // entry arrives
+    let mut clearing = HashMap::<ObjectId, Resident>::new();
+
+    let mut stream = store
+        .database()
+        .watch()
+        .pipeline([doc! { "$match": { "ns.coll": { "$in": [&collection, &history] } } }])
+        .full_document(FullDocumentType::WhenAvailable)
+        .full_document_before_change(FullDocumentBeforeChangeType::WhenAvailable)
+        .resume_after(resume_token)
+        .await?;
+    info!("Watching {} and {} for alarm events", collection, history);
+
+    while let Some(event) = stream.try_next().await? {
+        let coll = event.ns.as_ref().and_then(|ns| ns.coll.as_deref());
+        if coll == Some(collection.as_str()) {
+            if let Some(change) = resident_changed(&event)?
+                && filter.matches(&change.resident)
+            {
+                for alarm_event in &change.events {
+                    emit(alarm_event)?;
+                }
+                for alarm_id in change.removed {
+                    clearing.insert(alarm_id, change.resident.clone());
+                }
+            }
+        } else if coll == Some(history.as_str()) {
+            if event.operation_type == OperationType::Insert
+                && let Some(document) = event.full_document
+            {
+                let cleared: HistoryAlarm = bson::from_document(document)?;
+                match clearing.remove(&cleared.id) {
+                    Some(resident) => emit(&AlarmEvent::cleared(&resident, &cleared.into()))?,
+                    None => debug!(
+                        "History alarm {} was not cleared from an active alarm, not reported",
+                        cleared.id
+                    ),
+                }
+            }
+        } else if event.operation_type == OperationType::Invalidate {
+            warn!("Change stream invalidated, stopping");
+            break;
+        }
+        if let Some(path) = &params.resume_file
+            && let Some(token) = stream.resume_token()
+        {
+            save_resume_token(path, &token)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resident after a change with the alarm events of the change
+#[derive(Debug)]
+struct ResidentChange {
+    resident: Resident,
+    /// alarms raised or acknowledged
+    events: Vec<AlarmEvent>,
+    /// ids of the active alarms the change removed
+    removed: Vec<ObjectId>,
+}
+
+/// What a change did to the alarms of a resident, None if the change did not leave a
+/// resident behind
+fn resident_changed(event: &ChangeStreamEvent<bson::Document>) -> Result<Option<ResidentChange>> {
+    let id = event
+        .document_key
+        .as_ref()
+        .and_then(|key| key.get_object_id("_id").ok());
+    match event.operation_type {
+        OperationType::Insert | OperationType::Update | OperationType::Replace => {
+            // images are kept for a limited time, and not for changes made before they were enabled
+            let (Some(after), Some(id)) = (&event.full_document, id) else {
+                warn!(
+                    "No post-image for a change of resident {:?}, its alarm events are skipped",
+                    id
+                );
+                return Ok(None);
+            };
+            let resident: Resident = bson::from_document(after.clone())?;
+            let before: Option<Resident> = match &event.full_document_before_change {
+                Some(before) => Some(bson::from_document(before.clone())?),
+                None if event.operation_type == OperationType::Insert => None,
+                None => {
+                    warn!(
+                        "No pre-image for a change of resident {}, its alarm events are skipped",
+                        id
+                    );
+                    return Ok(Some(ResidentChange {
+                        resident,
+                        events: Vec::new(),
+                        removed: Vec::new(),
+                    }));
+                }
+            };
+            let events = alarm_events(before.as_ref(), &resident);
+            let removed = before
+                .iter()
+                .flat_map(|before| &before.active_alarms)
+                .filter(|alarm| !resident.active_alarms.iter().any(|a| a.id == alarm.id))
+                .map(|alarm| alarm.id)
+                .collect();
+            Ok(Some(ResidentChange {
+                resident,
+                events,
+                removed,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Alarms raised or acknowledged between `before` and `after` of the same resident
+fn alarm_events(before: Option<&Resident>, after: &Resident) -> Vec<AlarmEvent> {
+    let mut events = Vec::new();
+    for alarm in &after.active_alarms {
+        let previous = before.and_then(|b| b.active_alarms.iter().find(|a| a.id == alarm.id));
+        if previous.is_none() {
+            events.push(AlarmEvent::raised(after, alarm));
+        }
+        if alarm.acknowledged_at.is_some() && previous.is_none_or(|p| p.acknowledged_at.is_none()) {
+            events.push(AlarmEvent::acknowledged(after, alarm));
+        }
+    }
+    events
+}
+
+/// Resume tokens are kept as relaxed extended JSON
+fn load_resume_token(path: &Path) -> Result<ResumeToken> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let value: serde_json::Value =
+        serde_json::from_str(&data).with_context(|| format!("cannot parse {}", path.display()))?;
+    let token = bson::from_bson(bson::Bson::try_from(value)?)
+        .with_context(|| format!("invalid resume token in {}", path.display()))?;
+    info!("Resuming change stream from {}", path.display());
+    Ok(token)
+}
+
+fn save_resume_token(path: &Path, token: &ResumeToken) -> Result<()> {
+    let value = bson::to_bson(token)?.into_relaxed_extjson();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&value)?)
+        .with_context(|| format!("cannot write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("cannot write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ActiveAlarm, AlarmState};
+
+    fn states(events: &[AlarmEvent]) -> Vec<(AlarmState, &str)> {
+        events
+            .iter()
+            .map(|e| (e.event, e.message.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn diffs_active_alarms() {
+        let mut before = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        before.id = Some(ObjectId::new());
+        before.active_alarms.push(ActiveAlarm::new("call", None));
+        let mut after = before.clone();
+        after.active_alarms[0].acknowledged_at = Some(bson::DateTime::now());
+        after.active_alarms.push(ActiveAlarm::new("fall", None));
+
+        assert_eq!(
+            states(&alarm_events(Some(&before), &after)),
+            [
+                (AlarmState::Acknowledged, "call"),
+                (AlarmState::Raised, "fall")
+            ]
+        );
+        assert!(alarm_events(Some(&after), &after).is_empty());
+        // a new resident raises all of its alarms
+        assert_eq!(
+            states(&alarm_events(None, &after)),
+            [
+                (AlarmState::Raised, "call"),
+                (AlarmState::Acknowledged, "call"),
+                (AlarmState::Raised, "fall")
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_the_images_of_the_change() {
+        let mut before = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        let id = ObjectId::new();
+        before.id = Some(id);
+        let mut after = before.clone();
+        after.active_alarms.push(ActiveAlarm::new("call", None));
+        let event = |before: Option<&Resident>| -> ChangeStreamEvent<bson::Document> {
+            let mut event = doc! {
+                "_id": { "_data": "00" },
+                "operationType": "update",
+                "documentKey": { "_id": id },
+                "fullDocument": bson::to_document(&after).unwrap(),
+            };
+            if let Some(before) = before {
+                event.insert(
+                    "fullDocumentBeforeChange",
+                    bson::to_document(before).unwrap(),
+                );
+            }
+            bson::from_document(event).unwrap()
+        };
+
+        let change = resident_changed(&event(Some(&before))).unwrap().unwrap();
+        assert_eq!(change.resident.name, "Ann");
+        assert_eq!(states(&change.events), [(AlarmState::Raised, "call")]);
+        assert!(change.removed.is_empty());
+        // without a pre-image nothing is guessed
+        let change = resident_changed(&event(None)).unwrap().unwrap();
+        assert!(change.events.is_empty());
+        assert!(change.removed.is_empty());
+    }
+
+    #[test]
+    fn reports_removed_active_alarms() {
+        let mut before = Resident::new("Ann", "1940-05-01", "Room 1", "2020-01-01").unwrap();
+        let id = ObjectId::new();
+        before.id = Some(id);
+        before.active_alarms.push(ActiveAlarm::new("call", None));
+        before.active_alarms.push(ActiveAlarm::new("fall", None));
+        let mut after = before.clone();
+        let cleared = after.active_alarms.remove(0);
+        let event: ChangeStreamEvent<bson::Document> = bson::from_document(doc! {
+            "_id": { "_data": "00" },
+            "operationType": "update",
+            "documentKey": { "_id": id },
+            "fullDocument": bson::to_document(&after).unwrap(),
+            "fullDocumentBeforeChange": bson::to_document(&before).unwrap(),
+        })
+        .unwrap();
+        let change = resident_changed(&event).unwrap().unwrap();
+        assert!(change.events.is_empty());
+        assert_eq!(change.removed, [cleared.id]);
+    }
+}
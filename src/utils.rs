@@ -1,6 +1,9 @@
+use std::{io::Write, sync::OnceLock};
+
 use anyhow::Result;
-use chrono::Local;
-use futures::TryStreamExt as _;
+use chrono::{LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone as _};
+use chrono_tz::Tz;
+use itertools::Itertools as _;
 use mongodb::bson;
 
 pub enum DateTimeStr<'a> {
@@ -9,36 +12,110 @@ pub enum DateTimeStr<'a> {
     DateTime(bson::DateTime),
 }
 
-impl From<DateTimeStr<'_>> for bson::DateTime {
-    fn from(val: DateTimeStr<'_>) -> Self {
+static TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+/// Sets the facility timezone used to read date-only and naive date/time inputs
+/// and to display dates. Only the first call has an effect.
+pub fn set_timezone(tz: Tz) {
+    TIMEZONE.set(tz).ok();
+}
+
+/// Facility timezone, UTC unless set with [`set_timezone`]
+pub fn timezone() -> Tz {
+    TIMEZONE.get().copied().unwrap_or(Tz::UTC)
+}
+
+const NAIVE_FORMATS: [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Parses an RFC 3339 date/time with offset, a naive `YYYY-MM-DD[THH:MM[:SS]]`
+/// or a date-only `YYYY-MM-DD`; naive inputs are read in the facility [`timezone`]
+pub fn parse_datetime(s: &str) -> Result<bson::DateTime> {
+    parse_datetime_in(s, timezone())
+}
+
+fn parse_datetime_in(s: &str, tz: Tz) -> Result<bson::DateTime> {
+    let s = s.trim();
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(bson::DateTime::from_chrono(dt));
+    }
+    let naive = NAIVE_FORMATS
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .map(|d| d.and_time(NaiveTime::MIN))
+        });
+    let Some(naive) = naive else {
+        anyhow::bail!(
+            "invalid date '{s}': expected YYYY-MM-DD, YYYY-MM-DDTHH:MM[:SS] or RFC 3339 with offset"
+        );
+    };
+    from_local(s, naive, tz)
+}
+
+/// Like [`parse_datetime`] but a date-only input means the last millisecond of that day,
+/// for inclusive upper bounds
+pub fn parse_datetime_end(s: &str) -> Result<bson::DateTime> {
+    parse_datetime_end_in(s, timezone())
+}
+
+fn parse_datetime_end_in(s: &str, tz: Tz) -> Result<bson::DateTime> {
+    match NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d") {
+        Ok(date) => {
+            let Some(next_day) = date.succ_opt() else {
+                anyhow::bail!("invalid date '{s}': out of range");
+            };
+            let end = from_local(s, next_day.and_time(NaiveTime::MIN), tz)?;
+            Ok(bson::DateTime::from_millis(end.timestamp_millis() - 1))
+        }
+        Err(_) => parse_datetime_in(s, tz),
+    }
+}
+
+fn from_local(s: &str, naive: NaiveDateTime, tz: Tz) -> Result<bson::DateTime> {
+    match tz.from_local_datetime(&naive) {
+        LocalResult::Single(dt) => Ok(bson::DateTime::from_chrono(dt)),
+        // repeated hour when clocks go back, take the first occurrence
+        LocalResult::Ambiguous(dt, _) => Ok(bson::DateTime::from_chrono(dt)),
+        LocalResult::None => {
+            anyhow::bail!("invalid date '{s}': this local time does not exist in {tz}")
+        }
+    }
+}
+
+/// Today's date in the facility timezone
+pub fn today() -> NaiveDate {
+    chrono::Utc::now().with_timezone(&timezone()).date_naive()
+}
+
+/// Date of `dt` in the facility timezone
+pub fn local_date(dt: bson::DateTime) -> NaiveDate {
+    dt.to_chrono().with_timezone(&timezone()).date_naive()
+}
+
+/// Formats a date in the facility timezone, the one naive inputs are read in
+pub fn format_datetime(dt: &bson::DateTime, fmt: &str) -> String {
+    format_datetime_in(dt, fmt, timezone())
+}
+
+pub fn format_datetime_in(dt: &bson::DateTime, fmt: &str, tz: Tz) -> String {
+    dt.to_chrono().with_timezone(&tz).format(fmt).to_string()
+}
+
+impl TryFrom<DateTimeStr<'_>> for bson::DateTime {
+    type Error = anyhow::Error;
+
+    fn try_from(val: DateTimeStr<'_>) -> Result<Self> {
         match val {
-            DateTimeStr::Str(s) => {
-                if !s.contains('T') {
-                    // assume date only
-                    bson::DateTime::parse_rfc3339_str(s.to_string() + "T00:00:00Z")
-                        .unwrap_or_else(|_| bson::DateTime::now())
-                } else if !s.ends_with('Z') && !s.contains('+') && !s.contains('-') {
-                    // assume UTC if no timezone provided
-                    bson::DateTime::parse_rfc3339_str(s.to_string() + "Z")
-                        .unwrap_or_else(|_| bson::DateTime::now())
-                } else {
-                    bson::DateTime::parse_rfc3339_str(s).unwrap_or_else(|_| bson::DateTime::now())
-                }
-            }
-            DateTimeStr::String(s) => {
-                if !s.contains('T') {
-                    // assume date only
-                    bson::DateTime::parse_rfc3339_str(s + "T00:00:00Z")
-                        .unwrap_or_else(|_| bson::DateTime::now())
-                } else if !s.ends_with('Z') && !s.contains('+') && !s.contains('-') {
-                    // assume UTC if no timezone provided
-                    bson::DateTime::parse_rfc3339_str(s + "Z")
-                        .unwrap_or_else(|_| bson::DateTime::now())
-                } else {
-                    bson::DateTime::parse_rfc3339_str(&s).unwrap_or_else(|_| bson::DateTime::now())
-                }
-            }
-            DateTimeStr::DateTime(dt) => dt,
+            DateTimeStr::Str(s) => parse_datetime(s),
+            DateTimeStr::String(s) => parse_datetime(&s),
+            DateTimeStr::DateTime(dt) => Ok(dt),
         }
     }
 }
@@ -55,9 +132,9 @@ impl std::fmt::Display for DateTimeStr<'_> {
 
 pub mod serde_helpers {
     pub mod bson_dateonly {
-        use crate::utils::DateTimeStr;
+        use crate::utils::{DateTimeStr, timezone};
         use mongodb::bson;
-        use serde::{Deserialize, Deserializer, Serializer, ser};
+        use serde::{Deserialize, Deserializer, Serializer, de};
         use std::result::Result;
 
         /// Deserializes a [`bson::DateTime`] from an RFC 3339 formatted date (YYYY-MM-DD).
@@ -65,76 +142,173 @@ pub mod serde_helpers {
         where
             D: Deserializer<'de>,
         {
-            Ok(DateTimeStr::String(String::deserialize(deserializer)?).into())
+            DateTimeStr::String(String::deserialize(deserializer)?)
+                .try_into()
+                .map_err(de::Error::custom)
         }
 
-        /// Serializes a [`bson::DateTime`] as an RFC 3339 (ISO 8601) formatted date (YYYY-MM-DD).
+        /// Serializes a [`bson::DateTime`] as an RFC 3339 (ISO 8601) formatted date (YYYY-MM-DD)
+        /// in the facility timezone.
         pub fn serialize<S: Serializer>(
             val: &bson::DateTime,
             serializer: S,
         ) -> Result<S::Ok, S::Error> {
-            let formatted = &val.try_to_rfc3339_string().map_err(|e| {
-                ser::Error::custom(format!("cannot format {} as RFC 3339: {}", val, e))
-            })?[..10]; // only date part
-            serializer.serialize_str(formatted)
+            let formatted = val
+                .to_chrono()
+                .with_timezone(&timezone())
+                .format("%Y-%m-%d")
+                .to_string();
+            serializer.serialize_str(&formatted)
         }
     }
 }
 
-/// print query cursor to tty nice table view
-pub async fn bson_table_print(cursor: mongodb::Cursor<bson::Document>) -> Result<()> {
-    let mut table = comfy_table::Table::new();
-    let mut first = true;
-    let mut cursor = cursor;
-    while let Some(doc) = cursor.try_next().await? {
-        if first {
-            // Write the header row (keys of the BSON document)
-            let headers: Vec<&str> = doc.keys().map(|k| k.as_str()).collect();
-            table.set_header(headers);
-            first = false;
-        }
-        // Write the values row (values of the BSON document)
-        let values: Vec<String> = doc
-            .values()
-            .enumerate()
-            .map(|(i, v)| bson_value_to_str(v, doc.keys().nth(i).unwrap()))
-            .collect();
-        table.add_row(values);
-    }
-    println!("{table}");
-    Ok(())
+/// Output format of query results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Csv,
+    /// array of relaxed extended JSON documents with typed values
+    Json,
+    /// one relaxed extended JSON document per line
+    Ndjson,
+    Markdown,
+    /// standalone HTML page
+    Html,
 }
 
-/// Converts a BSON Documents Cursor to a CSV file
-pub async fn bson_to_csv(
-    mut cursor: mongodb::Cursor<bson::Document>,
-    file_path: &str,
+/// print query results to tty nice table view
+pub fn bson_table_print(docs: impl IntoIterator<Item = bson::Document>) -> Result<()> {
+    let docs = docs.into_iter().collect::<Vec<_>>();
+    bson_write(&docs, OutputFormat::Table, &mut std::io::stdout().lock())
+}
+
+/// Converts BSON Documents to a CSV file
+pub fn bson_to_csv(docs: impl IntoIterator<Item = bson::Document>, file_path: &str) -> Result<()> {
+    let docs = docs.into_iter().collect::<Vec<_>>();
+    let mut file = std::io::BufWriter::new(std::fs::File::create(file_path)?);
+    bson_write(&docs, OutputFormat::Csv, &mut file)
+}
+
+/// Writes query results in `format`. Text formats use the [`bson_value_to_str`] rules,
+/// JSON formats keep the typed values.
+pub fn bson_write(
+    docs: &[bson::Document],
+    format: OutputFormat,
+    out: &mut dyn Write,
 ) -> Result<()> {
-    // Create a CSV writer
-    let mut writer = csv::WriterBuilder::new().from_path(file_path)?;
-
-    let mut first = true;
-    while let Some(doc) = cursor.try_next().await? {
-        if first {
-            // Write the header row (keys of the BSON document)
-            let headers: Vec<&str> = doc.keys().map(|k| k.as_str()).collect();
+    match format {
+        OutputFormat::Json => {
+            let docs = docs
+                .iter()
+                .map(|doc| bson::Bson::Document(doc.clone()).into_relaxed_extjson())
+                .collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut *out, &docs)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for doc in docs {
+                let json = bson::Bson::Document(doc.clone()).into_relaxed_extjson();
+                serde_json::to_writer(&mut *out, &json)?;
+                writeln!(out)?;
+            }
+        }
+        OutputFormat::Table => {
+            let (headers, rows) = text_rows(docs);
+            let mut table = comfy_table::Table::new();
+            table.set_header(headers);
+            table.add_rows(rows);
+            writeln!(out, "{table}")?;
+        }
+        OutputFormat::Csv => {
+            let (headers, rows) = text_rows(docs);
+            let mut writer = csv::WriterBuilder::new().from_writer(&mut *out);
             writer.write_record(&headers)?;
-            first = false;
+            for row in rows {
+                writer.write_record(&row)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Markdown => {
+            let (headers, rows) = text_rows(docs);
+            let cell = |s: &str| s.trim().replace('|', "\\|").replace('\n', " ");
+            writeln!(out, "| {} |", headers.iter().map(|h| cell(h)).join(" | "))?;
+            writeln!(out, "|{}", "---|".repeat(headers.len()))?;
+            for row in rows {
+                writeln!(out, "| {} |", row.iter().map(|v| cell(v)).join(" | "))?;
+            }
+        }
+        OutputFormat::Html => {
+            let (headers, rows) = text_rows(docs);
+            writeln!(out, "{HTML_HEAD}")?;
+            writeln!(
+                out,
+                "<thead><tr>{}</tr></thead>\n<tbody>",
+                headers
+                    .iter()
+                    .map(|h| format!("<th>{}</th>", html_escape(h)))
+                    .join("")
+            )?;
+            for row in rows {
+                writeln!(
+                    out,
+                    "<tr>{}</tr>",
+                    row.iter()
+                        .map(|v| format!("<td>{}</td>", html_escape(v.trim())))
+                        .join("")
+                )?;
+            }
+            writeln!(out, "</tbody>\n</table>\n</body>\n</html>")?;
         }
-        // Write the values row (values of the BSON document)
-        let values: Vec<String> = doc
-            .values()
-            .enumerate()
-            .map(|(i, v)| bson_value_to_str(v, doc.keys().nth(i).unwrap()))
-            .collect();
-        writer.write_record(&values)?;
     }
-
-    // Flush and close the writer
-    writer.flush()?;
     Ok(())
 }
 
+/// Header and formatted rows; columns are the keys of all documents in first seen order,
+/// missing values are empty
+fn text_rows(docs: &[bson::Document]) -> (Vec<String>, Vec<Vec<String>>) {
+    let headers = docs
+        .iter()
+        .flat_map(|doc| doc.keys().cloned())
+        .unique()
+        .collect::<Vec<_>>();
+    let rows = docs
+        .iter()
+        .map(|doc| {
+            headers
+                .iter()
+                .map(|key| {
+                    doc.get(key)
+                        .map_or_else(String::new, |v| bson_value_to_str(v, key))
+                })
+                .collect()
+        })
+        .collect();
+    (headers, rows)
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Query results</title>
+<style>
+table { border-collapse: collapse; font-family: sans-serif; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }
+th { background: #eee; }
+</style>
+</head>
+<body>
+<table>"#;
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 pub fn format_timedelta(duration: &f64) -> String {
     let total_minutes = (duration / 60.0).round() as i64;
     let days = total_minutes / 1440;
@@ -160,11 +334,8 @@ fn bson_value_to_str(value: &bson::Bson, key: &str) -> String {
             }
         }
         bson::Bson::ObjectId(oid) => oid.to_string(),
-        bson::Bson::DateTime(dt) => dt
-            .to_chrono()
-            .with_timezone(&Local)
-            .format("%Y-%m-%d %H:%M")
-            .to_string(),
+        bson::Bson::Array(values) => values.iter().map(|v| bson_value_to_str(v, key)).join(", "),
+        bson::Bson::DateTime(dt) => format_datetime(dt, "%Y-%m-%d %H:%M"),
         bson::Bson::Double(d) => {
             if key.contains("duration") {
                 format_timedelta(d)
@@ -182,3 +353,100 @@ fn bson_value_to_str(value: &bson::Bson, key: &str) -> String {
         _ => value.to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> bson::DateTime {
+        bson::DateTime::parse_rfc3339_str(s).unwrap()
+    }
+
+    #[test]
+    fn naive_inputs_are_read_in_the_timezone() {
+        let tz = chrono_tz::Europe::Berlin;
+        assert_eq!(
+            parse_datetime_in("2024-01-15", tz).unwrap(),
+            utc("2024-01-14T23:00:00Z")
+        );
+        assert_eq!(
+            parse_datetime_in("2024-07-15T08:30", tz).unwrap(),
+            utc("2024-07-15T06:30:00Z")
+        );
+        assert_eq!(
+            parse_datetime_in(" 2024-07-15 08:30:15.250 ", tz).unwrap(),
+            utc("2024-07-15T06:30:15.250Z")
+        );
+    }
+
+    #[test]
+    fn formatted_dates_parse_back_to_the_same_time() {
+        let dt = utc("2024-07-15T06:30:15Z");
+        for tz in [
+            chrono_tz::UTC,
+            chrono_tz::Europe::Berlin,
+            chrono_tz::America::New_York,
+        ] {
+            let shown = format_datetime_in(&dt, "%Y-%m-%d %H:%M:%S", tz);
+            assert_eq!(parse_datetime_in(&shown, tz).unwrap(), dt, "{tz}: {shown}");
+        }
+        assert_eq!(
+            format_datetime_in(&dt, "%Y-%m-%d %H:%M", chrono_tz::Europe::Berlin),
+            "2024-07-15 08:30"
+        );
+    }
+
+    #[test]
+    fn offsets_win_over_the_timezone() {
+        let tz = chrono_tz::Asia::Tokyo;
+        assert_eq!(
+            parse_datetime_in("2024-07-15T08:30:00+02:00", tz).unwrap(),
+            utc("2024-07-15T06:30:00Z")
+        );
+        assert_eq!(
+            parse_datetime_end_in("2024-07-15T08:30:00Z", tz).unwrap(),
+            utc("2024-07-15T08:30:00Z")
+        );
+    }
+
+    #[test]
+    fn date_only_end_is_the_last_millisecond_of_the_local_day() {
+        let tz = chrono_tz::America::New_York;
+        assert_eq!(
+            parse_datetime_end_in("2024-01-15", tz).unwrap(),
+            utc("2024-01-16T04:59:59.999Z")
+        );
+        // a time is taken as is
+        assert_eq!(
+            parse_datetime_end_in("2024-01-15T10:00", tz).unwrap(),
+            utc("2024-01-15T15:00:00Z")
+        );
+    }
+
+    #[test]
+    fn daylight_saving_transitions() {
+        let tz = chrono_tz::Europe::Berlin;
+        let err = parse_datetime_in("2025-03-30T02:30", tz).unwrap_err();
+        assert!(err.to_string().contains("does not exist in Europe/Berlin"));
+        // the repeated hour resolves to its first occurrence
+        assert_eq!(
+            parse_datetime_in("2025-10-26T02:30", tz).unwrap(),
+            utc("2025-10-26T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for s in [
+            "",
+            "15.01.2024",
+            "2024-13-01",
+            "2024-01-15T25:00",
+            "yesterday",
+        ] {
+            let err = parse_datetime_in(s, Tz::UTC).unwrap_err();
+            assert!(err.to_string().starts_with("invalid date"), "{s}: {err}");
+        }
+        assert!(parse_datetime_end_in("2024-02-30", Tz::UTC).is_err());
+    }
+}